@@ -1,7 +1,10 @@
 use std::error::Error;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    prost_build::compile_protos(&["proto/messages.proto"], &["proto/"])?;
+    // `bytes` fields become `Bytes` views of the received frame instead of copies
+    prost_build::Config::new()
+        .bytes(["."])
+        .compile_protos(&["proto/messages.proto"], &["proto/"])?;
 
     Ok(())
 }
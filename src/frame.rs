@@ -0,0 +1,199 @@
+/*!
+ * \file frame.rs
+ * \author Mohamed Shaban Waaer
+ * \date 2024-12-24
+ *
+ * \brief Length-prefixed framing shared by the server and its clients.
+ *
+ * TCP is a byte stream, so a single `read` may return half a message or several
+ * messages glued together. Every protobuf payload is therefore sent as a frame:
+ * a 4-byte big-endian length followed by exactly that many payload bytes.
+ * A frame with a length of zero carries no message; it is a keepalive that
+ * the server silently ignores.
+ *
+ * `write_frame` and `read_frame` are the simplest blocking helpers, while
+ * `FrameDecoder` accumulates whatever bytes have arrived and pulls out complete
+ * frames as they become available. The decoder reads straight into a `BytesMut`
+ * and hands out payloads as `Bytes` views of that buffer, so a frame is never
+ * copied between the socket and `prost`; decoding from such a view leaves `bytes`
+ * fields pointing into it as well. Free space read into once is reused by later
+ * reads instead of being cleared again.
+ */
+
+ use bytes::{Buf, Bytes, BytesMut};
+ use std::io::{self, Read, Write};
+
+ /// Size of the length prefix that precedes every frame.
+ pub const HEADER_LEN: usize = 4;
+
+ /// Largest payload accepted in a single frame.
+ pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+ /// Free space made available in the decoder buffer before each read.
+ const READ_CHUNK: usize = 4096;
+
+ /*
+  * \brief Prepends the length prefix to `payload`.
+  *
+  * \param payload The encoded message to frame.
+  * \return The framed bytes, ready to be written to the socket.
+  */
+ pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+     let mut framed = Vec::with_capacity(HEADER_LEN + payload.len());
+     framed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+     framed.extend_from_slice(payload);
+     framed
+ }
+
+ /*
+  * \brief Writes `payload` as a single frame and flushes the writer.
+  *
+  * \param writer The destination stream.
+  * \param payload The encoded message to send.
+  * \return A result indicating success or failure of the write.
+  */
+ pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+     writer.write_all(&encode_frame(payload))?;
+     writer.flush()
+ }
+
+ /*
+  * \brief Reads exactly one frame, blocking until it has fully arrived.
+  *
+  * \param reader The source stream.
+  * \return The frame payload, or an error if the stream fails or the frame is too large.
+  */
+ pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+     let mut header = [0u8; HEADER_LEN];
+     reader.read_exact(&mut header)?;
+     let len = check_len(u32::from_be_bytes(header) as usize)?;
+
+     let mut payload = vec![0u8; len];
+     reader.read_exact(&mut payload)?;
+     Ok(payload)
+ }
+
+ /// Rejects frame lengths above `MAX_FRAME_SIZE`.
+ fn check_len(len: usize) -> io::Result<usize> {
+     if len > MAX_FRAME_SIZE {
+         return Err(io::Error::new(
+             io::ErrorKind::InvalidData,
+             format!("Frame of {} bytes exceeds the {} byte limit", len, MAX_FRAME_SIZE),
+         ));
+     }
+     Ok(len)
+ }
+
+ /// Accumulates raw bytes from a stream and splits them into complete frames.
+ #[derive(Default)]
+ pub struct FrameDecoder {
+     /// Received bytes, followed by free space that is already initialized.
+     buffer: BytesMut,
+     /// Length of the received bytes at the front of `buffer`.
+     filled: usize,
+ }
+
+ impl FrameDecoder {
+     /*
+      * \brief Creates an empty decoder.
+      */
+     pub fn new() -> Self {
+         FrameDecoder { buffer: BytesMut::new(), filled: 0 }
+     }
+
+     /*
+      * \brief Performs a single read from `reader` directly into the decoder buffer.
+      *
+      * \param reader The source stream.
+      * \return The number of bytes read; zero means end of stream.
+      */
+     pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+         // Only space never handed to a read before needs clearing
+         if self.buffer.len() - self.filled < READ_CHUNK {
+             self.buffer.resize(self.filled + READ_CHUNK, 0);
+         }
+         let bytes = reader.read(&mut self.buffer[self.filled..])?;
+         self.filled += bytes;
+         Ok(bytes)
+     }
+
+     /*
+      * \brief Reads from `reader` until a complete frame is buffered and returns it.
+      *
+      * Bytes beyond that frame stay buffered for the next call, and a read that fails,
+      * for example on a timeout, loses nothing. Reads interrupted by a signal are retried.
+      *
+      * \param reader The source stream.
+      * \return The frame payload, an `UnexpectedEof` error if the stream ends first, or
+      *         an error if the stream fails or the frame is too large.
+      */
+     pub fn read_frame<R: Read>(&mut self, reader: &mut R) -> io::Result<Bytes> {
+         loop {
+             if let Some(payload) = self.next_frame()? {
+                 return Ok(payload);
+             }
+             match self.read_from(reader) {
+                 Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                 Ok(_) => {}
+                 Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                 Err(e) => return Err(e),
+             }
+         }
+     }
+
+     /*
+      * \brief Appends bytes read from the stream.
+      *
+      * \param bytes The newly received bytes.
+      */
+     pub fn extend(&mut self, bytes: &[u8]) {
+         self.buffer.truncate(self.filled);
+         self.buffer.extend_from_slice(bytes);
+         self.filled += bytes.len();
+     }
+
+     /*
+      * \brief Returns the number of received bytes not yet returned as a frame.
+      */
+     pub fn buffered_len(&self) -> usize {
+         self.filled
+     }
+
+     /*
+      * \brief Removes raw bytes from the front of the buffer, bypassing the framing.
+      *
+      * \param len The number of bytes to take.
+      * \return The bytes, or `None` until at least `len` bytes have been received.
+      */
+     pub fn take_raw(&mut self, len: usize) -> Option<Bytes> {
+         if self.filled < len {
+             return None;
+         }
+         self.filled -= len;
+         Some(self.buffer.split_to(len).freeze())
+     }
+
+     /*
+      * \brief Removes the next complete frame from the buffer.
+      *
+      * \return `Ok(Some(payload))` when a whole frame is buffered, `Ok(None)` when more
+      *         bytes are needed, or an error if the announced length is too large.
+      */
+     pub fn next_frame(&mut self) -> io::Result<Option<Bytes>> {
+         if self.filled < HEADER_LEN {
+             return Ok(None);
+         }
+
+         let mut header = [0u8; HEADER_LEN];
+         header.copy_from_slice(&self.buffer[..HEADER_LEN]);
+         let len = check_len(u32::from_be_bytes(header) as usize)?;
+
+         if self.filled < HEADER_LEN + len {
+             return Ok(None);
+         }
+
+         self.buffer.advance(HEADER_LEN);
+         self.filled -= HEADER_LEN + len;
+         Ok(Some(self.buffer.split_to(len).freeze()))
+     }
+ }
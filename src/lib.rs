@@ -1,7 +1,70 @@
+pub mod frame;
 pub mod server;
+pub mod transport;
 
 pub mod message {
     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
+
+    /// Defines an accessor on `ServerMessage` for the payload of each listed variant.
+    macro_rules! variant_accessors {
+        ($($name:ident => $variant:ident),* $(,)?) => {
+            impl ServerMessage {
+                $(
+                    #[doc = concat!(
+                        "Returns the `",
+                        stringify!($variant),
+                        "` the server answered with, if any."
+                    )]
+                    pub fn $name(&self) -> Option<&$variant> {
+                        match self.message {
+                            Some(server_message::Message::$variant(ref payload)) => Some(payload),
+                            _ => None,
+                        }
+                    }
+                )*
+            }
+        };
+    }
+
+    variant_accessors!(
+        as_echo => EchoMessage,
+        as_add_response => AddResponse,
+        as_pong => PongResponse,
+        as_binary_echo => BinaryEcho,
+        as_capabilities => CapabilitiesResponse,
+        as_hello => HelloResponse,
+        as_shutdown => Shutdown,
+        as_greeting => Greeting,
+        as_chunk => Chunk,
+        as_stream_end => StreamEnd,
+        as_broadcast => Broadcast,
+        as_redirect => Redirect,
+        as_divide_response => DivideResponse,
+        as_batch_response => BatchResponse,
+    );
+
+    impl ServerMessage {
+        /// Returns true if the server answered with an `ErrorResponse`.
+        pub fn is_error(&self) -> bool {
+            self.as_error().is_some()
+        }
+
+        /// Returns the `ErrorResponse` the server answered with, if any.
+        pub fn as_error(&self) -> Option<&ErrorResponse> {
+            match self.message {
+                Some(server_message::Message::ErrorResponse(ref error)) => Some(error),
+                _ => None,
+            }
+        }
+
+        /// Turns an `ErrorResponse` into `Err`, passing every other message through as `Ok`.
+        pub fn into_result(self) -> Result<ServerMessage, ErrorResponse> {
+            match self.message {
+                Some(server_message::Message::ErrorResponse(error)) => Err(error),
+                _ => Ok(self),
+            }
+        }
+    }
 }
 
 use std::io;
@@ -2,178 +2,3284 @@
  * \file server.rs
  * \author Mohamed Shaban Waaer
  * \date 2024-12-24
- * 
+ *
  * \brief This file implements a basic TCP echo server using Rust.
- * 
+ *
  * This file contains the implementation of a simple multi-threaded server
  * that listens for client connections, reads messages sent from clients,
- * and echoes the message back to the client. It uses the `prost` library
+ * and answers each one with the matching response. It uses the `prost` library
  * for encoding and decoding messages. The server can handle multiple clients
  * concurrently by spawning new threads to handle each client.
- * 
+ *
  * The server listens on a specified address and port, accepts client connections,
  * and spawns new threads to handle client communication. Each client communicates
- * with the server via TCP and sends an `EchoMessage`, which is decoded and sent
- * back to the client.
- * 
+ * with the server via TCP and sends length-prefixed `ClientMessage` frames, which are
+ * decoded, queued and answered in order with a `ServerMessage`.
+ *
  * This file includes two main structures:
  * - `Client`: Represents a single client connection, with methods to handle communication.
  * - `Server`: Represents the server itself, which manages incoming client connections.
  */
 
- use crate::message::EchoMessage;
- use log::{error, info, warn};
+ use crate::frame::{self, FrameDecoder};
+ use crate::transport::Transport;
+ use crate::message::{
+     client_message, server_message, AddResponse, BatchRequest, BatchResponse,
+     CapabilitiesResponse, Chunk, ClientMessage, DivideResponse, ErrorCode, ErrorResponse,
+     Greeting, HelloResponse, Broadcast, PongResponse, Redirect, ServerMessage, Shutdown,
+     StreamEnd,
+ };
+ use bytes::Bytes;
+ use log::{debug, error, info, log, warn, Level};
  use prost::Message;
  use std::{
-     io::{self, ErrorKind, Read, Write},
-     net::{TcpListener, TcpStream},
-     sync::{Arc, Mutex},
+     cmp::Reverse,
+     collections::{BinaryHeap, HashMap, VecDeque},
+     any::Any,
+     io::{self, ErrorKind, Write},
+     net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream},
+     panic::{self, AssertUnwindSafe},
+     sync::{
+         atomic::{self, AtomicBool, AtomicU64, AtomicUsize},
+         mpsc::{self, Receiver, Sender, SyncSender, TryRecvError, TrySendError},
+         Arc, Condvar, Mutex, MutexGuard,
+     },
      thread,
      thread::JoinHandle,
-     time::Duration,
+     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
  };
- 
- /// Represents a client connected to the server.
- struct Client {
-     stream: TcpStream,
+
+ /// Highest protocol version this server speaks.
+ pub const PROTOCOL_VERSION: u32 = 1;
+
+ /// The kind of a client request, without its payload.
+ #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+ pub enum MessageKind {
+     /// `EchoMessage`
+     Echo,
+     /// `BinaryEcho`
+     BinaryEcho,
+     /// `AddRequest`
+     Add,
+     /// `PingRequest`
+     Ping,
+     /// `CapabilitiesRequest`
+     Capabilities,
+     /// `HelloRequest`
+     Hello,
+     /// `DivideRequest`
+     Divide,
+     /// `BatchRequest`
+     Batch,
+ }
+
+ /*
+  * \brief Returns the kind of a request.
+  *
+  * This is the one place that lists every `client_message::Message` variant; code that
+  * only cares about what a request is should match on its kind instead.
+  *
+  * \param message The decoded request.
+  * \return The request's kind.
+  */
+ pub fn kind(message: &client_message::Message) -> MessageKind {
+     match message {
+         client_message::Message::EchoMessage(_) => MessageKind::Echo,
+         client_message::Message::BinaryEcho(_) => MessageKind::BinaryEcho,
+         client_message::Message::AddRequest(_) => MessageKind::Add,
+         client_message::Message::PingRequest(_) => MessageKind::Ping,
+         client_message::Message::CapabilitiesRequest(_) => MessageKind::Capabilities,
+         client_message::Message::HelloRequest(_) => MessageKind::Hello,
+         client_message::Message::DivideRequest(_) => MessageKind::Divide,
+         client_message::Message::BatchRequest(_) => MessageKind::Batch,
+     }
+ }
+
+ /// Processing priority of a request; higher priorities get a worker first.
+ #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+ pub enum Priority {
+     /// Bulk traffic such as echo, arithmetic and batched requests.
+     Normal,
+     /// Health checks that must stay responsive under load.
+     High,
+ }
+
+ impl Priority {
+     /*
+      * \brief Returns the priority assigned to a kind of request.
+      *
+      * \param message The decoded request.
+      * \return The priority the request waits for a worker with.
+      */
+     pub fn of(message: &client_message::Message) -> Self {
+         match kind(message) {
+             MessageKind::Ping | MessageKind::Hello => Priority::High,
+             MessageKind::Echo
+             | MessageKind::BinaryEcho
+             | MessageKind::Add
+             | MessageKind::Divide
+             | MessageKind::Capabilities
+             | MessageKind::Batch => Priority::Normal,
+         }
+     }
+ }
+
+ /// What a handler does with a frame that decodes to no message variant it knows.
+ #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+ pub enum UnknownMessagePolicy {
+     /// Answer with an `ErrorResponse` carrying `ErrorCode::UnknownMessage`.
+     #[default]
+     Error,
+     /// Drop the frame without a response.
+     Ignore,
+     /// Close the connection.
+     Close,
+ }
+
+ /// What a handler does with an `EchoMessage` whose content is empty.
+ #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+ pub enum EmptyEchoPolicy {
+     /// Echo the empty content like any other.
+     #[default]
+     Echo,
+     /// Answer with an `ErrorResponse` carrying `ErrorCode::Rejected`.
+     Reject,
+ }
+
+ /// What a handler does with a client that reads its responses too slowly.
+ #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+ pub enum SlowClientPolicy {
+     /// Log a warning and carry on.
+     Log,
+     /// Log a warning and stop reading and answering for the given time, so the client
+     /// can catch up.
+     Throttle(Duration),
+     /// Close the connection; a write that takes too long is abandoned.
+     Disconnect,
+ }
+
+ /// Coarse state of a server, as returned by `Server::health`.
+ #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+ pub enum Health {
+     /// Accepting and handling connections.
+     Running,
+     /// Handling open connections but not accepting new ones, until `resume`.
+     Paused,
+     /// Finishing the requests of open connections before shutting down.
+     Draining,
+     /// Not accepting connections: not started yet, or stopped.
+     Stopped,
+ }
+
+ /// Error carried by `Server::run` when another call to `run` is already accepting connections.
+ #[derive(Debug)]
+ pub struct AlreadyRunning;
+
+ impl std::fmt::Display for AlreadyRunning {
+     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+         write!(f, "Server is already running on another thread")
+     }
+ }
+
+ impl std::error::Error for AlreadyRunning {}
+
+ /// What happened to the requests of all connections while the server drained.
+ #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+ pub struct ShutdownReport {
+     /// Requests answered with a regular response.
+     pub handled: u64,
+     /// Requests answered with an `ErrorResponse`.
+     pub rejected: u64,
+     /// Requests received but never answered, because their connection ended first.
+     pub dropped: u64,
+     /// Time from the start of the drain until every handler had finished.
+     pub duration: Duration,
+ }
+
+ /// Activity of all connections over one metrics interval, as returned by `Server::take_metrics`.
+ #[derive(Debug, Clone, Default, PartialEq, Eq)]
+ pub struct ServerMetrics {
+     /// Bytes read from clients, frame headers included.
+     pub bytes_read: u64,
+     /// Bytes written to clients, frame headers included.
+     pub bytes_written: u64,
+     /// Requests answered with a regular response.
+     pub requests_handled: u64,
+     /// Requests and frames answered with an `ErrorResponse`.
+     pub requests_rejected: u64,
+     /// Requests received but never answered.
+     pub requests_dropped: u64,
+     /// Requests handled per tenant; tenants without requests in the interval are left out.
+     pub tenant_messages: HashMap<String, u64>,
+ }
+
+ impl ServerMetrics {
+     /// Returns the activity between the `earlier` totals and these ones.
+     fn since(&self, earlier: &ServerMetrics) -> ServerMetrics {
+         ServerMetrics {
+             bytes_read: self.bytes_read - earlier.bytes_read,
+             bytes_written: self.bytes_written - earlier.bytes_written,
+             requests_handled: self.requests_handled - earlier.requests_handled,
+             requests_rejected: self.requests_rejected - earlier.requests_rejected,
+             requests_dropped: self.requests_dropped - earlier.requests_dropped,
+             tenant_messages: self
+                 .tenant_messages
+                 .iter()
+                 .map(|(tenant, count)| {
+                     (tenant.clone(), count - earlier.tenant_messages.get(tenant).unwrap_or(&0))
+                 })
+                 .filter(|(_, count)| *count > 0)
+                 .collect(),
+         }
+     }
+ }
+
+ /// Distribution of the payload sizes of the frames received by a server.
+ #[derive(Debug, Clone, Copy, PartialEq)]
+ pub struct MessageSizeStats {
+     /// Frames received, keepalives excluded.
+     pub count: u64,
+     /// Smallest payload, in bytes.
+     pub min: usize,
+     /// Largest payload, in bytes.
+     pub max: usize,
+     /// Average payload, in bytes.
+     pub mean: f64,
+     /// Payload size 99% of the recent frames do not exceed, in bytes.
+     pub p99: usize,
+ }
+
+ /// Number of recent frame sizes kept to estimate the 99th percentile.
+ const SIZE_SAMPLE_WINDOW: usize = 10_000;
+
+ /// Running totals behind `MessageSizeStats`.
+ #[derive(Default)]
+ struct SizeSamples {
+     count: u64,
+     total: u64,
+     min: usize,
+     max: usize,
+     recent: VecDeque<usize>,
+ }
+
+ impl SizeSamples {
+     /// Adds the payload size of one received frame.
+     fn record(&mut self, size: usize) {
+         self.min = if self.count == 0 { size } else { self.min.min(size) };
+         self.max = self.max.max(size);
+         self.count += 1;
+         self.total += size as u64;
+         if self.recent.len() == SIZE_SAMPLE_WINDOW {
+             self.recent.pop_front();
+         }
+         self.recent.push_back(size);
+     }
+
+     /// Summarizes the sizes recorded so far, or `None` before the first frame.
+     fn stats(&self) -> Option<MessageSizeStats> {
+         if self.count == 0 {
+             return None;
+         }
+         let mut recent: Vec<usize> = self.recent.iter().copied().collect();
+         recent.sort_unstable();
+         let p99_index = (recent.len() * 99).div_ceil(100) - 1;
+         Some(MessageSizeStats {
+             count: self.count,
+             min: self.min,
+             max: self.max,
+             mean: self.total as f64 / self.count as f64,
+             p99: recent[p99_index],
+         })
+     }
+ }
+
+ /// A decoded request waiting in a client's handler queue.
+ struct QueuedRequest {
+     size: usize,
+     /// The decoded request, or the error answering a frame that did not decode to one.
+     message: Result<client_message::Message, (ErrorCode, String)>,
+     /// The client's send time from the request, or zero.
+     client_send_ts: u64,
+     /// When the request was decoded; only recorded if the client sent a timestamp.
+     server_recv_ts: u64,
+     /// Whether the handler must leave its state untouched.
+     dry_run: bool,
+ }
+
+ /// Bounds how many requests the whole server processes at once, admitting the most urgent first.
+ struct WorkerPool {
+     state: Mutex<PoolState>,
+     released: Condvar,
+ }
+
+ struct PoolState {
+     /// Workers not busy with a request.
+     idle: usize,
+     /// Requests waiting for a worker, by priority and then by arrival.
+     waiting: BinaryHeap<(Priority, Reverse<u64>)>,
+     next_ticket: u64,
+ }
+
+ impl WorkerPool {
+     fn new(workers: usize) -> Self {
+         WorkerPool {
+             state: Mutex::new(PoolState {
+                 idle: workers.max(1),
+                 waiting: BinaryHeap::new(),
+                 next_ticket: 0,
+             }),
+             released: Condvar::new(),
+         }
+     }
+
+     fn lock(&self) -> MutexGuard<'_, PoolState> {
+         self.state.lock().unwrap_or_else(|e| e.into_inner())
+     }
+
+     /*
+      * \brief Waits for an idle worker, letting more urgent requests go first.
+      *
+      * Requests of the same priority get a worker in the order they asked for one.
+      *
+      * \param priority The priority of the request about to be processed.
+      * \return The worker, given back when it is dropped.
+      */
+     fn acquire(&self, priority: Priority) -> PoolWorker<'_> {
+         let mut state = self.lock();
+         let ticket = (priority, Reverse(state.next_ticket));
+         state.next_ticket += 1;
+         state.waiting.push(ticket);
+         while state.idle == 0 || state.waiting.peek() != Some(&ticket) {
+             state = self.released.wait(state).unwrap_or_else(|e| e.into_inner());
+         }
+         state.waiting.pop();
+         state.idle -= 1;
+         drop(state);
+         // The next request in line may find another idle worker
+         self.released.notify_all();
+         PoolWorker(self)
+     }
+ }
+
+ /// A worker of a `WorkerPool`, held while one request is processed.
+ struct PoolWorker<'a>(&'a WorkerPool);
+
+ impl Drop for PoolWorker<'_> {
+     fn drop(&mut self) {
+         self.0.lock().idle += 1;
+         self.0.released.notify_all();
+     }
+ }
+
+ /// Rate limit for a log message one connection may repeat many times, such as a decode failure.
+ #[derive(Default)]
+ struct LogThrottle {
+     last_logged: Option<Instant>,
+     suppressed: u64,
+ }
+
+ impl LogThrottle {
+     /*
+      * \brief Decides whether an occurrence of the message is logged.
+      *
+      * The first occurrence is logged, and after that at most one per `LOG_THROTTLE_INTERVAL`.
+      *
+      * \return The number of occurrences suppressed since the last logged one, or `None`
+      *         if this occurrence must not be logged.
+      */
+     fn admit(&mut self) -> Option<u64> {
+         let now = Instant::now();
+         match self.last_logged {
+             Some(last) if now.duration_since(last) < LOG_THROTTLE_INTERVAL => {
+                 self.suppressed += 1;
+                 None
+             }
+             _ => {
+                 self.last_logged = Some(now);
+                 Some(std::mem::take(&mut self.suppressed))
+             }
+         }
+     }
+ }
+
+ /// Describes how many occurrences of a throttled log message were left out, if any.
+ fn suppressed_note(suppressed: u64) -> String {
+     match suppressed {
+         0 => String::new(),
+         n => format!(" ({} more since the last report)", n),
+     }
+ }
+
+ /// Encoded size of the requests a handler holds before it stops reading from the client.
+ pub const MAX_QUEUED_BYTES: usize = 256 * 1024;
+
+ /// Longest tenant label, in bytes, a Hello may announce.
+ pub const MAX_TENANT_LEN: usize = 64;
+
+ /// Number of distinct tenants counted; requests of further tenants are not counted.
+ const MAX_TENANTS: usize = 1024;
+
+ /// Upper bound on what wrapping a slice of a response in a `Chunk` adds to the frame payload.
+ const CHUNK_OVERHEAD: usize = 16;
+
+ /// Shortest gap between two logs of the same repetitive message on one connection.
+ const LOG_THROTTLE_INTERVAL: Duration = Duration::from_secs(5);
+
+ /// How long a blocked read waits before the handler re-checks for a graceful shutdown.
+ const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+ /// Longest the accept loop waits for a connection before re-checking for a stop request.
+ const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+ /// Connections the kernel completes and queues before `run` accepts them.
+ ///
+ /// The standard library listens with a backlog of 128; a burst of clients beyond that
+ /// has its handshakes dropped and retried seconds later, which stalls them.
+ const LISTEN_BACKLOG: i32 = 4096;
+
+ /// First pause of the accept loop after running out of file descriptors.
+ const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+
+ /// Longest pause of the accept loop while file descriptors stay exhausted.
+ const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+ /// `errno` values of `accept` meaning the process (EMFILE) or system (ENFILE) is out of
+ /// descriptors.
+ #[cfg(unix)]
+ const EXHAUSTION_ERRNOS: [i32; 2] = [24, 23];
+
+ /// Windows socket error meaning too many sockets are open (WSAEMFILE).
+ #[cfg(windows)]
+ const EXHAUSTION_ERRNOS: [i32; 1] = [10024];
+
+ /// Default prefix of the names given to handler threads.
+ const DEFAULT_THREAD_NAME_PREFIX: &str = "echo-client";
+
+ /// Custom request handler owned by one connection, told whether the request is a dry run;
+ /// returning `None` falls back to the built-in response.
+ pub type MessageHandler =
+     dyn FnMut(&client_message::Message, bool) -> Option<server_message::Message> + Send;
+
+ /// Creates the custom handler of each new connection.
+ type HandlerFactory = dyn Fn() -> Box<MessageHandler> + Send + Sync;
+
+ /// Lines of traffic the tee writer thread has not written yet, beyond which lines are dropped.
+ const TEE_QUEUE_LINES: usize = 1024;
+
+ /// Handler answering a request with several responses; returning `None` falls back to the
+ /// other handlers.
+ pub type StreamHandler =
+     dyn Fn(&client_message::Message) -> Option<Vec<server_message::Message>> + Send + Sync;
+
+ /// Handler taking a request to answer later through the connection's send queue; returning
+ /// `false` falls back to the other handlers.
+ pub type AsyncHandler = dyn Fn(&client_message::Message, &ResponseSender) -> bool + Send + Sync;
+
+ /// Sending end of a connection's send queue; `None` once the connection stopped writing
+ /// queued responses.
+ type SendQueue = Mutex<Option<Sender<QueuedResponse>>>;
+
+ /// A response queued by the async handler.
+ struct QueuedResponse {
+     /// The response, carrying the timestamps of the request it answers.
+     response: ServerMessage,
+     /// Raised once the request has been counted, shared by all its responses.
+     counted: Arc<AtomicBool>,
+ }
+
+ /// \brief Sending end of a connection's send queue, handed to the async handler.
+ ///
+ /// Each request gets its own sender, so its responses carry the request's timestamps.
+ /// Clones may be moved to other threads; every response sent through them is written
+ /// to the client by its connection's handler thread, in the order it was sent.
+ #[derive(Clone)]
+ pub struct ResponseSender {
+     queue: Arc<SendQueue>,
+     client_send_ts: u64,
+     server_recv_ts: u64,
+     counted: Arc<AtomicBool>,
+ }
+
+ impl ResponseSender {
+     /*
+      * \brief Queues a response for the client.
+      *
+      * \param response The response to send.
+      * \return `true` if the response will be written to the client, `false` if the
+      *         connection no longer writes queued responses and the response is dropped.
+      */
+     pub fn send(&self, response: server_message::Message) -> bool {
+         let queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+         queue.as_ref().is_some_and(|sender| {
+             let response = ServerMessage {
+                 message: Some(response),
+                 client_send_ts: self.client_send_ts,
+                 server_recv_ts: self.server_recv_ts,
+             };
+             sender.send(QueuedResponse { response, counted: self.counted.clone() }).is_ok()
+         })
+     }
+ }
+
+ /// Check run on every request before it is handled; `Err` carries the reason it is rejected.
+ pub type RequestValidator = dyn Fn(&client_message::Message) -> Result<(), String> + Send + Sync;
+
+ /// Callback told the kind, processing latency and peer of every request once it is answered.
+ pub type MessageObserver = dyn Fn(MessageKind, Duration, SocketAddr) + Send + Sync;
+
+ /// A layer around request handling, installed with `Server::with_middleware`.
+ ///
+ /// Both hooks do nothing by default, so a layer only implements the side it cares about.
+ pub trait Middleware: Send + Sync {
+     /*
+      * \brief Inspects or rewrites a request before it is validated and handled.
+      *
+      * \param message The decoded request.
+      */
+     fn on_request(&self, _message: &mut client_message::Message) {}
+
+     /*
+      * \brief Inspects or rewrites a response before it is sent.
+      *
+      * \param response The response about to be written.
+      */
+     fn on_response(&self, _response: &mut server_message::Message) {}
+ }
+
+ /// Cleanup run once by `stop_graceful` after every connection has been drained.
+ pub type ShutdownHook = dyn FnOnce() + Send;
+
+ /// Peer address reported for connections served over a transport other than TCP.
+ pub const IN_PROCESS_PEER: SocketAddr =
+     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+ /// Settings chosen through the `Server::with_*` methods, shared by every handler.
+ #[derive(Clone)]
+ struct Config {
+     worker_pool: Option<Arc<WorkerPool>>,
+     thread_name_prefix: String,
+     stack_size: Option<usize>,
+     handler_factory: Option<Arc<HandlerFactory>>,
+     stream_handler: Option<Arc<StreamHandler>>,
+     async_handler: Option<Arc<AsyncHandler>>,
+     validator: Option<Arc<RequestValidator>>,
+     message_observer: Option<Arc<MessageObserver>>,
+     echo_delay: Duration,
+     shutdown_notice: Option<String>,
+     redirect: Option<(String, u16)>,
+     unknown_message: UnknownMessagePolicy,
+     empty_echo: EmptyEchoPolicy,
+     strict_decoding: bool,
+     session_timeout: Option<Duration>,
+     handshake_timeout: Option<Duration>,
+     max_runtime: Option<Duration>,
+     greeting: Option<String>,
+     max_response_frame: usize,
+     max_pending_handshakes: Option<usize>,
+     magic: Option<[u8; 2]>,
+     /// Queue of the thread writing to the tee sink.
+     tee: Option<SyncSender<String>>,
+     event_driven_accept: bool,
+     cork_bursts: bool,
+     max_queued_frames: Option<usize>,
+     slow_client: Option<(Duration, SlowClientPolicy)>,
+     middleware: Vec<Arc<dyn Middleware>>,
+     control_channels: bool,
+     audit_level: Level,
+ }
+
+ /// Byte counters shared by all connections of a server.
+ #[derive(Default)]
+ struct IoStats {
+     /// Bytes read from clients, frame headers included.
+     bytes_read: AtomicU64,
+     /// Bytes written to clients, frame headers included.
+     bytes_written: AtomicU64,
+ }
+
+ /// Request and connection counters shared by all connections of a server.
+ #[derive(Default)]
+ struct ServerCounters {
+     /// Encoded size of the requests decoded but not yet answered.
+     queued_bytes: AtomicUsize,
+     /// Connections that have not completed the Hello handshake yet.
+     pending_handshakes: AtomicUsize,
+     /// Requests answered with a regular response.
+     requests_handled: AtomicU64,
+     /// Requests and frames answered with an `ErrorResponse`.
+     requests_rejected: AtomicU64,
+     /// Requests received but never answered.
+     requests_dropped: AtomicU64,
+     /// Requests handled per tenant label announced in the Hello handshake.
+     tenant_messages: Mutex<HashMap<String, Arc<AtomicU64>>>,
+     /// Payload sizes of the non-empty frames received.
+     message_sizes: Mutex<SizeSamples>,
+ }
+
+ /// Sessions opened by data connections, with the control channel attached to each.
+ #[derive(Default)]
+ struct Sessions {
+     /// Control channel of each open session; `None` until its control connection arrives.
+     channels: Mutex<HashMap<String, Option<Sender<server_message::Message>>>>,
+ }
+
+ impl Sessions {
+     fn lock(
+         &self,
+     ) -> std::sync::MutexGuard<'_, HashMap<String, Option<Sender<server_message::Message>>>> {
+         self.channels.lock().unwrap_or_else(|e| e.into_inner())
+     }
+
+     /*
+      * \brief Opens a session for a data connection.
+      *
+      * Anyone who knows a session's id can attach a control channel to it, so ids are
+      * 128 random bits from the operating system rather than anything a peer could guess.
+      *
+      * \return The id of the new session, or the error if no random bits were available.
+      */
+     fn open(&self) -> Result<String, getrandom::Error> {
+         let mut bits = [0u8; 16];
+         getrandom::fill(&mut bits)?;
+         let id: String = bits.iter().map(|byte| format!("{:02x}", byte)).collect();
+         self.lock().insert(id.clone(), None);
+         Ok(id)
+     }
+
+     /*
+      * \brief Attaches a control channel to an open session.
+      *
+      * \param id The session to attach to.
+      * \return The receiving end of the channel, or `None` if the session does not exist or
+      *         already has a control channel.
+      */
+     fn attach(&self, id: &str) -> Option<Receiver<server_message::Message>> {
+         let mut channels = self.lock();
+         let channel = channels.get_mut(id).filter(|channel| channel.is_none())?;
+         let (sender, receiver) = mpsc::channel();
+         *channel = Some(sender);
+         Some(receiver)
+     }
+ }
+
+ /// Represents a client connected to the server over a TCP or in-process transport.
+ struct Client<S = TcpStream> {
+     stream: S,
+     peer: SocketAddr,
+     awaiting_magic: bool,
+     /// Whether the connection has not sent a request yet and counts as a pending handshake.
+     handshake_pending: bool,
+     config: Arc<Config>,
+     draining: Arc<AtomicBool>,
+     io_stats: Arc<IoStats>,
+     counters: Arc<ServerCounters>,
+     decoder: FrameDecoder,
+     queue: VecDeque<QueuedRequest>,
+     queued_bytes: usize,
+     protocol_version: Option<u32>,
+     /// Request counter of the tenant named in the Hello, if it is counted.
+     tenant: Option<Arc<AtomicU64>>,
+     handler: Option<Box<MessageHandler>>,
+     processing_time: Duration,
+     unknown_message_log: LogThrottle,
+     decode_error_log: LogThrottle,
+     slow_client_log: LogThrottle,
+     slow_write: Option<Duration>,
+     sessions: Arc<Sessions>,
+     /// Session opened by this connection and closed when it ends, or the one it controls.
+     session_id: Option<String>,
+     /// Notifications to push, if this is a control connection.
+     notifications: Option<Receiver<server_message::Message>>,
+     /// Whether the stream is corked for a burst of responses.
+     corked: bool,
+     /// Responses queued by the async handler, and the sending end shared by its senders.
+     send_queue: (Arc<SendQueue>, Receiver<QueuedResponse>),
  }
- 
- impl Client {
+
+ impl<S: Transport> Client<S> {
+     /*
+      * \brief Constructs a new `Client` instance.
+      *
+      * This function initializes a `Client` with the given stream, which represents
+      * the connection between the server and the client.
+      *
+      * \param stream The stream representing the client's connection.
+      * \param peer The address of the client.
+      * \param config The server settings shared by all handlers.
+      * \param draining Flag raised by the server when it shuts down gracefully.
+      * \param io_stats The server's byte counters.
+      * \param counters The server's request and connection counters.
+      * \param sessions The server's open sessions and their control channels.
+      * \return A new `Client` instance.
+      */
+     fn new(
+         stream: S,
+         peer: SocketAddr,
+         config: Arc<Config>,
+         draining: Arc<AtomicBool>,
+         io_stats: Arc<IoStats>,
+         counters: Arc<ServerCounters>,
+         sessions: Arc<Sessions>,
+     ) -> Self {
+         counters.pending_handshakes.fetch_add(1, atomic::Ordering::SeqCst);
+         let (sender, receiver) = mpsc::channel();
+         Client {
+             stream,
+             peer,
+             awaiting_magic: config.magic.is_some(),
+             handshake_pending: true,
+             config,
+             draining,
+             io_stats,
+             counters,
+             decoder: FrameDecoder::new(),
+             queue: VecDeque::new(),
+             queued_bytes: 0,
+             protocol_version: None,
+             tenant: None,
+             handler: None,
+             processing_time: Duration::ZERO,
+             unknown_message_log: LogThrottle::default(),
+             decode_error_log: LogThrottle::default(),
+             slow_client_log: LogThrottle::default(),
+             slow_write: None,
+             sessions,
+             session_id: None,
+             notifications: None,
+             corked: false,
+             send_queue: (Arc::new(Mutex::new(Some(sender))), receiver),
+         }
+     }
+
+     /*
+      * \brief Handles communication with the client.
+      *
+      * This function continuously reads frames from the client, decodes them into the
+      * handler queue and answers the queued requests in the order they arrived, so a
+      * client that pipelines requests can match each response to its request. The socket
+      * is only read in blocking mode when the queue is empty.
+      *
+      * Once the server starts draining, the handler stops waiting for new data: it collects
+      * whatever the client has already sent, answers every complete request, sends the
+      * configured redirect and shutdown notice and then closes the connection.
+      *
+      * A client that vanishes while a response is being written, or that resets the
+      * connection, has simply disconnected: this is logged at info level and the handler
+      * returns `Ok`.
+      *
+      * Reading stops while `MAX_QUEUED_BYTES` of requests, or the configured number of
+      * frames, are waiting for an answer. A client that sends faster than it reads then
+      * fills the socket buffers until its own writes block, instead of making the handler
+      * buffer an unbounded backlog: besides the queue, at most one partial frame and one
+      * read chunk are held in memory.
+      *
+      * If a greeting is configured, it is sent before anything is read from the client.
+      * The connection's custom handler, if any, is created here so that it lives on the
+      * handler thread for the whole session. The time spent answering requests adds up;
+      * once it exceeds the configured session timeout, the connection is closed. After
+      * each request, a response that was slow to send triggers the slow client policy.
+      *
+      * On a control connection, the notifications pushed to its session are written
+      * between requests, and the connection is closed once the session ends. Responses
+      * queued by the async handler are written between requests too; while the client
+      * is idle, they wait for the current read to time out, at most `SHUTDOWN_POLL_INTERVAL`.
+      * When the connection ends, the send queue is closed and what it still holds is
+      * written before the handler returns.
+      *
+      * \return A result indicating success (`Ok`) or failure (`Err`).
+      */
+     pub fn handle(&mut self) -> io::Result<()> {
+         let result = self.serve();
+         self.close_send_queue();
+         match (result, self.push_queued_responses()) {
+             (Err(e), _) => Err(e),
+             // Nothing can be written to a client that has gone away
+             (Ok(()), Err(ref e)) if is_disconnect(e) => Ok(()),
+             (Ok(()), flushed) => flushed,
+         }
+     }
+
+     /*
+      * \brief Serves requests until the connection ends, for `handle`.
+      *
+      * \return A result indicating success (`Ok`) or failure (`Err`).
+      */
+     fn serve(&mut self) -> io::Result<()> {
+         self.handler = self.config.handler_factory.as_ref().map(|factory| factory());
+         if let Some(server_name) = self.config.greeting.clone() {
+             self.write_message(server_message::Message::Greeting(Greeting {
+                 server_name,
+                 version: env!("CARGO_PKG_VERSION").to_string(),
+             }))?;
+         }
+         let mut connected = true;
+         let mut shutting_down = false;
+         let handshake_deadline =
+             self.config.handshake_timeout.map(|timeout| Instant::now() + timeout);
+         self.stream.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+         if let Some((threshold, SlowClientPolicy::Disconnect)) = self.config.slow_client {
+             self.stream.set_write_timeout(Some(threshold))?;
+         }
+
+         // Keep handling messages as long as the client is connected or requests are queued
+         loop {
+             // Frames left in the decoder by a full queue go first; reading waits until none
+             // are left
+             self.enqueue_frames()?;
+             self.push_queued_responses()?;
+             if !self.push_notifications()? {
+                 info!("Closing control connection: its session has ended.");
+                 return Ok(());
+             }
+
+             // Hello is optional, so any request ends the handshake phase
+             if self.handshake_pending
+                 && handshake_deadline.is_some_and(|deadline| Instant::now() >= deadline)
+             {
+                 let reason = format!(
+                     "no handshake within {:?}",
+                     self.config.handshake_timeout.unwrap_or_default()
+                 );
+                 audit(self.config.audit_level, "reject", self.peer, &reason);
+                 return Ok(());
+             }
+
+             if connected && !self.queue_full() {
+                 let draining = self.draining.load(atomic::Ordering::SeqCst);
+                 self.stream.set_nonblocking(draining || !self.queue.is_empty())?;
+                 let read = self.decoder.read_from(&mut self.stream);
+                 self.stream.set_nonblocking(false)?;
+
+                 match read {
+                     Ok(0) => {
+                         // A frame cut short by the disconnect can never complete
+                         if self.decoder.buffered_len() > 0 {
+                             info!(
+                                 "Client disconnected mid-frame, discarding {} bytes.",
+                                 self.decoder.buffered_len()
+                             );
+                         }
+                         connected = false;
+                     }
+                     Ok(bytes) => {
+                         self.io_stats
+                             .bytes_read
+                             .fetch_add(bytes as u64, atomic::Ordering::Relaxed);
+                         self.enqueue_frames()?
+                     }
+                     Err(ref e)
+                         if e.kind() == ErrorKind::WouldBlock
+                             || e.kind() == ErrorKind::TimedOut =>
+                     {
+                         // Nothing left in the socket; when draining, finish what is queued
+                         // and close
+                         if draining {
+                             info!("Closing client connection for shutdown.");
+                             // Complete frames are queued by now, so what is left can never
+                             // complete
+                             if self.decoder.buffered_len() > 0 {
+                                 info!(
+                                     "Discarding a request cut short by the shutdown, {} bytes.",
+                                     self.decoder.buffered_len()
+                                 );
+                                 self.counters
+                                     .requests_dropped
+                                     .fetch_add(1, atomic::Ordering::Relaxed);
+                             }
+                             connected = false;
+                             shutting_down = true;
+                         }
+                     }
+                     Err(ref e) if e.kind() == ErrorKind::Interrupted => {
+                         // A signal arrived before any data; the next iteration reads again
+                         debug!("Read from client interrupted, retrying");
+                     }
+                     Err(ref e) if is_disconnect(e) => {
+                         info!("Client connection lost: {}", e);
+                         return Ok(()); // Nothing can be answered on a reset connection
+                     }
+                     Err(e) => {
+                         error!("Error reading from client: {}", e);
+                         return Err(e); // Error while reading from client
+                     }
+                 }
+             }
+
+             match self.pop_request() {
+                 Some(request) => {
+                     let started = Instant::now();
+                     // Responses to pipelined requests go out together once the queue runs dry
+                     if self.config.cork_bursts && !self.queue.is_empty() {
+                         self.set_corked(true)?;
+                     }
+                     let result = self.process(request);
+                     if self.queue.is_empty() {
+                         self.set_corked(false)?;
+                     }
+                     if let Some(elapsed) = self.slow_write.take() {
+                         if !self.tolerate_slow_client(elapsed) {
+                             if result.is_err() {
+                                 self.counters
+                                     .requests_dropped
+                                     .fetch_add(1, atomic::Ordering::Relaxed);
+                             }
+                             return Ok(()); // The policy asks to let the slow client go
+                         }
+                     }
+                     match result {
+                         Err(ref e) if is_disconnect(e) => {
+                             info!("Client disconnected before the response was sent: {}", e);
+                             self.counters.requests_dropped.fetch_add(1, atomic::Ordering::Relaxed);
+                             return Ok(()); // The peer left; that is not a server error
+                         }
+                         result => result?,
+                     }
+                     self.processing_time += started.elapsed();
+                     if self
+                         .config
+                         .session_timeout
+                         .is_some_and(|timeout| self.processing_time > timeout)
+                     {
+                         warn!(
+                             "Closing client connection after {:?} of processing.",
+                             self.processing_time
+                         );
+                         return Ok(()); // The session used up its processing budget
+                     }
+                 }
+                 None if shutting_down => {
+                     // The notice is the last thing the client is sent
+                     self.close_send_queue();
+                     self.push_queued_responses()?;
+                     self.send_shutdown_notice();
+                     return Ok(()); // Server is draining and nothing is left to answer
+                 }
+                 None if !connected => {
+                     info!("Client disconnected.");
+                     return Ok(()); // Client disconnected and nothing is left to answer
+                 }
+                 None => {}
+             }
+         }
+     }
+
+     /*
+      * \brief Reads until one complete request has arrived, answers it and returns.
+      *
+      * Malformed and unknown messages are answered as usual and do not count as the
+      * request. Anything the client sent after the request is discarded.
+      *
+      * \return The kind of the answered request, or an `UnexpectedEof` error if the client
+      *         disconnected first.
+      */
+     fn handle_one(&mut self) -> io::Result<MessageKind> {
+         self.handler = self.config.handler_factory.as_ref().map(|factory| factory());
+         loop {
+             self.enqueue_frames()?;
+             if let Some(request) = self.pop_request() {
+                 let kind = request.message.as_ref().ok().map(kind);
+                 self.process(request)?;
+                 match kind {
+                     Some(kind) => return Ok(kind),
+                     None => continue, // Only an error was answered
+                 }
+             }
+             let read = match self.decoder.read_from(&mut self.stream) {
+                 Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                 read => read?,
+             };
+             match read {
+                 0 => {
+                     return Err(io::Error::new(
+                         ErrorKind::UnexpectedEof,
+                         "Client disconnected before sending a request",
+                     ))
+                 }
+                 bytes => {
+                     self.io_stats.bytes_read.fetch_add(bytes as u64, atomic::Ordering::Relaxed)
+                 }
+             };
+         }
+     }
+
+     /*
+      * \brief Writes the notifications waiting for a control connection.
+      *
+      * \return `false` once the session the connection controls has ended.
+      */
+     fn push_notifications(&mut self) -> io::Result<bool> {
+         let Some(ref notifications) = self.notifications else {
+             return Ok(true);
+         };
+         let mut pending = Vec::new();
+         let open = loop {
+             match notifications.try_recv() {
+                 Ok(notification) => pending.push(notification),
+                 Err(TryRecvError::Empty) => break true,
+                 Err(TryRecvError::Disconnected) => break false,
+             }
+         };
+         for notification in pending {
+             self.write_message(notification)?;
+         }
+         Ok(open)
+     }
+
+     /*
+      * \brief Corks or uncorks the stream, unless it already is.
+      *
+      * \param corked Whether to hold back partial packets.
+      * \return A result indicating success (`Ok`) or failure (`Err`).
+      */
+     fn set_corked(&mut self, corked: bool) -> io::Result<()> {
+         if self.corked != corked {
+             self.stream.set_corked(corked)?;
+             self.corked = corked;
+         }
+         Ok(())
+     }
+
+     /*
+      * \brief Writes the responses the async handler queued since the last call.
+      *
+      * \return A result indicating success (`Ok`) or failure (`Err`) of the writes.
+      */
+     fn push_queued_responses(&mut self) -> io::Result<()> {
+         let pending: Vec<_> = self.send_queue.1.try_iter().collect();
+         for QueuedResponse { mut response, counted } in pending {
+             if let Some(message) = response.message.as_mut() {
+                 self.apply_response_middleware(message);
+             }
+             let rejected =
+                 matches!(response.message, Some(server_message::Message::ErrorResponse(_)));
+             let sent = self.write_response(response)?;
+             // A request answered with several responses counts once
+             if !counted.swap(true, atomic::Ordering::Relaxed) {
+                 let outcome = if rejected || !sent {
+                     &self.counters.requests_rejected
+                 } else {
+                     &self.counters.requests_handled
+                 };
+                 outcome.fetch_add(1, atomic::Ordering::Relaxed);
+             }
+         }
+         Ok(())
+     }
+
+     /*
+      * \brief Makes later sends through the async handler's senders fail.
+      *
+      * Every response a sender accepted before is in the queue once this returns.
+      */
+     fn close_send_queue(&self) {
+         self.send_queue.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+     }
+
+     /*
+      * \brief Tells whether the handler queue must not take more requests for now.
+      *
+      * \return `true` once the queue holds `MAX_QUEUED_BYTES` of requests or the configured
+      *         maximum number of frames.
+      */
+     fn queue_full(&self) -> bool {
+         self.queued_bytes >= MAX_QUEUED_BYTES
+             || self.config.max_queued_frames.is_some_and(|limit| self.queue.len() >= limit)
+     }
+
+     /*
+      * \brief Applies the slow client policy after a response took too long to send.
+      *
+      * \param elapsed How long the slow write blocked.
+      * \return `false` if the connection must be closed.
+      */
+     fn tolerate_slow_client(&mut self, elapsed: Duration) -> bool {
+         let Some((threshold, policy)) = self.config.slow_client else {
+             return true;
+         };
+         if policy == SlowClientPolicy::Disconnect {
+             warn!(
+                 "Closing connection to a slow client: a response took over {:?} to send.",
+                 threshold
+             );
+             return false;
+         }
+         if let Some(suppressed) = self.slow_client_log.admit() {
+             warn!(
+                 "Slow client: a response took {:?} to send{}",
+                 elapsed,
+                 suppressed_note(suppressed)
+             );
+         }
+         if let SlowClientPolicy::Throttle(pause) = policy {
+             debug!("Pausing the connection for {:?} to let the client catch up", pause);
+             thread::sleep(pause);
+         }
+         true
+     }
+
+     /// Takes the oldest queued request, releasing the bytes it held.
+     fn pop_request(&mut self) -> Option<QueuedRequest> {
+         let request = self.queue.pop_front()?;
+         self.queued_bytes -= request.size;
+         self.counters.queued_bytes.fetch_sub(request.size, atomic::Ordering::Relaxed);
+         Some(request)
+     }
+
+     /*
+      * \brief Moves every complete frame from the decoder into the handler queue.
+      *
+      * Zero-length frames are keepalives and are skipped without a response. Frames that
+      * cannot be decoded as a `ClientMessage` are queued as the `ErrorResponse` answering
+      * them, so the error goes out after the responses to earlier requests. An invalid
+      * frame header is reported at once before the connection is given up, since the
+      * stream can no longer be split into frames. Frames carrying an unknown message
+      * variant are handled according to the configured policy. Once the queue is full,
+      * the remaining frames stay in the decoder. With a magic number configured, nothing
+      * is decoded before it has arrived.
+      *
+      * \return An error if the frame header or magic is invalid or the connection must be closed.
+      */
+     fn enqueue_frames(&mut self) -> io::Result<()> {
+         if self.awaiting_magic {
+             let Some(prefix) = self.decoder.take_raw(2) else {
+                 return Ok(());
+             };
+             if Some(prefix.as_ref()) != self.config.magic.as_ref().map(|magic| magic.as_slice()) {
+                 audit(
+                     self.config.audit_level,
+                     "reject",
+                     self.peer,
+                     "connection does not start with the protocol magic",
+                 );
+                 return Err(io::Error::new(
+                     ErrorKind::InvalidData,
+                     format!(
+                         "Connection does not start with the protocol magic, got {:02x?}",
+                         prefix.as_ref()
+                     ),
+                 ));
+             }
+             self.awaiting_magic = false;
+         }
+         while !self.queue_full() {
+             let payload = match self.decoder.next_frame() {
+                 Ok(Some(payload)) => payload,
+                 Ok(None) => return Ok(()),
+                 Err(e) => {
+                     error!("Invalid frame header: {}", e);
+                     self.send_error(ErrorCode::MalformedMessage, e.to_string())?;
+                     return Err(e);
+                 }
+             };
+
+             if payload.is_empty() {
+                 debug!("Received keepalive frame");
+                 continue;
+             }
+
+             let size = payload.len();
+             self.counters.message_sizes.lock().unwrap_or_else(|e| e.into_inner()).record(size);
+             let decoded = ClientMessage::decode(payload.clone());
+             if let Ok(ref request) = decoded {
+                 self.tee("->", request);
+                 self.end_handshake();
+             }
+             // prost drops unknown fields, so they are looked for in the frame itself
+             let unknown = match decoded {
+                 Ok(ClientMessage { message: Some(_), .. }) if self.config.strict_decoding => {
+                     unknown_field(&payload, &CLIENT_MESSAGE)
+                 }
+                 _ => Ok(None),
+             };
+             let message = match decoded {
+                 Ok(_) if !matches!(unknown, Ok(None)) => {
+                     if let Some(suppressed) = self.decode_error_log.admit() {
+                         match unknown {
+                             Ok(field) => error!(
+                                 "Rejected a message with unknown field {:?}{}",
+                                 field,
+                                 suppressed_note(suppressed)
+                             ),
+                             Err(e) => error!(
+                                 "Rejected a message with unreadable fields: {}{}",
+                                 e,
+                                 suppressed_note(suppressed)
+                             ),
+                         }
+                     }
+                     Err((
+                         ErrorCode::MalformedMessage,
+                         "Message carries unknown fields".to_string(),
+                     ))
+                 }
+                 Ok(ClientMessage { message: Some(message), dry_run, client_send_ts }) => {
+                     self.enqueue(QueuedRequest {
+                         size,
+                         message: Ok(message),
+                         client_send_ts,
+                         server_recv_ts: if client_send_ts != 0 { unix_micros() } else { 0 },
+                         dry_run,
+                     });
+                     continue;
+                 }
+                 Ok(ClientMessage { message: None, .. }) => match self.config.unknown_message {
+                     UnknownMessagePolicy::Error => {
+                         if let Some(suppressed) = self.unknown_message_log.admit() {
+                             warn!("Received an unknown message{}", suppressed_note(suppressed));
+                         }
+                         Err((ErrorCode::UnknownMessage, "Unknown message".to_string()))
+                     }
+                     UnknownMessagePolicy::Ignore => {
+                         debug!("Ignoring an unknown message");
+                         continue;
+                     }
+                     UnknownMessagePolicy::Close => {
+                         return Err(io::Error::new(
+                             ErrorKind::InvalidData,
+                             "Closing connection after an unknown message",
+                         ));
+                     }
+                 },
+                 Err(e) => {
+                     if let Some(suppressed) = self.decode_error_log.admit() {
+                         error!("Failed to decode message: {}{}", e, suppressed_note(suppressed));
+                     }
+                     Err((ErrorCode::MalformedMessage, format!("Failed to decode message: {}", e)))
+                 }
+             };
+             // Answered in turn, so the error does not overtake the responses to earlier requests
+             self.enqueue(QueuedRequest {
+                 size,
+                 message,
+                 client_send_ts: 0,
+                 server_recv_ts: 0,
+                 dry_run: false,
+             });
+         }
+         Ok(())
+     }
+
+     /*
+      * \brief Looks up the request counter of a tenant, creating it on its first connection.
+      *
+      * \param tenant The tenant label from the Hello.
+      * \return The counter, or `None` once `MAX_TENANTS` other tenants are already counted.
+      */
+     fn tenant_counter(&self, tenant: String) -> Option<Arc<AtomicU64>> {
+         let mut tenants = self.counters.tenant_messages.lock().unwrap_or_else(|e| e.into_inner());
+         if let Some(counter) = tenants.get(&tenant) {
+             return Some(counter.clone());
+         }
+         if tenants.len() >= MAX_TENANTS {
+             warn!(
+                 "Not counting the requests of tenant {:?}: {} tenants are counted already",
+                 tenant, MAX_TENANTS
+             );
+             return None;
+         }
+         Some(tenants.entry(tenant).or_default().clone())
+     }
+
+     /// Stops counting the connection as a pending handshake once it has sent a request.
+     fn end_handshake(&mut self) {
+         if self.handshake_pending {
+             self.handshake_pending = false;
+             self.counters.pending_handshakes.fetch_sub(1, atomic::Ordering::SeqCst);
+         }
+     }
+
+     /// Appends a request to the handler queue, accounting for the bytes it holds.
+     fn enqueue(&mut self, request: QueuedRequest) {
+         self.queued_bytes += request.size;
+         self.counters.queued_bytes.fetch_add(request.size, atomic::Ordering::Relaxed);
+         self.queue.push_back(request);
+     }
+
+     /*
+      * \brief Reports a request the server cannot answer to the client.
+      *
+      * \param code The kind of problem.
+      * \param message The description of the problem.
+      * \return A result indicating success (`Ok`) or failure (`Err`) of the write.
+      */
+     fn send_error(&mut self, code: ErrorCode, message: String) -> io::Result<()> {
+         self.write_message(server_message::Message::ErrorResponse(ErrorResponse {
+             message,
+             code: code.into(),
+         }))?;
+         self.counters.requests_rejected.fetch_add(1, atomic::Ordering::Relaxed);
+         Ok(())
+     }
+
+     /*
+      * \brief Sends one message to the client.
+      *
+      * A message that fits the configured response frame size is sent as a single frame.
+      * A larger one is encoded once and streamed as a series of `Chunk` messages, each
+      * carrying the next slice of the encoding; all but the last have `more` set, so the
+      * client knows when to decode the reassembled bytes.
+      *
+      * \param message The message to send.
+      * \return A result indicating success (`Ok`) or failure (`Err`) of the write.
+      */
+     fn write_message(&mut self, message: server_message::Message) -> io::Result<()> {
+         self.write_response(ServerMessage { message: Some(message), ..Default::default() })?;
+         Ok(())
+     }
+
+     /*
+      * \brief Sends a complete `ServerMessage`, chunked like `write_message` when needed.
+      *
+      * A response that cannot be encoded is never sent in part: the failure is logged and
+      * an `ErrorResponse` with `ErrorCode::Internal` goes out in its place, carrying the
+      * same timestamps.
+      *
+      * \param response The message to send, including any timestamps.
+      * \return `Ok(true)` if the response was sent, `Ok(false)` if the error was sent
+      *         instead, or an error if the write failed.
+      */
+     fn write_response(&mut self, response: ServerMessage) -> io::Result<bool> {
+         let payload = match encode_response(&response) {
+             Ok(payload) => payload,
+             Err(e) => {
+                 error!("Failed to encode a response of {} bytes: {}", response.encoded_len(), e);
+                 let fallback = ServerMessage {
+                     message: Some(server_message::Message::ErrorResponse(ErrorResponse {
+                         message: format!("The server could not encode its response: {}", e),
+                         code: ErrorCode::Internal.into(),
+                     })),
+                     ..response
+                 };
+                 self.write_response(fallback)?;
+                 return Ok(false);
+             }
+         };
+         self.tee("<-", &response);
+         self.write_encoded(&payload)?;
+         Ok(true)
+     }
+
+     /*
+      * \brief Sends an encoded response, split into `Chunk` messages if it is too large.
+      *
+      * \param payload The encoded `ServerMessage`.
+      * \return A result indicating success (`Ok`) or failure (`Err`) of the write.
+      */
+     fn write_encoded(&mut self, payload: &[u8]) -> io::Result<()> {
+         if payload.len() <= self.config.max_response_frame {
+             return self.write_payload(payload);
+         }
+
+         let chunk_size = self.config.max_response_frame - CHUNK_OVERHEAD;
+         let chunks = payload.len().div_ceil(chunk_size);
+         debug!("Streaming a {} byte response in {} chunks", payload.len(), chunks);
+         for (index, data) in payload.chunks(chunk_size).enumerate() {
+             let chunk = ServerMessage {
+                 message: Some(server_message::Message::Chunk(Chunk {
+                     data: Bytes::copy_from_slice(data),
+                     more: index + 1 < chunks,
+                 })),
+                 ..Default::default()
+             };
+             self.write_payload(&chunk.encode_to_vec())?;
+         }
+         Ok(())
+     }
+
+     /*
+      * \brief Copies a message to the configured tee sink, if any.
+      *
+      * The line is queued for the tee writer thread and never waits for it: when the
+      * writer has fallen `TEE_QUEUE_LINES` lines behind, the line is dropped.
+      *
+      * \param direction `->` for a request, `<-` for a response.
+      * \param message The message to copy.
+      */
+     fn tee(&self, direction: &str, message: &dyn std::fmt::Debug) {
+         let Some(ref sink) = self.config.tee else {
+             return;
+         };
+         match sink.try_send(format!("{} {} {:?}\n", self.peer, direction, message)) {
+             Ok(()) => {}
+             Err(TrySendError::Full(_)) => {
+                 debug!("The tee sink is falling behind, dropping a line")
+             }
+             Err(TrySendError::Disconnected(_)) => {
+                 debug!("The tee writer has stopped, dropping a line")
+             }
+         }
+     }
+
+     /*
+      * \brief Writes an encoded message as one frame and counts the bytes sent.
+      *
+      * \param payload The encoded message.
+      * \return A result indicating success (`Ok`) or failure (`Err`) of the write.
+      */
+     fn write_payload(&mut self, payload: &[u8]) -> io::Result<()> {
+         let started = Instant::now();
+         let result = frame::write_frame(&mut self.stream, payload);
+         if let Some((threshold, _)) = self.config.slow_client {
+             let timed_out = result
+                 .as_ref()
+                 .is_err_and(|e| matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut));
+             let elapsed = started.elapsed();
+             if timed_out || elapsed >= threshold {
+                 self.slow_write = Some(self.slow_write.unwrap_or_default().max(elapsed));
+             }
+         }
+         result?;
+         let written = (frame::HEADER_LEN + payload.len()) as u64;
+         self.io_stats.bytes_written.fetch_add(written, atomic::Ordering::Relaxed);
+         Ok(())
+     }
+
+     /*
+      * \brief Builds the response to a single request and sends it to the client.
+      *
+      * If the server has a worker pool, the request first waits for one of its workers.
+      *
+      * Echo requests are held back by the configured echo delay. The custom handler, if
+      * one is installed, gets the first chance to answer any request except the Hello
+      * handshake; requests it declines receive the built-in response. A request carrying a
+      * send timestamp gets it back together with the time the server received it. The
+      * middleware sees the request first and every response last. A queued frame that
+      * did not decode is answered with its error instead.
+      *
+      * \param request The request to answer.
+      * \return A result indicating success (`Ok`) or failure (`Err`).
+      */
+     fn process(&mut self, request: QueuedRequest) -> io::Result<()> {
+         let mut message = match request.message {
+             Ok(message) => message,
+             Err((code, description)) => return self.send_error(code, description),
+         };
+         let pool = self.config.worker_pool.clone();
+         let _worker = pool.as_ref().map(|pool| pool.acquire(Priority::of(&message)));
+         let started = Instant::now();
+         for layer in &self.config.middleware {
+             layer.on_request(&mut message);
+         }
+         let kind = kind(&message);
+         if let Some(tenant) = self.tenant.as_ref().filter(|_| kind != MessageKind::Hello) {
+             tenant.fetch_add(1, atomic::Ordering::Relaxed);
+         }
+         let timestamps = (request.client_send_ts, request.server_recv_ts);
+         let response = match self.validate(kind, &message) {
+             Err(reason) => {
+                 warn!("Rejected request: {}", reason);
+                 server_message::Message::ErrorResponse(ErrorResponse {
+                     message: reason,
+                     code: ErrorCode::Rejected.into(),
+                 })
+             }
+             Ok(()) if !request.dry_run && self.hand_off(kind, &message, timestamps) => {
+                 // Answered later through the send queue, and counted once written
+                 return Ok(());
+             }
+             Ok(()) => match self.stream_responses(kind, &message) {
+                 Some(responses) => {
+                     for mut response in responses {
+                         self.apply_response_middleware(&mut response);
+                         self.write_response(ServerMessage {
+                             message: Some(response),
+                             client_send_ts: request.client_send_ts,
+                             server_recv_ts: request.server_recv_ts,
+                         })?;
+                     }
+                     server_message::Message::StreamEnd(StreamEnd {})
+                 }
+                 None => self.dispatch(kind, message, request.dry_run),
+             },
+         };
+         let mut response = response;
+         self.apply_response_middleware(&mut response);
+         let rejected = matches!(response, server_message::Message::ErrorResponse(_));
+
+         let sent = self.write_response(ServerMessage {
+             message: Some(response),
+             client_send_ts: request.client_send_ts,
+             server_recv_ts: request.server_recv_ts,
+         })?;
+         let rejected = rejected || !sent;
+
+         let outcome = if rejected {
+             &self.counters.requests_rejected
+         } else {
+             &self.counters.requests_handled
+         };
+         outcome.fetch_add(1, atomic::Ordering::Relaxed);
+
+         info!("Sent response.");
+         if let Some(observer) = &self.config.message_observer {
+             observer(kind, started.elapsed(), self.peer);
+         }
+         Ok(())
+     }
+
+     /// Passes a response through the middleware, the last registered layer first.
+     fn apply_response_middleware(&self, response: &mut server_message::Message) {
+         for layer in self.config.middleware.iter().rev() {
+             layer.on_response(response);
+         }
+     }
+
+     /*
+      * \brief Runs the configured validator on a request.
+      *
+      * The Hello handshake is never validated, so a client can always negotiate. An
+      * empty echo is refused first if the empty echo policy says so.
+      *
+      * \param kind The variant of the request.
+      * \param message The request to check.
+      * \return `Err` with the reason if the request must not be handled.
+      */
+     fn validate(
+         &self,
+         kind: MessageKind,
+         message: &client_message::Message,
+     ) -> Result<(), String> {
+         if let client_message::Message::EchoMessage(echo) = message {
+             if echo.content.is_empty() && self.config.empty_echo == EmptyEchoPolicy::Reject {
+                 return Err("Echo content is empty".to_string());
+             }
+         }
+         match (&self.config.validator, kind) {
+             (_, MessageKind::Hello) | (None, _) => Ok(()),
+             (Some(validator), _) => validator(message),
+         }
+     }
+
+     /*
+      * \brief Offers a request to the async handler.
+      *
+      * \param kind The variant of the request.
+      * \param message The request to answer.
+      * \param timestamps The request's client send and server receive times, carried by
+      *        the responses.
+      * \return `true` if the async handler took the request and will answer it.
+      */
+     fn hand_off(
+         &self,
+         kind: MessageKind,
+         message: &client_message::Message,
+         (client_send_ts, server_recv_ts): (u64, u64),
+     ) -> bool {
+         match (&self.config.async_handler, kind) {
+             (_, MessageKind::Hello) | (None, _) => false,
+             (Some(async_handler), _) => {
+                 let sender = ResponseSender {
+                     queue: self.send_queue.0.clone(),
+                     client_send_ts,
+                     server_recv_ts,
+                     counted: Arc::new(AtomicBool::new(false)),
+                 };
+                 async_handler(message, &sender)
+             }
+         }
+     }
+
+     /*
+      * \brief Asks the stream handler for the responses to a request.
+      *
+      * \param kind The variant of the request.
+      * \param message The request to answer.
+      * \return The responses, or `None` if the request is not answered as a stream.
+      */
+     fn stream_responses(
+         &self,
+         kind: MessageKind,
+         message: &client_message::Message,
+     ) -> Option<Vec<server_message::Message>> {
+         match (&self.config.stream_handler, kind) {
+             (_, MessageKind::Hello) | (None, _) => None,
+             (Some(stream_handler), _) => stream_handler(message),
+         }
+     }
+
+     /*
+      * \brief Answers a request with the custom handler, falling back to the built-in response.
+      *
+      * \param kind The variant of the request.
+      * \param message The request to answer.
+      * \param dry_run Whether the custom handler must leave its state untouched.
+      * \return The response message.
+      */
+     fn dispatch(
+         &mut self,
+         kind: MessageKind,
+         message: client_message::Message,
+         dry_run: bool,
+     ) -> server_message::Message {
+         if let MessageKind::Echo | MessageKind::BinaryEcho = kind {
+             if !self.config.echo_delay.is_zero() {
+                 thread::sleep(self.config.echo_delay);
+             }
+         }
+
+         // A batch is handed to the custom handler one operation at a time
+         let custom = match kind {
+             MessageKind::Hello | MessageKind::Batch => None,
+             _ => self.handler.as_mut().and_then(|handler| handler(&message, dry_run)),
+         };
+         match custom {
+             Some(response) => response,
+             None => self.builtin_response(message, dry_run),
+         }
+     }
+
+     /*
+      * \brief Answers every operation of a batch, each on its own.
+      *
+      * An operation goes through validation and the custom handler like a request of its
+      * own, and one that fails gets an `ErrorResponse` in its slot without stopping the
+      * rest. Handshakes and nested batches cannot be batched.
+      *
+      * \param batch The operations to answer.
+      * \param dry_run Whether the custom handler must leave its state untouched.
+      * \return A `BatchResponse` with one result per operation, in order.
+      */
+     fn batch_response(&mut self, batch: BatchRequest, dry_run: bool) -> server_message::Message {
+         info!("Received: batch of {} operations", batch.operations.len());
+         let mut results = Vec::with_capacity(batch.operations.len());
+         for operation in batch.operations {
+             let response = match operation.message.map(|message| (kind(&message), message)) {
+                 None => server_message::Message::ErrorResponse(ErrorResponse {
+                     message: "Batch operation carries no message".to_string(),
+                     code: ErrorCode::MalformedMessage.into(),
+                 }),
+                 Some((kind @ (MessageKind::Hello | MessageKind::Batch), _)) => {
+                     server_message::Message::ErrorResponse(ErrorResponse {
+                         message: format!("{:?} requests cannot be batched", kind),
+                         code: ErrorCode::Rejected.into(),
+                     })
+                 }
+                 Some((kind, message)) => match self.validate(kind, &message) {
+                     Err(reason) => server_message::Message::ErrorResponse(ErrorResponse {
+                         message: reason,
+                         code: ErrorCode::Rejected.into(),
+                     }),
+                     Ok(()) => self.dispatch(kind, message, dry_run || operation.dry_run),
+                 },
+             };
+             results.push(ServerMessage { message: Some(response), ..Default::default() });
+         }
+         server_message::Message::BatchResponse(BatchResponse { results })
+     }
+
+     /*
+      * \brief Tells the client where to reconnect and that the server is going away, as configured.
+      *
+      * The redirect goes first, so a client acting on it never mistakes the shutdown
+      * notice for the end of the service. Both are best effort: the client may already be gone.
+      */
+     fn send_shutdown_notice(&mut self) {
+         if let Some((host, port)) = self.config.redirect.clone() {
+             let redirect = server_message::Message::Redirect(Redirect { host, port: port.into() });
+             if let Err(e) = self.write_message(redirect) {
+                 info!("Could not deliver redirect: {}", e);
+                 return;
+             }
+         }
+         let Some(reason) = self.config.shutdown_notice.clone() else {
+             return;
+         };
+         if let Err(e) = self.write_message(server_message::Message::Shutdown(Shutdown { reason }))
+         {
+             info!("Could not deliver shutdown notice: {}", e);
+         }
+     }
+
+     /*
+      * \brief Computes the server's standard answer to a request.
+      *
+      * \param message The request to answer.
+      * \param dry_run Whether the operations of a batch are dry runs.
+      * \return The response to send back.
+      */
+     fn builtin_response(
+         &mut self,
+         message: client_message::Message,
+         dry_run: bool,
+     ) -> server_message::Message {
+         match message {
+             client_message::Message::EchoMessage(echo) => {
+                 info!("Received: {}", echo.content);
+                 server_message::Message::EchoMessage(echo)
+             }
+             client_message::Message::BinaryEcho(echo) => {
+                 info!("Received: {} bytes", echo.data.len());
+                 server_message::Message::BinaryEcho(echo)
+             }
+             client_message::Message::AddRequest(add) => {
+                 info!("Received: {} + {}", add.a, add.b);
+                 let sum = add.a.wrapping_add(add.b);
+                 server_message::Message::AddResponse(AddResponse {
+                     result: sum as i32,
+                     result64: sum,
+                 })
+             }
+             client_message::Message::DivideRequest(divide) => {
+                 info!("Received: {} / {}", divide.a, divide.b);
+                 if divide.b == 0 {
+                     return server_message::Message::ErrorResponse(ErrorResponse {
+                         message: "Division by zero".to_string(),
+                         code: ErrorCode::InvalidArgument.into(),
+                     });
+                 }
+                 server_message::Message::DivideResponse(DivideResponse {
+                     result: divide.a.wrapping_div(divide.b),
+                 })
+             }
+             client_message::Message::BatchRequest(batch) => self.batch_response(batch, dry_run),
+             client_message::Message::PingRequest(_) => {
+                 info!("Received: ping");
+                 server_message::Message::PongResponse(PongResponse {})
+             }
+             client_message::Message::CapabilitiesRequest(_) => {
+                 info!("Received: capabilities request");
+                 server_message::Message::CapabilitiesResponse(CapabilitiesResponse {
+                     features: capabilities(&self.config),
+                 })
+             }
+             client_message::Message::HelloRequest(hello) => {
+                 if hello.tenant.len() > MAX_TENANT_LEN {
+                     let reason = format!(
+                         "tenant label of {} bytes exceeds {}",
+                         hello.tenant.len(),
+                         MAX_TENANT_LEN
+                     );
+                     audit(self.config.audit_level, "reject", self.peer, &reason);
+                     return server_message::Message::ErrorResponse(ErrorResponse {
+                         message: format!("Tenant labels are limited to {} bytes", MAX_TENANT_LEN),
+                         code: ErrorCode::Rejected.into(),
+                     });
+                 }
+                 if hello.control && self.protocol_version.is_none() {
+                     if !self.config.control_channels {
+                         let reason = "control channels are not enabled";
+                         audit(self.config.audit_level, "reject", self.peer, reason);
+                         return server_message::Message::ErrorResponse(ErrorResponse {
+                             message: "Control channels are not enabled".to_string(),
+                             code: ErrorCode::Rejected.into(),
+                         });
+                     }
+                     let Some(notifications) = self.sessions.attach(&hello.session_id) else {
+                         let reason = format!(
+                             "no session {:?} to attach a control channel to",
+                             hello.session_id
+                         );
+                         audit(self.config.audit_level, "reject", self.peer, &reason);
+                         return server_message::Message::ErrorResponse(ErrorResponse {
+                             message: format!(
+                                 "Session {:?} is not open or already has a control channel",
+                                 hello.session_id
+                             ),
+                             code: ErrorCode::Rejected.into(),
+                         });
+                     };
+                     info!("Control connection attached to session {}", hello.session_id);
+                     self.session_id = Some(hello.session_id);
+                     self.notifications = Some(notifications);
+                 } else if self.config.control_channels && self.protocol_version.is_none() {
+                     match self.sessions.open() {
+                         Ok(id) => self.session_id = Some(id),
+                         Err(e) => {
+                             error!("Failed to open a session for {}: {}", self.peer, e);
+                             return server_message::Message::ErrorResponse(ErrorResponse {
+                                 message: "Failed to open a session".to_string(),
+                                 code: ErrorCode::Internal.into(),
+                             });
+                         }
+                     }
+                 }
+                 // A repeated Hello keeps the version agreed the first time
+                 if self.protocol_version.is_none() && !hello.tenant.is_empty() {
+                     self.tenant = self.tenant_counter(hello.tenant);
+                 }
+                 let version = *self
+                     .protocol_version
+                     .get_or_insert(hello.protocol_version.clamp(1, PROTOCOL_VERSION));
+                 info!(
+                     "Received: hello (client v{}), using protocol v{}",
+                     hello.protocol_version, version
+                 );
+                 server_message::Message::HelloResponse(HelloResponse {
+                     protocol_version: version,
+                     session_id: self.session_id.clone().unwrap_or_default(),
+                 })
+             }
+         }
+     }
+ }
+
+ impl<S> Drop for Client<S> {
+     fn drop(&mut self) {
+         // Requests still queued when the connection ends are never answered
+         self.counters.queued_bytes.fetch_sub(self.queued_bytes, atomic::Ordering::Relaxed);
+         self.counters
+             .requests_dropped
+             .fetch_add(self.queue.len() as u64, atomic::Ordering::Relaxed);
+         if self.handshake_pending {
+             self.counters.pending_handshakes.fetch_sub(1, atomic::Ordering::SeqCst);
+         }
+         if let Some(id) = self.session_id.as_ref().filter(|_| self.notifications.is_none()) {
+             // Dropping the sender lets the control connection close
+             self.sessions.lock().remove(id);
+         }
+         if self.unknown_message_log.suppressed > 0 {
+             warn!(
+                 "Received {} more unknown messages before disconnecting",
+                 self.unknown_message_log.suppressed
+             );
+         }
+         if self.decode_error_log.suppressed > 0 {
+             error!(
+                 "Failed to decode {} more messages before disconnecting",
+                 self.decode_error_log.suppressed
+             );
+         }
+         if self.slow_client_log.suppressed > 0 {
+             warn!(
+                 "Client was slow {} more times before disconnecting",
+                 self.slow_client_log.suppressed
+             );
+         }
+     }
+ }
+
+ /// \brief Field numbers a message defines, each with the schema of the message it holds, if any.
+ struct Schema(&'static [(u32, Option<&'static Schema>)]);
+
+ static ECHO_MESSAGE: Schema = Schema(&[(1, None)]);
+ static ADD_REQUEST: Schema = Schema(&[(1, None), (2, None)]);
+ static PING_REQUEST: Schema = Schema(&[]);
+ static BINARY_ECHO: Schema = Schema(&[(1, None)]);
+ static CAPABILITIES_REQUEST: Schema = Schema(&[]);
+ static HELLO_REQUEST: Schema = Schema(&[(1, None), (2, None), (3, None), (4, None)]);
+ static DIVIDE_REQUEST: Schema = Schema(&[(1, None), (2, None)]);
+ static BATCH_REQUEST: Schema = Schema(&[(1, Some(&CLIENT_MESSAGE))]);
+ static CLIENT_MESSAGE: Schema = Schema(&[
+     (1, Some(&ECHO_MESSAGE)),
+     (2, Some(&ADD_REQUEST)),
+     (3, Some(&PING_REQUEST)),
+     (4, Some(&BINARY_ECHO)),
+     (5, Some(&CAPABILITIES_REQUEST)),
+     (6, Some(&HELLO_REQUEST)),
+     (7, Some(&DIVIDE_REQUEST)),
+     (8, Some(&BATCH_REQUEST)),
+     (14, None),
+     (15, None),
+ ]);
+
+ /*
+  * \brief Finds a field of an encoded message that its schema does not define.
+  *
+  * The wire tags are walked directly, since decoding drops unknown fields, and the
+  * messages nested in known fields are searched as well. Only the field numbers matter,
+  * so fields holding their default value are fine. The message must have been decoded
+  * successfully first, which also bounds the nesting depth.
+  *
+  * \param buf The encoded message.
+  * \param schema The fields the message may carry.
+  * \return The number of the first unknown field, or `None` if all fields are known.
+  */
+ fn unknown_field(mut buf: &[u8], schema: &Schema) -> Result<Option<u32>, prost::DecodeError> {
+     while !buf.is_empty() {
+         let (number, wire_type) = prost::encoding::decode_key(&mut buf)?;
+         match schema.0.iter().find(|(known, _)| *known == number) {
+             None => return Ok(Some(number)),
+             Some((_, Some(nested))) if wire_type == prost::encoding::WireType::LengthDelimited => {
+                 let len = prost::encoding::decode_varint(&mut buf)? as usize;
+                 let (message, rest) = buf.split_at(len.min(buf.len()));
+                 if let Some(unknown) = unknown_field(message, nested)? {
+                     return Ok(Some(unknown));
+                 }
+                 buf = rest;
+             }
+             Some(_) => {
+                 prost::encoding::skip_field(wire_type, number, &mut buf, Default::default())?
+             }
+         }
+     }
+     Ok(None)
+ }
+
+ /*
+  * \brief Logs a decision to accept or reject a connection, for the audit trail.
+  *
+  * Every decision has the same shape, `decision=<accept|reject> peer=<address>
+  * reason="<why>"`, and the same level, so the trail is easy to filter and parse.
+  *
+  * \param level The configured audit level.
+  * \param decision `accept` or `reject`.
+  * \param peer The address of the client.
+  * \param reason Why the decision was taken.
+  */
+ fn audit(level: Level, decision: &str, peer: SocketAddr, reason: &str) {
+     log!(level, "decision={} peer={} reason={:?}", decision, peer, reason);
+ }
+
+ #[cfg(test)]
+ thread_local! {
+     /// Number of upcoming `encode_response` calls on this thread that fail, for tests.
+     static FAILING_ENCODES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+ }
+
+ /*
+  * \brief Encodes a response.
+  *
+  * Encoding into a growing buffer cannot run out of room, so this only fails when a
+  * test asks it to through `FAILING_ENCODES`.
+  *
+  * \param response The message to encode.
+  * \return The encoding, or the reason it failed.
+  */
+ fn encode_response(response: &ServerMessage) -> Result<Vec<u8>, prost::EncodeError> {
+     #[cfg(test)]
+     if FAILING_ENCODES.with(|failing| failing.replace(failing.get().saturating_sub(1))) > 0 {
+         // Encoding into no room at all yields a genuine `EncodeError`
+         let mut no_room: &mut [u8] = &mut [];
+         response.encode(&mut no_room)?;
+     }
+     let mut payload = Vec::with_capacity(response.encoded_len());
+     response.encode(&mut payload)?;
+     Ok(payload)
+ }
+
+ /*
+  * \brief Tells whether an `accept` error means descriptors or memory ran out.
+  *
+  * \param e The error returned by `accept`.
+  * \return `true` if the accept loop should back off instead of retrying immediately.
+  */
+ fn is_resource_exhaustion(e: &io::Error) -> bool {
+     e.kind() == ErrorKind::OutOfMemory
+         || e.raw_os_error().is_some_and(|code| EXHAUSTION_ERRNOS.contains(&code))
+ }
+
+ /*
+  * \brief Logs the address the server listens on.
+  *
+  * Failing to read the address only costs the log line, never the server start.
+  *
+  * \param addr The listener's local address, as reported by the socket.
+  * \return The address, or `None` if it could not be determined.
+  */
+ fn announce_listening(addr: io::Result<SocketAddr>) -> Option<SocketAddr> {
+     match addr {
+         Ok(addr) => {
+             info!("Server is running on {}", addr);
+             Some(addr)
+         }
+         Err(e) => {
+             warn!("Server is running, but its address is unknown: {}", e);
+             None
+         }
+     }
+ }
+
+ /*
+  * \brief Returns the current wall-clock time in microseconds since the Unix epoch.
+  */
+ fn unix_micros() -> u64 {
+     SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_micros() as u64)
+ }
+
+ /*
+  * \brief Tells whether an I/O error means the client went away.
+  *
+  * \param e The error returned by a read or write on the connection.
+  * \return `true` if the error is a disconnect rather than a failure of the server.
+  */
+ fn is_disconnect(e: &io::Error) -> bool {
+     matches!(
+         e.kind(),
+         ErrorKind::BrokenPipe
+             | ErrorKind::ConnectionReset
+             | ErrorKind::ConnectionAborted
+             | ErrorKind::NotConnected
+     )
+ }
+
+ /*
+  * \brief Computes the next accept-loop pause, doubling up to `ACCEPT_BACKOFF_MAX`.
+  *
+  * \param current The previous pause, or zero after a successful accept.
+  * \return The pause to apply now.
+  */
+ fn next_backoff(current: Duration) -> Duration {
+     if current.is_zero() {
+         ACCEPT_BACKOFF_MIN
+     } else {
+         (current * 2).min(ACCEPT_BACKOFF_MAX)
+     }
+ }
+
+ /*
+  * \brief Extracts the message from a panic payload.
+  *
+  * \param payload The payload caught by `catch_unwind`.
+  * \return The panic message, or a placeholder for non-string payloads.
+  */
+ fn panic_message(payload: &(dyn Any + Send)) -> &str {
+     if let Some(message) = payload.downcast_ref::<&str>() {
+         message
+     } else if let Some(message) = payload.downcast_ref::<String>() {
+         message
+     } else {
+         "<non-string panic payload>"
+     }
+ }
+
+ /*
+  * \brief Lists the features this server configuration supports.
+  *
+  * The message kinds every server answers are always listed; every other entry comes
+  * from the setting that enables it. Settings that do not change what a client may send
+  * or receive are not advertised.
+  *
+  * \param config The server settings.
+  * \return The feature names reported in a `CapabilitiesResponse`.
+  */
+ fn capabilities(config: &Config) -> Vec<String> {
+     // Spelled out in full so a new setting cannot be added without deciding on its entry
+     let Config {
+         thread_name_prefix: _,
+         stack_size: _,
+         handler_factory,
+         stream_handler,
+         async_handler,
+         validator,
+         message_observer: _,
+         echo_delay: _,
+         shutdown_notice,
+         redirect,
+         unknown_message: _,
+         empty_echo,
+         strict_decoding,
+         session_timeout,
+         handshake_timeout,
+         max_runtime: _,
+         greeting,
+         max_response_frame: _,
+         max_pending_handshakes: _,
+         magic,
+         tee: _,
+         event_driven_accept: _,
+         cork_bursts: _,
+         max_queued_frames: _,
+         slow_client: _,
+         middleware: _,
+         control_channels,
+         audit_level: _,
+         worker_pool,
+     } = config;
+     let mut features = vec![
+         "hello",
+         "echo",
+         "binary_echo",
+         "add",
+         "divide",
+         "batch",
+         "ping",
+         "keepalive",
+         "capabilities",
+         "chunked_responses",
+     ];
+     let optional = [
+         (handler_factory.is_some(), "custom_handler"),
+         (stream_handler.is_some(), "stream_responses"),
+         (async_handler.is_some(), "async_responses"),
+         (validator.is_some(), "request_validation"),
+         (shutdown_notice.is_some(), "shutdown_notice"),
+         (redirect.is_some(), "redirect"),
+         (*empty_echo == EmptyEchoPolicy::Reject, "empty_echo_rejected"),
+         (*strict_decoding, "strict_decoding"),
+         (session_timeout.is_some(), "session_timeout"),
+         (handshake_timeout.is_some(), "handshake_timeout"),
+         (greeting.is_some(), "greeting"),
+         (magic.is_some(), "frame_magic"),
+         (*control_channels, "control_channel"),
+         (worker_pool.is_some(), "priorities"),
+     ];
+     features
+         .extend(optional.into_iter().filter(|(enabled, _)| *enabled).map(|(_, feature)| feature));
+     features.into_iter().map(String::from).collect()
+ }
+
+ /// How the accept loop waits once no connection is pending.
+ enum AcceptWait {
+     /// Sleep for the whole poll interval.
+     Sleep,
+     /// Wait on the listener's readiness, waking as soon as a connection arrives.
+     Readiness {
+         poll: mio::Poll,
+         events: mio::Events,
+         /// Registered clone of the server's listener.
+         _listener: mio::net::TcpListener,
+     },
+ }
+
+ impl AcceptWait {
+     /*
+      * \brief Prepares the waiting strategy for `listener`.
+      *
+      * \param listener The server's non-blocking listener.
+      * \param event_driven Whether to wait on readiness rather than sleep.
+      * \return The strategy, or an error if the readiness poller cannot be set up.
+      */
+     fn new(listener: &TcpListener, event_driven: bool) -> io::Result<Self> {
+         if !event_driven {
+             return Ok(AcceptWait::Sleep);
+         }
+         let poll = mio::Poll::new()?;
+         // The clone shares the socket, so its readiness is the listener's
+         let mut clone = mio::net::TcpListener::from_std(listener.try_clone()?);
+         poll.registry().register(&mut clone, mio::Token(0), mio::Interest::READABLE)?;
+         Ok(AcceptWait::Readiness { poll, events: mio::Events::with_capacity(1), _listener: clone })
+     }
+
+     /*
+      * \brief Waits until a connection may be pending or `timeout` has elapsed.
+      *
+      * \param timeout The longest wait.
+      */
+     fn wait(&mut self, timeout: Duration) {
+         match self {
+             AcceptWait::Sleep => thread::sleep(timeout),
+             AcceptWait::Readiness { poll, events, .. } => {
+                 if let Err(e) = poll.poll(events, Some(timeout)) {
+                     if e.kind() != ErrorKind::Interrupted {
+                         warn!("Waiting for connections failed ({}), sleeping instead", e);
+                         thread::sleep(timeout);
+                     }
+                 }
+             }
+         }
+     }
+ }
+
+ /// Handles on the sockets of all open connections, keyed by peer address.
+ type Registry = Mutex<HashMap<SocketAddr, TcpStream>>;
+
+ /// Lowers the flag marking a call to `Server::run` as active when dropped and wakes the
+ /// callers waiting for `run` to return, such as `drain_and_report`.
+ struct RunGuard<'a> {
+     active: &'a Mutex<bool>,
+     exited: &'a Condvar,
+ }
+
+ impl Drop for RunGuard<'_> {
+     fn drop(&mut self) {
+         *self.active.lock().unwrap_or_else(|e| e.into_inner()) = false;
+         self.exited.notify_all();
+     }
+ }
+
+ /// An accepted connection whose handler the caller runs on a thread of its choosing.
+ pub struct Connection {
+     client: Client,
+     peer: SocketAddr,
+     registry: Arc<Registry>,
+ }
+
+ impl Connection {
+     /*
+      * \brief Serves the client until it disconnects or the server drains.
+      *
+      * This blocks the calling thread for the whole session, exactly like the handler
+      * threads spawned by `Server::run`.
+      *
+      * \return A result indicating success (`Ok`) or failure (`Err`).
+      */
+     pub fn handle(mut self) -> io::Result<()> {
+         self.client.handle()
+     }
+
+     /*
+      * \brief Returns the underlying TCP stream, e.g. to tune socket options.
+      */
+     pub fn stream(&self) -> &TcpStream {
+         &self.client.stream
+     }
+
+     /*
+      * \brief Returns the address of the connected client.
+      */
+     pub fn peer_addr(&self) -> SocketAddr {
+         self.peer
+     }
+ }
+
+ impl Drop for Connection {
+     fn drop(&mut self) {
+         self.registry.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.peer);
+     }
+ }
+
+ /// Represents the echo server.
+ pub struct Server {
+     max_clients: usize,
+     /// The listening socket, until `stop` closes it.
+     listener: Mutex<Option<Arc<TcpListener>>>,
+     is_running: Arc<Mutex<bool>>,
+     draining: Arc<AtomicBool>,
+     active_clients: Arc<AtomicUsize>,
+     io_stats: Arc<IoStats>,
+     counters: Arc<ServerCounters>,
+     registry: Arc<Registry>,
+     sessions: Arc<Sessions>,
+     ready: Mutex<bool>,
+     ready_changed: Condvar,
+     paused: AtomicBool,
+     /// Raised while a call to `run` is active.
+     run_active: Mutex<bool>,
+     /// Signalled when a call to `run` returns.
+     run_exited: Condvar,
+     /// Totals at the last `take_metrics` call.
+     metrics_baseline: Mutex<ServerMetrics>,
+     workers: Mutex<Vec<JoinHandle<()>>>,
+     /// Hooks registered with `on_shutdown`, until the first graceful shutdown runs them.
+     shutdown_hooks: Mutex<Vec<Box<ShutdownHook>>>,
+     config: Config,
+ }
+
+ impl Server {
+     /*
+      * \brief Constructs a new `Server` instance.
+      *
+      * This function initializes a `Server` with the given address and maximum number
+      * of clients. The server will listen for incoming TCP connections and handle them.
+      *
+      * \param addr The address the server should bind to.
+      * \param max_clients The maximum number of clients the server should handle.
+      * \return A result containing the new `Server` instance on success, or an error.
+      */
+     pub fn new(addr: &str, max_clients: usize) -> io::Result<Self> {
+         let listener = TcpListener::bind(addr)?;
+         // Listening again only enlarges the queue; the kernel caps it at its own limit
+         socket2::SockRef::from(&listener).listen(LISTEN_BACKLOG)?;
+         Self::from_listener(listener, max_clients)
+     }
+
+     /*
+      * \brief Constructs a `Server` that accepts on an already bound listener.
+      *
+      * This is the entry point for socket activation and socket handoff: the listener
+      * may come from a process supervisor such as systemd (`LISTEN_FDS`) or from a
+      * previous server process, so connections queued on it are not lost. The listen
+      * backlog is left as its creator configured it.
+      *
+      * \param listener The bound, listening socket to accept connections on.
+      * \param max_clients The maximum number of clients the server should handle.
+      * \return A result containing the new `Server` instance on success, or an error.
+      */
+     pub fn from_listener(listener: TcpListener, max_clients: usize) -> io::Result<Self> {
+         // Non-blocking accepts let `run` notice a `stop` request between connections
+         listener.set_nonblocking(true)?;
+         Ok(Self::with_listener(Some(listener), max_clients))
+     }
+
+     /*
+      * \brief Constructs a `Server` that opens no socket at all.
+      *
+      * Such a server only serves streams handed to `handle_stream` or `serve_once`, for
+      * example one end of `transport::memory_pair`, so tests can exercise a handler
+      * without binding a port. `run` and `accept_one` fail with `NotConnected`.
+      *
+      * \return The new `Server` instance.
+      */
+     pub fn in_process() -> Self {
+         Self::with_listener(None, usize::MAX)
+     }
+
+     /// Builds a server around `listener` with the default configuration.
+     fn with_listener(listener: Option<TcpListener>, max_clients: usize) -> Self {
+         let is_running = Arc::new(Mutex::new(true)); // Ensure server runs until explicitly stopped
+         Server {
+             listener: Mutex::new(listener.map(Arc::new)),
+             is_running,
+             draining: Arc::new(AtomicBool::new(false)),
+             active_clients: Arc::new(AtomicUsize::new(0)),
+             io_stats: Arc::new(IoStats::default()),
+             counters: Arc::new(ServerCounters::default()),
+             registry: Arc::new(Mutex::new(HashMap::new())),
+             sessions: Arc::new(Sessions::default()),
+             ready: Mutex::new(false),
+             ready_changed: Condvar::new(),
+             paused: AtomicBool::new(false),
+             run_active: Mutex::new(false),
+             run_exited: Condvar::new(),
+             metrics_baseline: Mutex::new(ServerMetrics::default()),
+             workers: Mutex::new(Vec::new()),
+             shutdown_hooks: Mutex::new(Vec::new()),
+             max_clients,
+             config: Config {
+                 worker_pool: None,
+                 thread_name_prefix: DEFAULT_THREAD_NAME_PREFIX.to_string(),
+                 stack_size: None,
+                 handler_factory: None,
+                 stream_handler: None,
+                 async_handler: None,
+                 validator: None,
+                 message_observer: None,
+                 echo_delay: Duration::ZERO,
+                 shutdown_notice: None,
+                 redirect: None,
+                 unknown_message: UnknownMessagePolicy::default(),
+                 empty_echo: EmptyEchoPolicy::default(),
+                 strict_decoding: false,
+                 session_timeout: None,
+                 handshake_timeout: None,
+                 max_runtime: None,
+                 greeting: None,
+                 max_response_frame: frame::MAX_FRAME_SIZE,
+                 max_pending_handshakes: None,
+                 magic: None,
+                 tee: None,
+                 event_driven_accept: false,
+                 cork_bursts: false,
+                 max_queued_frames: None,
+                 slow_client: None,
+                 middleware: Vec::new(),
+                 control_channels: false,
+                 audit_level: Level::Info,
+             },
+         }
+     }
+
+     /*
+      * \brief Limits how many requests all connections together process at once.
+      *
+      * Requests beyond the limit wait for a worker to become idle, and waiting requests
+      * are admitted by `Priority` first, so a ping overtakes the echo traffic of other
+      * connections while the server is saturated. Each connection still answers its own
+      * requests in the order they arrived. Unlimited by default.
+      *
+      * \param workers The number of requests processed at once; at least one.
+      * \return The server with the limit applied.
+      */
+     pub fn with_worker_pool(mut self, workers: usize) -> Self {
+         self.config.worker_pool = Some(Arc::new(WorkerPool::new(workers)));
+         self
+     }
+
+     /*
+      * \brief Sets the prefix used to name handler threads.
+      *
+      * Each handler thread is named `<prefix>-<peer address>`, which makes it easy to
+      * tell connections apart in debuggers and panic messages. Defaults to `echo-client`.
+      *
+      * \param prefix The thread name prefix.
+      * \return The server with the new prefix.
+      */
+     pub fn with_handler_thread_name(mut self, prefix: &str) -> Self {
+         self.config.thread_name_prefix = prefix.to_string();
+         self
+     }
+
+     /*
+      * \brief Stops the server on its own once it has run for `runtime`.
+      *
+      * `run` then stops accepting and returns as if `stop` had been called. This is a
+      * safety net for test and demo servers that might otherwise never be stopped.
+      *
+      * \param runtime How long `run` may accept connections.
+      * \return The server with the limit applied.
+      */
+     pub fn with_max_runtime(mut self, runtime: Duration) -> Self {
+         self.config.max_runtime = Some(runtime);
+         self
+     }
+
+     /*
+      * \brief Requires every connection to start with a two-byte magic number.
+      *
+      * The magic is sent once, before the first frame, and tells this protocol apart from
+      * stray traffic such as port scanners or misdirected clients. A connection starting
+      * with anything else is closed as soon as its first two bytes arrive, without any
+      * response. By default no magic is expected.
+      *
+      * \param magic The bytes each connection must start with.
+      * \return The server with the check enabled.
+      */
+     pub fn with_frame_magic(mut self, magic: [u8; 2]) -> Self {
+         self.config.magic = Some(magic);
+         self
+     }
+
+     /*
+      * \brief Copies the traffic of every connection to a secondary sink.
+      *
+      * Every decoded request and every response is written to `sink` as one line holding
+      * the client address, `->` for a request or `<-` for a response, and the message in
+      * its debug form. Large responses appear whole, before being split into chunks.
+      *
+      * The lines are written by a dedicated thread, so a slow or blocked sink never holds
+      * up a handler. Up to 1024 lines wait for that thread; while the queue is full,
+      * further lines are dropped. Writing is best effort: errors are logged at debug level
+      * and a sink that panics loses only the line it was writing. The thread ends once
+      * the server and all its connections are gone.
+      *
+      * \param sink Where to write the copy, such as a file or a socket.
+      * \return The server with the tee enabled.
+      */
+     pub fn with_tee<W: Write + Send + 'static>(mut self, mut sink: W) -> Self {
+         let (sender, lines) = mpsc::sync_channel::<String>(TEE_QUEUE_LINES);
+         let writer = thread::Builder::new().name("tee-writer".to_string()).spawn(move || {
+             for line in lines {
+                 match panic::catch_unwind(AssertUnwindSafe(|| sink.write_all(line.as_bytes()))) {
+                     Ok(Ok(())) => {}
+                     Ok(Err(e)) => debug!("Could not write to the tee sink: {}", e),
+                     Err(payload) => {
+                         error!("The tee sink panicked: {}", panic_message(payload.as_ref()))
+                     }
+                 }
+             }
+         });
+         match writer {
+             Ok(_) => self.config.tee = Some(sender),
+             Err(e) => error!("Could not start the tee writer, not copying traffic: {}", e),
+         }
+         self
+     }
+
+     /*
+      * \brief Caps how many decoded requests a connection holds before it stops reading.
+      *
+      * Every connection already stops reading at `MAX_QUEUED_BYTES` of queued requests,
+      * which still allows thousands of small frames. With this limit, reading also stops
+      * once `frames` requests wait for an answer, and resumes as the handler works through
+      * them; a client pipelining faster than that blocks on its own writes. The frames of
+      * the last read chunk not yet decoded are held on top, at most 4 KiB. Unlimited by default.
+      *
+      * \param frames The largest number of requests queued per connection; at least one.
+      * \return The server with the limit applied.
+      */
+     pub fn with_max_queued_frames(mut self, frames: usize) -> Self {
+         self.config.max_queued_frames = Some(frames.max(1));
+         self
+     }
+
+     /*
+      * \brief Makes the accept loop wait on listener readiness instead of sleeping.
+      *
+      * By default the accept loop sleeps 100 ms whenever no connection is pending, so a
+      * client arriving on an idle server may wait that long to be accepted. Event-driven
+      * accepting blocks in `epoll` (or the platform's equivalent, through `mio`) and wakes
+      * as soon as a connection arrives, then accepts everything pending in one burst. This
+      * lowers accept latency and avoids needless wake-ups under connection churn.
+      *
+      * \param enabled Whether to wait on readiness.
+      * \return The server with the accept strategy applied.
+      */
+     pub fn with_event_driven_accept(mut self, enabled: bool) -> Self {
+         self.config.event_driven_accept = enabled;
+         self
+     }
+
+     /*
+      * \brief Coalesces the responses to pipelined requests into fewer packets.
+      *
+      * While more requests are queued behind the one being answered, the connection is
+      * corked (`TCP_CORK`), so the kernel holds back partial packets; once the queue
+      * runs dry it is uncorked and everything held back goes out together. This saves
+      * packets when a client pipelines many small requests. Corking is only available
+      * on Linux and Android; elsewhere, and on in-process transports, it has no effect.
+      *
+      * \param enabled Whether to cork bursts of responses.
+      * \return The server with corking enabled or disabled.
+      */
+     pub fn with_corked_bursts(mut self, enabled: bool) -> Self {
+         self.config.cork_bursts = enabled;
+         self
+     }
+
+     /*
+      * \brief Sets the level of the audit trail of connection decisions.
+      *
+      * Every accepted connection and every rejected one, whether refused for the client
+      * limit, the pending handshake limit, a failed socket setup, a missing handshake,
+      * a wrong protocol magic or an unknown control session, is logged as one line of
+      * the form `decision=<accept|reject> peer=<address> reason="<why>"`. Defaults to
+      * `Level::Info`.
+      *
+      * \param level The level of the audit lines.
+      * \return The server with the audit level applied.
+      */
+     pub fn with_audit_log_level(mut self, level: Level) -> Self {
+         self.config.audit_level = level;
+         self
+     }
+
+     /*
+      * \brief Lets clients open a control channel next to their data connection.
+      *
+      * Every connection completing the Hello handshake then opens a session, whose id
+      * the `HelloResponse` carries. A second connection sending a Hello with `control`
+      * set and that id becomes the session's control channel: `broadcast` pushes its
+      * notifications there, apart from the request/response traffic of the data
+      * connection. The control connection is closed once the data connection ends.
+      *
+      * \param enabled Whether sessions and control channels are available.
+      * \return The server with control channels enabled or disabled.
+      */
+     pub fn with_control_channels(mut self, enabled: bool) -> Self {
+         self.config.control_channels = enabled;
+         self
+     }
+
+     /*
+      * \brief Sets the stack size of handler threads.
+      *
+      * Deeply recursive custom handlers may need more than the platform's default, which
+      * is used unless this is called.
+      *
+      * \param bytes The stack size of each handler thread.
+      * \return The server with the stack size applied.
+      */
+     pub fn with_handler_stack_size(mut self, bytes: usize) -> Self {
+         self.config.stack_size = Some(bytes);
+         self
+     }
+
+     /*
+      * \brief Delays every echo response by a fixed duration.
+      *
+      * Meant for testing client timeouts deterministically. Defaults to no delay.
+      *
+      * \param delay How long to wait before answering an echo request.
+      * \return The server with the delay applied.
+      */
+     pub fn with_echo_delay(mut self, delay: Duration) -> Self {
+         self.config.echo_delay = delay;
+         self
+     }
+
+     /*
+      * \brief Announces graceful shutdowns to connected clients.
+      *
+      * During `stop_graceful`, each client receives a `Shutdown` message carrying `reason`
+      * after its pending requests are answered and before its connection is closed, so it
+      * can reconnect elsewhere instead of seeing an abrupt end of stream. Off by default.
+      *
+      * \param reason The reason reported to clients.
+      * \return The server with the notice enabled.
+      */
+     pub fn with_shutdown_notice(mut self, reason: &str) -> Self {
+         self.config.shutdown_notice = Some(reason.to_string());
+         self
+     }
+
+     /*
+      * \brief Points clients at a replacement server during graceful shutdowns.
+      *
+      * During `stop_graceful`, each client receives a `Redirect` message naming `host` and
+      * `port` once its pending requests are answered, ahead of any shutdown notice. A
+      * client reconnecting automatically moves over to the replacement, which makes
+      * rolling restarts nearly seamless. Off by default.
+      *
+      * \param host The host of the replacement server.
+      * \param port The port of the replacement server.
+      * \return The server with the redirect enabled.
+      */
+     pub fn with_redirect(mut self, host: &str, port: u16) -> Self {
+         self.config.redirect = Some((host.to_string(), port));
+         self
+     }
+
+     /*
+      * \brief Chooses how handlers answer an echo of empty content.
+      *
+      * Some clients take an empty response for a failure, so a server can refuse such
+      * echoes outright instead. Defaults to `EmptyEchoPolicy::Echo`.
+      *
+      * \param policy The reaction to an empty echo.
+      * \return The server with the policy applied.
+      */
+     pub fn with_empty_echo_policy(mut self, policy: EmptyEchoPolicy) -> Self {
+         self.config.empty_echo = policy;
+         self
+     }
+
+     /*
+      * \brief Chooses how handlers treat messages of a variant they do not know.
+      *
+      * Such messages come from clients speaking a newer protocol, or from a plain bug.
+      * Defaults to `UnknownMessagePolicy::Error`.
+      *
+      * \param policy The reaction to an unknown message.
+      * \return The server with the policy applied.
+      */
+     pub fn with_unknown_message_policy(mut self, policy: UnknownMessagePolicy) -> Self {
+         self.config.unknown_message = policy;
+         self
+     }
+
+     /*
+      * \brief Rejects requests carrying fields this server does not know.
+      *
+      * By default such fields are ignored, as protobuf intends, so newer clients keep
+      * working. In strict mode a request with unknown fields, at any nesting level, is
+      * answered with an `ErrorResponse` carrying `ErrorCode::MalformedMessage`. The check
+      * walks the field numbers of the frame, so only fields the protocol does not define
+      * are rejected; a known field sent with its default value is accepted. A frame
+      * naming no known message variant still follows the unknown message policy.
+      *
+      * \param strict Whether unknown fields are rejected.
+      * \return The server with the mode applied.
+      */
+     pub fn with_strict_decoding(mut self, strict: bool) -> Self {
+         self.config.strict_decoding = strict;
+         self
+     }
+
+     /*
+      * \brief Caps the total time a connection may spend having its requests answered.
+      *
+      * Only processing counts, not the time spent waiting for the client, so this guards
+      * against a sequence of slow requests rather than against idle connections. The
+      * request that exceeds the budget is still answered before the connection is closed.
+      * Unlimited by default.
+      *
+      * \param timeout The processing budget of each connection.
+      * \return The server with the limit applied.
+      */
+     pub fn with_session_timeout(mut self, timeout: Duration) -> Self {
+         self.config.session_timeout = Some(timeout);
+         self
+     }
+
+     /*
+      * \brief Chooses how handlers treat clients that read their responses too slowly.
+      *
+      * A client counts as slow when sending one response frame blocks for `threshold` or
+      * longer, because the client leaves its receive buffer full. The policy is applied
+      * once the request is answered. With `SlowClientPolicy::Disconnect`, writes time out
+      * after `threshold`, so a client that stops reading altogether is let go as well.
+      * Slow clients are not detected by default.
+      *
+      * \param threshold How long a single write may block before the client counts as slow.
+      * \param policy The reaction to a slow client.
+      * \return The server with the policy applied.
+      */
+     pub fn with_slow_client_policy(
+         mut self,
+         threshold: Duration,
+         policy: SlowClientPolicy,
+     ) -> Self {
+         self.config.slow_client = Some((threshold, policy));
+         self
+     }
+
+     /*
+      * \brief Closes connections that do not send their first request in time.
+      *
+      * A client that connects and then stalls holds a connection slot and counts as a
+      * pending handshake; this frees both. Since the Hello is optional, any request that
+      * decodes ends the handshake phase, and the connection is then never closed for
+      * this reason. The check runs while the handler waits for data, so a connection is
+      * closed at most about 100 ms after its deadline. Unlimited by default.
+      *
+      * \param timeout How long after connecting a client must have sent a request.
+      * \return The server with the limit applied.
+      */
+     pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+         self.config.handshake_timeout = Some(timeout);
+         self
+     }
+
+     /*
+      * \brief Greets every new connection before it sends its first request.
+      *
+      * The `Greeting` carries `server_name` and the server's crate version, letting
+      * clients identify what they connected to. Off by default.
+      *
+      * \param server_name The name announced to clients.
+      * \return The server with the greeting enabled.
+      */
+     pub fn with_greeting(mut self, server_name: &str) -> Self {
+         self.config.greeting = Some(server_name.to_string());
+         self
+     }
+
+     /*
+      * \brief Sets the largest frame payload used for a single response.
+      *
+      * Larger responses are streamed as `Chunk` messages instead of failing, so big
+      * transfers do not require raising the frame size limit. Defaults to
+      * `frame::MAX_FRAME_SIZE`; values too small to carry any data are raised to the
+      * minimum that can.
+      *
+      * \param size The largest response frame payload, in bytes.
+      * \return The server with the limit applied.
+      */
+     pub fn with_max_response_frame(mut self, size: usize) -> Self {
+         self.config.max_response_frame = size.max(CHUNK_OVERHEAD + 1);
+         self
+     }
+
+     /*
+      * \brief Limits how many connections may wait for their Hello handshake at once.
+      *
+      * A connection counts as pending from the moment it is accepted until the first
+      * request it sends is decoded, whatever its type, since the Hello is optional.
+      * While the limit is reached, `run` and `accept_one` refuse new connections, `run`
+      * before they take a client slot, so clients that connect and never send anything
+      * cannot exhaust the server. Unlimited by default.
+      *
+      * \param limit The number of connections allowed to be mid-handshake.
+      * \return The server with the limit applied.
+      */
+     pub fn with_max_pending_handshakes(mut self, limit: usize) -> Self {
+         self.config.max_pending_handshakes = Some(limit);
+         self
+     }
+
+     /*
+      * \brief Installs a callback invoked after every request has been answered.
+      *
+      * The callback receives the kind of the request, the time from starting to process
+      * it until its response was written, and the address of the client. It runs on the
+      * connection's thread, so it should return quickly. Requests whose response could
+      * not be written are not reported.
+      *
+      * \param observer The closure to invoke.
+      * \return The server with the callback installed.
+      */
+     pub fn on_message_handled<F>(mut self, observer: F) -> Self
+     where
+         F: Fn(MessageKind, Duration, SocketAddr) + Send + Sync + 'static,
+     {
+         self.config.message_observer = Some(Arc::new(observer));
+         self
+     }
+
      /*
-      * \brief Constructs a new `Client` instance.
-      * 
-      * This function initializes a `Client` with the given TCP stream, which represents
-      * the connection between the server and the client.
-      * 
-      * \param stream The TCP stream representing the client's connection.
-      * \return A new `Client` instance.
+      * \brief Registers cleanup to run when the server shuts down gracefully.
+      *
+      * Hooks run during `stop_graceful` or `drain_and_report`, once every connection has
+      * been drained and before the call returns, in the order they were registered. Each
+      * runs at most once, however often the server is stopped. A panicking hook is logged
+      * and does not keep the following ones from running.
+      *
+      * \param hook The cleanup, such as flushing logs or closing a connection pool.
+      * \return The server with the hook registered.
       */
-     pub fn new(stream: TcpStream) -> Self {
-         Client { stream }
+     pub fn on_shutdown<F>(mut self, hook: F) -> Self
+     where
+         F: FnOnce() + Send + 'static,
+     {
+         self.shutdown_hooks.get_mut().unwrap_or_else(|e| e.into_inner()).push(Box::new(hook));
+         self
      }
- 
+
      /*
-      * \brief Handles communication with the client.
-      * 
-      * This function continuously reads messages from the client, decodes them, and then
-      * sends a response back to the client. If an error occurs during reading or encoding,
-      * it returns an error.
-      * 
-      * \return A result indicating success (`Ok`) or failure (`Err`).
+      * \brief Adds a layer around the handling of every request.
+      *
+      * Layers nest in registration order: requests pass through them first to last
+      * before validation and handling, and responses last to first before being sent,
+      * so the first layer is the outermost. This is the place for cross-cutting concerns
+      * such as logging, authentication or metrics. Every request goes through the
+      * layers, the Hello handshake included, and so does each response of a stream.
+      *
+      * \param layer The middleware to add.
+      * \return The server with the layer added.
       */
-     pub fn handle(&mut self) -> io::Result<()> {
-         let mut buffer = [0u8; 4096];
- 
-         // Keep handling messages as long as the client is connected
-         loop {
-             let bytes_read = match self.stream.read(&mut buffer) {
-                 Ok(0) => {
-                     info!("Client disconnected.");
-                     return Ok(()); // Client disconnected
-                 }
-                 Ok(bytes) => bytes,
-                 Err(e) => {
-                     error!("Error reading from client: {}", e);
-                     return Err(e); // Error while reading from client
-                 }
-             };
- 
-             // Decode the received message from the buffer
-             if let Ok(message) = EchoMessage::decode(&buffer[..bytes_read]) {
-                 info!("Received: {}", message.content);
- 
-                 // Re-encode and send the response
-                 let payload = message.encode_to_vec();
-                 self.stream.write_all(&payload)?;
-                 self.stream.flush()?;
- 
-                 info!("Sent: {}", message.content);
-             } else {
-                 error!("Failed to decode message");
-             }
-         }
+     pub fn with_middleware<M: Middleware + 'static>(mut self, layer: M) -> Self {
+         self.config.middleware.push(Arc::new(layer));
+         self
      }
- }
- 
- /// Represents the echo server.
- pub struct Server {
-     max_clients: usize,
-     listener: TcpListener,
-     is_running: Arc<Mutex<bool>>,
-     workers: Vec<JoinHandle<()>>,
- }
- 
- impl Server {
+
      /*
-      * \brief Constructs a new `Server` instance.
-      * 
-      * This function initializes a `Server` with the given address and maximum number
-      * of clients. The server will listen for incoming TCP connections and handle them.
-      * 
-      * \param addr The address the server should bind to.
-      * \param max_clients The maximum number of clients the server should handle.
-      * \return A result containing the new `Server` instance on success, or an error.
+      * \brief Installs a check every request must pass before it is handled.
+      *
+      * A request the validator rejects is answered with an `ErrorResponse` carrying the
+      * `REJECTED` code and the validator's reason; neither the custom handler nor the
+      * built-in behaviour sees it. The Hello handshake is not validated.
+      *
+      * \param validator The closure returning `Err(reason)` for requests to reject.
+      * \return The server with the validator installed.
       */
-     pub fn new(addr: &str, max_clients: usize) -> io::Result<Self> {
-         let listener = TcpListener::bind(addr)?;
-         let is_running = Arc::new(Mutex::new(true)); // Ensure server runs until explicitly stopped
-         Ok(Server {
-             listener,
-             is_running,
-             workers: Vec::new(),
-             max_clients,
-         })
+     pub fn with_validator<F>(mut self, validator: F) -> Self
+     where
+         F: Fn(&client_message::Message) -> Result<(), String> + Send + Sync + 'static,
+     {
+         self.config.validator = Some(Arc::new(validator));
+         self
+     }
+
+     /*
+      * \brief Installs a custom request handler.
+      *
+      * The handler is consulted before the built-in behaviour; returning `None` lets the
+      * server answer the request as usual.
+      *
+      * \param handler The closure invoked for every request.
+      * \return The server with the handler installed.
+      */
+     pub fn with_handler<F>(self, handler: F) -> Self
+     where
+         F: Fn(&client_message::Message) -> Option<server_message::Message> + Send + Sync + 'static,
+     {
+         self.with_context_handler(|| (), move |_, message, _| handler(message))
+     }
+
+     /*
+      * \brief Installs a handler answering requests with a stream of responses.
+      *
+      * Each response is sent as its own message, and a `StreamEnd` message follows the
+      * last one so the client knows the stream is complete. The stream handler is asked
+      * before the custom handler; returning `None` leaves the request to it and to the
+      * built-in behaviour. The Hello handshake is never streamed.
+      *
+      * \param handler The closure invoked for every request.
+      * \return The server with the handler installed.
+      */
+     pub fn with_stream_handler<F>(mut self, handler: F) -> Self
+     where
+         F: Fn(&client_message::Message) -> Option<Vec<server_message::Message>>
+             + Send
+             + Sync
+             + 'static,
+     {
+         self.config.stream_handler = Some(Arc::new(handler));
+         self
+     }
+
+     /*
+      * \brief Installs a handler answering requests asynchronously.
+      *
+      * Each connection has a send queue, and the handler gets a sending end for every
+      * request. Returning `true` takes the request: nothing is sent in reply right away,
+      * and the handler, or a thread it hands the sender to, queues any number of
+      * responses later. They carry the request's timestamps and are written to the client
+      * between requests, in queue order, so they may arrive after the responses to later
+      * requests. The request is counted once its first response is written; once the
+      * connection ends, sending fails instead. Returning `false` leaves the request to the
+      * stream handler, the custom handler and the built-in behaviour. Dry runs and the
+      * Hello handshake are never offered to it, and the message observer is not told
+      * about requests it takes.
+      *
+      * \param handler The closure invoked for every request.
+      * \return The server with the handler installed.
+      */
+     pub fn with_async_handler<F>(mut self, handler: F) -> Self
+     where
+         F: Fn(&client_message::Message, &ResponseSender) -> bool + Send + Sync + 'static,
+     {
+         self.config.async_handler = Some(Arc::new(handler));
+         self
      }
- 
+
+     /*
+      * \brief Installs a custom request handler with per-connection state.
+      *
+      * `factory` runs once on each connection's handler thread to create a context, which
+      * is then passed mutably to `handler` for every request of that connection. This keeps
+      * request-scoped resources (e.g. a database connection) out of global state. As with
+      * `with_handler`, returning `None` lets the server answer the request as usual.
+      *
+      * The handler is told whether the request is flagged `dry_run`; a dry run must produce
+      * the response the request would get while leaving the context as it was.
+      *
+      * \param factory Creates the context of a new connection.
+      * \param handler The closure invoked for every request.
+      * \return The server with the handler installed.
+      */
+     pub fn with_context_handler<C, F, H>(mut self, factory: F, handler: H) -> Self
+     where
+         C: Send + 'static,
+         F: Fn() -> C + Send + Sync + 'static,
+         H: Fn(&mut C, &client_message::Message, bool) -> Option<server_message::Message>
+             + Send
+             + Sync
+             + 'static,
+     {
+         let handler = Arc::new(handler);
+         self.config.handler_factory = Some(Arc::new(move || {
+             let mut context = factory();
+             let handler = handler.clone();
+             Box::new(move |message: &client_message::Message, dry_run: bool| {
+                 handler(&mut context, message, dry_run)
+             }) as Box<MessageHandler>
+         }));
+         self
+     }
+
      /*
       * \brief Runs the server, accepting and handling client connections.
-      * 
+      *
       * This function continuously accepts incoming client connections and spawns a new
-      * thread to handle each client. The server runs until it is explicitly stopped.
-      * 
-      * \return A result indicating success (`Ok`) or failure (`Err`).
+      * thread to handle each client. Connections beyond `max_clients` are refused. When
+      * the process runs out of file descriptors, accepting backs off with a growing
+      * delay instead of spinning. Once no connection is pending, the loop sleeps for up to
+      * 100 ms, or with event-driven accepting waits until the next one arrives. A
+      * handler that panics is logged with its peer address and its connection closed,
+      * without affecting other clients. The server runs until it is explicitly stopped.
+      * Only one call runs at a time: while the loop is active, further calls fail at once
+      * instead of competing for the listener. A stopped server cannot be run again, as
+      * `stop` releases the listening socket: a call made after `stop`, including one made
+      * before any `run`, returns at once without accepting anything.
+      *
+      * \return A result indicating success (`Ok`) or failure (`Err`); a `ResourceBusy`
+      *         error wrapping `AlreadyRunning` if `run` is already active on another thread.
       */
-     pub fn run(&mut self) -> io::Result<()> {
+     pub fn run(&self) -> io::Result<()> {
+         {
+             let mut active = self.run_active.lock().unwrap_or_else(|e| e.into_inner());
+             if *active {
+                 return Err(io::Error::new(ErrorKind::ResourceBusy, AlreadyRunning));
+             }
+             *active = true;
+         }
+         // Marks this call as finished once it returns, whichever way it does
+         let _active = RunGuard { active: &self.run_active, exited: &self.run_exited };
          let is_running = self.is_running.clone();
-         info!("Server is running on {}", self.listener.local_addr()?);
- 
-         while *is_running.lock().unwrap() {
-             match self.listener.accept() {
+         if !*is_running.lock().unwrap_or_else(|e| e.into_inner()) {
+             info!("Server was stopped before it started, not accepting connections.");
+             return Ok(());
+         }
+         // Holding the socket keeps it open until the loop ends, even once `stop` released it
+         let listener = self.listener()?;
+         let config = Arc::new(self.config.clone());
+         let mut backoff = Duration::ZERO;
+         let mut accept_wait = AcceptWait::new(&listener, self.config.event_driven_accept)?;
+         announce_listening(listener.local_addr());
+         self.set_ready(true);
+         let deadline = self.config.max_runtime.map(|runtime| Instant::now() + runtime);
+
+         while *is_running.lock().unwrap_or_else(|e| e.into_inner()) {
+             if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                 info!(
+                     "Maximum runtime of {:?} reached.",
+                     self.config.max_runtime.unwrap_or_default()
+                 );
+                 self.stop();
+                 break;
+             }
+             if self.paused.load(atomic::Ordering::SeqCst) {
+                 // New clients wait in the listen backlog until `resume`
+                 self.reap_workers();
+                 thread::sleep(ACCEPT_POLL_INTERVAL);
+                 continue;
+             }
+             match listener.accept() {
                  Ok((stream, addr)) => {
-                     info!("New client connected: {}", addr);
- 
-                     // Create and handle the client in a separate thread
-                     let mut client = Client::new(stream);
-                     let handle = thread::spawn(move || {
-                         if let Err(e) = client.handle() {
-                             error!("Error handling client: {}", e);
+                     backoff = Duration::ZERO;
+                     if self.active_clients.load(atomic::Ordering::SeqCst) >= self.max_clients {
+                         let reason = format!("{} clients already connected", self.max_clients);
+                         audit(config.audit_level, "reject", addr, &reason);
+                         continue;
+                     }
+                     if let Err(reason) = self.admit_handshake() {
+                         audit(config.audit_level, "reject", addr, &reason);
+                         continue;
+                     }
+
+                     let mut connection = match self.open_connection(stream, addr, config.clone()) {
+                         Ok(connection) => connection,
+                         Err(e) => {
+                             audit(
+                                 config.audit_level,
+                                 "reject",
+                                 addr,
+                                 &format!("socket setup failed: {}", e),
+                             );
+                             continue;
+                         }
+                     };
+                     audit(config.audit_level, "accept", addr, "new client");
+                     self.active_clients.fetch_add(1, atomic::Ordering::SeqCst);
+
+                     // Handle the client in a separate thread
+                     let active_clients = self.active_clients.clone();
+                     let mut builder = thread::Builder::new()
+                         .name(format!("{}-{}", config.thread_name_prefix, addr));
+                     if let Some(stack_size) = config.stack_size {
+                         builder = builder.stack_size(stack_size);
+                     }
+                     let spawned = builder.spawn(move || {
+                         // A panicking handler must not take the connection slot with it
+                         match panic::catch_unwind(AssertUnwindSafe(|| connection.client.handle()))
+                         {
+                             Ok(Ok(())) => {}
+                             Ok(Err(e)) => error!("Error handling client: {}", e),
+                             Err(payload) => {
+                                 error!(
+                                     "Handler for {} panicked: {}",
+                                     addr,
+                                     panic_message(payload.as_ref())
+                                 )
+                             }
                          }
+                         drop(connection); // Close the socket before releasing the slot
+                         active_clients.fetch_sub(1, atomic::Ordering::SeqCst);
                      });
- 
-                     self.workers.push(handle);
+
+                     match spawned {
+                         Ok(handle) => {
+                             self.reap_workers();
+                             self.workers.lock().unwrap_or_else(|e| e.into_inner()).push(handle);
+                         }
+                         Err(e) => {
+                             error!("Error spawning handler thread for {}: {}", addr, e);
+                             self.active_clients.fetch_sub(1, atomic::Ordering::SeqCst);
+                         }
+                     }
                  }
                  Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                     // Handle non-blocking acceptance, retry after delay
-                     thread::sleep(Duration::from_millis(100));
+                     // Every pending connection is accepted; wait for the next burst
+                     self.reap_workers();
+                     accept_wait.wait(ACCEPT_POLL_INTERVAL);
+                 }
+                 Err(ref e) if is_resource_exhaustion(e) => {
+                     // Retrying at once would spin until descriptors are freed
+                     backoff = next_backoff(backoff);
+                     warn!(
+                         "Out of resources accepting connection ({}), retrying in {:?}",
+                         e, backoff
+                     );
+                     thread::sleep(backoff);
                  }
                  Err(e) => {
                      error!("Error accepting connection: {}", e);
                  }
              }
          }
- 
+
+         self.set_ready(false);
          info!("Server stopped.");
          Ok(())
      }
- 
+
+     /*
+      * \brief Blocks until `run` has entered its accept loop.
+      *
+      * Connections made after this returns are accepted without any retrying, which
+      * makes it a replacement for polling the port until a connection succeeds.
+      *
+      * \param timeout How long to wait for the accept loop to start.
+      * \return `Ok` once the server accepts connections, or a `TimedOut` error.
+      */
+     pub fn wait_ready(&self, timeout: Duration) -> io::Result<()> {
+         let ready = self.ready.lock().unwrap_or_else(|e| e.into_inner());
+         let (ready, _) = self
+             .ready_changed
+             .wait_timeout_while(ready, timeout, |ready| !*ready)
+             .unwrap_or_else(|e| e.into_inner());
+         if *ready {
+             Ok(())
+         } else {
+             Err(io::Error::new(ErrorKind::TimedOut, "Server did not start accepting in time"))
+         }
+     }
+
+     /// Returns the listening socket, or a `NotConnected` error once `stop` has closed it.
+     fn listener(&self) -> io::Result<Arc<TcpListener>> {
+         self.listener.lock().unwrap_or_else(|e| e.into_inner()).clone().ok_or_else(|| {
+             io::Error::new(
+                 ErrorKind::NotConnected,
+                 "The server is stopped and no longer listening",
+             )
+         })
+     }
+
+     /// Records whether the accept loop is running and wakes `wait_ready` callers.
+     fn set_ready(&self, ready: bool) {
+         *self.ready.lock().unwrap_or_else(|e| e.into_inner()) = ready;
+         self.ready_changed.notify_all();
+     }
+
+     /*
+      * \brief Accepts a single connection without spawning a handler for it.
+      *
+      * This is the building block for callers that drive their own accept loop and
+      * threading model instead of using `run`. The connection uses the server's
+      * configuration, takes part in graceful shutdown and counts towards `io_stats`, but
+      * it is neither limited by `max_clients` nor reported by `active_clients`. Connections
+      * beyond the pending handshake limit are refused and the next one is awaited.
+      *
+      * \return The accepted connection, ready to be handled.
+      */
+     pub fn accept_one(&self) -> io::Result<Connection> {
+         loop {
+             match self.listener()?.accept() {
+                 Ok((stream, peer)) => {
+                     if let Err(reason) = self.admit_handshake() {
+                         audit(self.config.audit_level, "reject", peer, &reason);
+                         continue;
+                     }
+                     let connection =
+                         self.open_connection(stream, peer, Arc::new(self.config.clone()));
+                     match connection {
+                         Ok(_) => audit(
+                             self.config.audit_level,
+                             "accept",
+                             peer,
+                             "accepted by accept_one",
+                         ),
+                         Err(ref e) => audit(
+                             self.config.audit_level,
+                             "reject",
+                             peer,
+                             &format!("socket setup failed: {}", e),
+                         ),
+                     }
+                     return connection;
+                 }
+                 Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                     thread::sleep(Duration::from_millis(10))
+                 }
+                 Err(e) => return Err(e),
+             }
+         }
+     }
+
+     /// Checks the pending handshake limit, returning the reason to refuse a new connection.
+     fn admit_handshake(&self) -> Result<(), String> {
+         match self.config.max_pending_handshakes {
+             Some(limit)
+                 if self.counters.pending_handshakes.load(atomic::Ordering::SeqCst) >= limit =>
+             {
+                 Err(format!("{} handshakes already pending", limit))
+             }
+             _ => Ok(()),
+         }
+     }
+
+     /*
+      * \brief Prepares an accepted socket for its handler and registers the connection.
+      *
+      * \param stream The accepted socket.
+      * \param peer The address of the client.
+      * \param config The settings the handler runs with.
+      * \return The connection, or an error if the socket could not be configured.
+      */
+     fn open_connection(
+         &self,
+         stream: TcpStream,
+         peer: SocketAddr,
+         config: Arc<Config>,
+     ) -> io::Result<Connection> {
+         // Accepted sockets must block; only the listener polls
+         stream.set_nonblocking(false)?;
+         let handle = stream.try_clone()?;
+         self.registry.lock().unwrap_or_else(|e| e.into_inner()).insert(peer, handle);
+
+         Ok(Connection {
+             client: self.new_client(stream, peer, config),
+             peer,
+             registry: self.registry.clone(),
+         })
+     }
+
+     /*
+      * \brief Serves a connection over any transport on the calling thread.
+      *
+      * This is meant for tests using `transport::memory_pair`, which talk to a handler
+      * without opening a socket. The connection behaves like one accepted by `run`, but
+      * it is not registered, so `disconnect_client` cannot reach it, and its peer address
+      * is reported as `IN_PROCESS_PEER`.
+      *
+      * \param stream The server's end of the connection.
+      * \return A result indicating success (`Ok`) or failure (`Err`) once the session ends.
+      */
+     pub fn handle_stream<S: Transport>(&self, stream: S) -> io::Result<()> {
+         self.new_client(stream, IN_PROCESS_PEER, Arc::new(self.config.clone())).handle()
+     }
+
+     /*
+      * \brief Answers exactly one request on a stream, on the calling thread.
+      *
+      * Like `accept_one`, but for a stream the caller already has, and it stops after a
+      * single request instead of serving the whole session, which keeps tests of the
+      * dispatch logic tightly scoped. No greeting is sent and no handshake is required.
+      * The peer address is reported as `IN_PROCESS_PEER`.
+      *
+      * \param stream The server's end of the connection.
+      * \return The kind of the request that was answered.
+      */
+     pub fn serve_once<S: Transport>(&self, stream: S) -> io::Result<MessageKind> {
+         self.new_client(stream, IN_PROCESS_PEER, Arc::new(self.config.clone())).handle_one()
+     }
+
+     /// Creates a handler for `stream` that shares this server's state and counters.
+     fn new_client<S: Transport>(
+         &self,
+         stream: S,
+         peer: SocketAddr,
+         config: Arc<Config>,
+     ) -> Client<S> {
+         Client::new(
+             stream,
+             peer,
+             config,
+             self.draining.clone(),
+             self.io_stats.clone(),
+             self.counters.clone(),
+             self.sessions.clone(),
+         )
+     }
+
+     /*
+      * \brief Closes the connection of a single client.
+      *
+      * The socket is shut down in both directions, so the handler sees the client go away
+      * and finishes as if it had disconnected. Requests it already received are still
+      * processed, but their responses can no longer be delivered.
+      *
+      * \param peer The address of the client, as seen by the server.
+      * \return `true` if a connection from `peer` was open.
+      */
+     pub fn disconnect_client(&self, peer: SocketAddr) -> bool {
+         let Some(stream) = self.registry.lock().unwrap_or_else(|e| e.into_inner()).remove(&peer)
+         else {
+             return false;
+         };
+         info!("Disconnecting client {} on request.", peer);
+         if let Err(e) = stream.shutdown(std::net::Shutdown::Both) {
+             warn!("Could not shut down connection of {}: {}", peer, e);
+         }
+         true
+     }
+
+     /*
+      * \brief Pushes a notification to the control channel of every open session.
+      *
+      * Each control connection writes the notification between two requests, so it
+      * arrives within one poll interval even while the connection is idle.
+      *
+      * \param content The text of the notification.
+      * \return The number of control channels the notification was queued for.
+      */
+     pub fn broadcast(&self, content: &str) -> usize {
+         let channels = self.sessions.lock();
+         let notification =
+             server_message::Message::Broadcast(Broadcast { content: content.to_string() });
+         channels
+             .values()
+             .flatten()
+             .filter(|channel| channel.send(notification.clone()).is_ok())
+             .count()
+     }
+
      /*
       * \brief Stops the server by setting the `is_running` flag to `false`.
-      * 
+      *
       * This function sends a shutdown signal to stop the server from accepting new
-      * connections and terminate the running threads.
+      * connections and terminate the running threads. The listening socket is released:
+      * at once if `run` was never called, otherwise as soon as `run` returns, so the port
+      * can be bound again without waiting for the server to be dropped.
       */
      pub fn stop(&self) {
-         let mut is_running = self.is_running.lock().unwrap();
+         if let Some(listener) = self.listener.lock().unwrap_or_else(|e| e.into_inner()).take() {
+             // Closes the socket unless `run` still holds it
+             drop(listener);
+             debug!("Released the listening socket.");
+         }
+         let mut is_running = self.is_running.lock().unwrap_or_else(|e| e.into_inner());
          if *is_running {
              *is_running = false;
              info!("Shutdown signal sent.");
@@ -181,19 +3287,548 @@
              warn!("Server was already stopped or not running.");
          }
      }
- 
+
+     /*
+      * \brief Stops the server and lets every connection finish its pending work.
+      *
+      * This function stops accepting new connections, then asks each handler to answer
+      * all complete requests the client has already sent before closing its connection.
+      * It returns once `run` has returned, every handler has finished and the shutdown
+      * hooks have run.
+      */
+     pub fn stop_graceful(&self) {
+         self.drain_and_report();
+     }
+
+     /*
+      * \brief Shuts down like `stop_graceful` and summarizes what happened meanwhile.
+      *
+      * The report counts the requests of all connections finished between the start of
+      * the drain and the moment the last handler returned, whenever they were received.
+      * A request the client had only partly sent when its connection was closed counts
+      * as dropped.
+      *
+      * \return How many requests were answered, rejected or left unanswered, and how
+      *         long the drain took.
+      */
+     pub fn drain_and_report(&self) -> ShutdownReport {
+         let started = Instant::now();
+         let before = self.request_totals();
+         self.stop();
+         self.draining.store(true, atomic::Ordering::SeqCst);
+         // A connection accepted just before the stop may not be registered yet
+         let active = self.run_active.lock().unwrap_or_else(|e| e.into_inner());
+         drop(
+             self.run_exited
+                 .wait_while(active, |active| *active)
+                 .unwrap_or_else(|e| e.into_inner()),
+         );
+         self.join_workers();
+         let after = self.request_totals();
+
+         let report = ShutdownReport {
+             handled: after[0] - before[0],
+             rejected: after[1] - before[1],
+             dropped: after[2] - before[2],
+             duration: started.elapsed(),
+         };
+         let hooks =
+             std::mem::take(&mut *self.shutdown_hooks.lock().unwrap_or_else(|e| e.into_inner()));
+         for hook in hooks {
+             if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(hook)) {
+                 error!("Shutdown hook panicked: {}", panic_message(payload.as_ref()));
+             }
+         }
+         info!("Graceful shutdown complete: {:?}", report);
+         report
+     }
+
+     /// Returns the handled, rejected and dropped request totals since the server was created.
+     fn request_totals(&self) -> [u64; 3] {
+         [
+             &self.counters.requests_handled,
+             &self.counters.requests_rejected,
+             &self.counters.requests_dropped,
+         ]
+         .map(|counter| counter.load(atomic::Ordering::Relaxed))
+     }
+
+     /*
+      * \brief Stops accepting new connections while serving the open ones.
+      *
+      * Clients connecting meanwhile wait in the listen backlog and are accepted after
+      * `resume`, within the limits of the backlog and of their connect timeout.
+      */
+     pub fn pause(&self) {
+         if !self.paused.swap(true, atomic::Ordering::SeqCst) {
+             info!("Paused accepting connections.");
+         }
+     }
+
+     /*
+      * \brief Accepts connections again after `pause`.
+      */
+     pub fn resume(&self) {
+         if self.paused.swap(false, atomic::Ordering::SeqCst) {
+             info!("Resumed accepting connections.");
+         }
+     }
+
+     /*
+      * \brief Returns the server's state, for liveness checks.
+      *
+      * \return `Draining` while a graceful shutdown waits for connections to finish,
+      *         otherwise `Running` or `Paused` while the accept loop runs, and `Stopped`
+      *         before `run` is called and after it returns or is asked to return.
+      */
+     pub fn health(&self) -> Health {
+         if self.draining.load(atomic::Ordering::SeqCst) && self.active_clients() > 0 {
+             return Health::Draining;
+         }
+         let running = *self.is_running.lock().unwrap_or_else(|e| e.into_inner());
+         let accepting = running && *self.ready.lock().unwrap_or_else(|e| e.into_inner());
+         match (accepting, self.paused.load(atomic::Ordering::SeqCst)) {
+             (false, _) => Health::Stopped,
+             (true, true) => Health::Paused,
+             (true, false) => Health::Running,
+         }
+     }
+
+     /*
+      * \brief Returns the number of connections currently being handled.
+      */
+     pub fn active_clients(&self) -> usize {
+         self.active_clients.load(atomic::Ordering::SeqCst)
+     }
+
+     /*
+      * \brief Returns the bytes read from and written to all connections so far.
+      *
+      * The counts include frame headers and cover every connection since the server was
+      * created, which makes them suitable for bandwidth monitoring.
+      *
+      * \return The `(read, written)` byte totals.
+      */
+     pub fn io_stats(&self) -> (u64, u64) {
+         (
+             self.io_stats.bytes_read.load(atomic::Ordering::Relaxed),
+             self.io_stats.bytes_written.load(atomic::Ordering::Relaxed),
+         )
+     }
+
+     /*
+      * \brief Returns the encoded size of the requests decoded but not yet answered.
+      *
+      * Each connection stops reading once it holds `MAX_QUEUED_BYTES`, so it never exceeds
+      * that limit by more than one frame.
+      *
+      * \return The total over all connections, in bytes.
+      */
+     pub fn queued_bytes(&self) -> usize {
+         self.counters.queued_bytes.load(atomic::Ordering::Relaxed)
+     }
+
+     /*
+      * \brief Returns the number of requests handled for each tenant.
+      *
+      * Clients name their tenant in the Hello handshake; requests of connections without
+      * a tenant label are not counted. The handshake itself is not counted either. At most
+      * 1024 tenants are counted; the requests of later tenants are left out.
+      *
+      * \return The request count per tenant label since the server was created.
+      */
+     pub fn tenant_metrics(&self) -> HashMap<String, u64> {
+         self.counters
+             .tenant_messages
+             .lock()
+             .unwrap_or_else(|e| e.into_inner())
+             .iter()
+             .map(|(tenant, count)| (tenant.clone(), count.load(atomic::Ordering::Relaxed)))
+             .collect()
+     }
+
+     /*
+      * \brief Returns the activity since the previous call, for interval metrics.
+      *
+      * Each call reports what happened since the last one, as if the counters were reset
+      * to zero, while the totals behind `io_stats` and `tenant_metrics` keep growing. The
+      * counters only ever increase, so every byte and request lands in exactly one
+      * snapshot, however handlers and concurrent calls interleave. A request is counted
+      * once its response has been written.
+      *
+      * \return The activity since the previous call, or since the server was created.
+      */
+     pub fn take_metrics(&self) -> ServerMetrics {
+         let mut baseline = self.metrics_baseline.lock().unwrap_or_else(|e| e.into_inner());
+         let [requests_handled, requests_rejected, requests_dropped] = self.request_totals();
+         let (bytes_read, bytes_written) = self.io_stats();
+         let totals = ServerMetrics {
+             bytes_read,
+             bytes_written,
+             requests_handled,
+             requests_rejected,
+             requests_dropped,
+             tenant_messages: self.tenant_metrics(),
+         };
+         let interval = totals.since(&baseline);
+         *baseline = totals;
+         interval
+     }
+
+     /*
+      * \brief Returns the distribution of the payload sizes received by the server.
+      *
+      * Every non-empty frame counts, whether or not its payload decodes. Minimum, maximum
+      * and mean cover all frames since the server was created; the 99th percentile is
+      * taken over the last 10 000 frames to keep memory bounded.
+      *
+      * \return The size statistics, or `None` if no frame has been received yet.
+      */
+     pub fn message_size_stats(&self) -> Option<MessageSizeStats> {
+         self.counters.message_sizes.lock().unwrap_or_else(|e| e.into_inner()).stats()
+     }
+
      /*
       * \brief Waits for all worker threads to finish.
-      * 
+      *
       * This function waits for all worker threads handling client connections to
       * complete their tasks before the server fully shuts down.
       */
-     pub fn join_workers(&mut self) {
-         for worker in self.workers.drain(..) {
+     pub fn join_workers(&self) {
+         let workers: Vec<JoinHandle<()>> =
+             self.workers.lock().unwrap_or_else(|e| e.into_inner()).drain(..).collect();
+         for worker in workers {
+             if let Err(e) = worker.join() {
+                 error!("Error joining worker thread: {:?}", e);
+             }
+         }
+     }
+
+     /*
+      * \brief Returns the number of worker threads the server still holds a handle to.
+      *
+      * `run` reaps finished workers as it goes, so this tracks the live connections
+      * rather than every connection ever accepted.
+      */
+     pub fn worker_count(&self) -> usize {
+         self.workers.lock().unwrap_or_else(|e| e.into_inner()).len()
+     }
+
+     /*
+      * \brief Joins the worker threads that have already finished, without blocking.
+      */
+     fn reap_workers(&self) {
+         let mut workers = self.workers.lock().unwrap_or_else(|e| e.into_inner());
+         let (finished, running): (Vec<_>, Vec<_>) =
+             workers.drain(..).partition(|worker| worker.is_finished());
+         *workers = running;
+         drop(workers);
+         for worker in finished {
              if let Err(e) = worker.join() {
                  error!("Error joining worker thread: {:?}", e);
              }
          }
      }
  }
- 
\ No newline at end of file
+
+ impl Drop for Server {
+     /*
+      * \brief Closes every connection still open when the server goes away.
+      *
+      * Without this, handler threads would outlive the server and its clients would
+      * wait for responses until their read timeout. Shutting the sockets down gives each
+      * client an immediate end-of-file, and each handler finishes as if its client had
+      * disconnected.
+      */
+     fn drop(&mut self) {
+         let registry =
+             std::mem::take(&mut *self.registry.lock().unwrap_or_else(|e| e.into_inner()));
+         if !registry.is_empty() {
+             info!("Server dropped, closing {} open connections.", registry.len());
+         }
+         for (peer, stream) in registry {
+             if let Err(e) = stream.shutdown(std::net::Shutdown::Both) {
+                 debug!("Could not shut down connection of {}: {}", peer, e);
+             }
+         }
+     }
+ }
+
+ #[cfg(test)]
+ mod tests {
+     use super::*;
+     use std::io::Read;
+
+     #[test]
+     fn test_worker_pool_admits_urgent_requests_first() {
+         let pool = Arc::new(WorkerPool::new(1));
+         let order = Arc::new(Mutex::new(Vec::new()));
+         let busy = pool.acquire(Priority::Normal);
+
+         // Queue bulk requests first, then a ping, while the only worker is busy
+         let mut waiters = Vec::new();
+         for priority in [Priority::Normal, Priority::Normal, Priority::High] {
+             let queued = pool.lock().waiting.len();
+             let (waiter_pool, waiter_order) = (pool.clone(), order.clone());
+             waiters.push(thread::spawn(move || {
+                 let _worker = waiter_pool.acquire(priority);
+                 waiter_order.lock().unwrap().push(priority);
+             }));
+             while pool.lock().waiting.len() == queued {
+                 thread::yield_now();
+             }
+         }
+         drop(busy);
+         for waiter in waiters {
+             waiter.join().unwrap();
+         }
+
+         assert_eq!(*order.lock().unwrap(), [Priority::High, Priority::Normal, Priority::Normal]);
+     }
+
+     #[test]
+     fn test_descriptor_exhaustion_is_classified() {
+         for code in EXHAUSTION_ERRNOS {
+             assert!(is_resource_exhaustion(&io::Error::from_raw_os_error(code)));
+         }
+         assert!(is_resource_exhaustion(&io::Error::from(ErrorKind::OutOfMemory)));
+         assert!(!is_resource_exhaustion(&io::Error::from(ErrorKind::ConnectionAborted)));
+         assert!(!is_resource_exhaustion(&io::Error::from(ErrorKind::WouldBlock)));
+     }
+
+     #[test]
+     fn test_accept_backoff_grows_to_cap() {
+         let mut backoff = next_backoff(Duration::ZERO);
+         assert_eq!(backoff, ACCEPT_BACKOFF_MIN);
+
+         backoff = next_backoff(backoff);
+         assert_eq!(backoff, ACCEPT_BACKOFF_MIN * 2);
+
+         for _ in 0..20 {
+             backoff = next_backoff(backoff);
+         }
+         assert_eq!(backoff, ACCEPT_BACKOFF_MAX);
+     }
+
+     #[test]
+     fn test_unknown_listener_address_is_not_fatal() {
+         let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+         assert_eq!(announce_listening(Ok(addr)), Some(addr));
+         assert_eq!(announce_listening(Err(io::Error::from(ErrorKind::NotConnected))), None);
+     }
+
+     #[test]
+     fn test_unencodable_response_becomes_error() {
+         use crate::message::{BinaryEcho, EchoMessage};
+         use crate::transport::memory_pair;
+
+         // The response fails to encode, so the client must get an error, not a partial frame
+         let server = Server::new("127.0.0.1:0", 1).unwrap().with_handler(|_| {
+             Some(server_message::Message::BinaryEcho(BinaryEcho { data: vec![0; 64].into() }))
+         });
+         FAILING_ENCODES.with(|failing| failing.set(1));
+         let (mut client_end, server_end) = memory_pair();
+         let echo =
+             client_message::Message::EchoMessage(EchoMessage { content: "oversized".to_string() });
+         frame::write_frame(
+             &mut client_end,
+             &ClientMessage { message: Some(echo), client_send_ts: 7, ..Default::default() }
+                 .encode_to_vec(),
+         )
+         .unwrap();
+         assert_eq!(server.serve_once(server_end).unwrap(), MessageKind::Echo);
+
+         let response =
+             ServerMessage::decode(frame::read_frame(&mut client_end).unwrap().as_slice()).unwrap();
+         assert_eq!(response.client_send_ts, 7);
+         assert_eq!(response.as_error().map(|error| error.code()), Some(ErrorCode::Internal));
+         assert_eq!(server.counters.requests_rejected.load(atomic::Ordering::Relaxed), 1);
+         assert_eq!(server.counters.requests_handled.load(atomic::Ordering::Relaxed), 0);
+     }
+
+     /// Transport whose first reads fail with `Interrupted`, as if a signal had arrived.
+     struct Interrupting<S> {
+         inner: S,
+         interruptions: usize,
+     }
+
+     impl<S: Read> Read for Interrupting<S> {
+         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+             if self.interruptions > 0 {
+                 self.interruptions -= 1;
+                 return Err(io::Error::from(ErrorKind::Interrupted));
+             }
+             self.inner.read(buf)
+         }
+     }
+
+     impl<S: Write> Write for Interrupting<S> {
+         fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+             self.inner.write(buf)
+         }
+
+         fn flush(&mut self) -> io::Result<()> {
+             self.inner.flush()
+         }
+     }
+
+     impl<S: Transport> Transport for Interrupting<S> {
+         fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+             self.inner.set_read_timeout(timeout)
+         }
+
+         fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+             self.inner.set_nonblocking(nonblocking)
+         }
+
+         fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+             self.inner.set_write_timeout(timeout)
+         }
+     }
+
+     #[test]
+     fn test_interrupted_reads_are_retried() {
+         use crate::message::EchoMessage;
+         use crate::transport::{memory_pair, MemoryStream};
+
+         let server = Arc::new(Server::new("127.0.0.1:0", 1).unwrap());
+         let echo = |content: &str| {
+             let echo =
+                 client_message::Message::EchoMessage(EchoMessage { content: content.to_string() });
+             ClientMessage { message: Some(echo), ..Default::default() }.encode_to_vec()
+         };
+         let read_echo = |stream: &mut MemoryStream| {
+             let response =
+                 ServerMessage::decode(frame::read_frame(stream).unwrap().as_slice()).unwrap();
+             response.as_echo().map(|echo| echo.content.clone())
+         };
+
+         let (mut client_end, server_end) = memory_pair();
+         frame::write_frame(&mut client_end, &echo("once")).unwrap();
+         assert_eq!(
+             server.serve_once(Interrupting { inner: server_end, interruptions: 3 }).unwrap(),
+             MessageKind::Echo
+         );
+         assert_eq!(read_echo(&mut client_end).as_deref(), Some("once"));
+
+         let (mut client_end, server_end) = memory_pair();
+         let session = {
+             let server = server.clone();
+             thread::spawn(move || {
+                 server.handle_stream(Interrupting { inner: server_end, interruptions: 3 })
+             })
+         };
+         frame::write_frame(&mut client_end, &echo("session")).unwrap();
+         assert_eq!(read_echo(&mut client_end).as_deref(), Some("session"));
+         drop(client_end);
+         assert!(
+             session.join().unwrap().is_ok(),
+             "An interrupted read must not end the session with an error"
+         );
+     }
+
+     #[test]
+     fn test_capabilities_follow_configuration() {
+         let base = || Server::new("localhost:0", 1).expect("Failed to create server");
+         let optional = |server: &Server| {
+             let all = capabilities(&server.config);
+             all[capabilities(&base().config).len()..].to_vec()
+         };
+         assert!(optional(&base()).is_empty(), "Defaults advertise {:?}", optional(&base()));
+
+         type Configure = fn(Server) -> Server;
+         let cases: Vec<(&str, Configure)> = vec![
+             ("custom_handler", |server| server.with_handler(|_| None)),
+             ("stream_responses", |server| server.with_stream_handler(|_| None)),
+             ("async_responses", |server| server.with_async_handler(|_, _| false)),
+             ("request_validation", |server| server.with_validator(|_| Ok(()))),
+             ("shutdown_notice", |server| server.with_shutdown_notice("maintenance")),
+             ("redirect", |server| server.with_redirect("localhost", 1)),
+             ("empty_echo_rejected", |server| {
+                 server.with_empty_echo_policy(EmptyEchoPolicy::Reject)
+             }),
+             ("strict_decoding", |server| server.with_strict_decoding(true)),
+             ("session_timeout", |server| server.with_session_timeout(Duration::from_secs(1))),
+             ("handshake_timeout", |server| server.with_handshake_timeout(Duration::from_secs(1))),
+             ("greeting", |server| server.with_greeting("test")),
+             ("frame_magic", |server| server.with_frame_magic(*b"EM")),
+             ("control_channel", |server| server.with_control_channels(true)),
+             ("priorities", |server| server.with_worker_pool(1)),
+         ];
+         for (feature, configure) in cases {
+             assert_eq!(optional(&configure(base())), vec![feature.to_string()]);
+         }
+     }
+
+     #[test]
+     fn test_kind_maps_every_variant() {
+         use crate::message::{
+             AddRequest, BatchRequest, BinaryEcho, CapabilitiesRequest, DivideRequest, EchoMessage,
+             HelloRequest, PingRequest,
+         };
+
+         let cases = [
+             (client_message::Message::EchoMessage(EchoMessage::default()), MessageKind::Echo),
+             (client_message::Message::BinaryEcho(BinaryEcho::default()), MessageKind::BinaryEcho),
+             (client_message::Message::AddRequest(AddRequest::default()), MessageKind::Add),
+             (client_message::Message::PingRequest(PingRequest::default()), MessageKind::Ping),
+             (
+                 client_message::Message::CapabilitiesRequest(CapabilitiesRequest::default()),
+                 MessageKind::Capabilities,
+             ),
+             (client_message::Message::HelloRequest(HelloRequest::default()), MessageKind::Hello),
+             (
+                 client_message::Message::DivideRequest(DivideRequest::default()),
+                 MessageKind::Divide,
+             ),
+             (client_message::Message::BatchRequest(BatchRequest::default()), MessageKind::Batch),
+         ];
+         for (message, expected) in cases {
+             assert_eq!(kind(&message), expected, "Wrong kind for {:?}", message);
+         }
+     }
+
+     #[test]
+     fn test_poisoned_locks_are_recovered() {
+         use crate::message::EchoMessage;
+
+         let server = Arc::new(Server::new("127.0.0.1:0", 1).unwrap());
+         let addr = server.listener.lock().unwrap().as_ref().unwrap().local_addr().unwrap();
+
+         // Panic while holding the size samples and the running flag
+         let poisoner = server.clone();
+         let panicked = thread::spawn(move || {
+             let _sizes = poisoner.counters.message_sizes.lock().unwrap();
+             let _running = poisoner.is_running.lock().unwrap();
+             panic!("Poisoning the server's locks");
+         })
+         .join();
+         assert!(panicked.is_err());
+         assert!(server.counters.message_sizes.is_poisoned());
+         assert!(server.is_running.is_poisoned());
+
+         let runner = server.clone();
+         let handle = thread::spawn(move || runner.run());
+         server.wait_ready(Duration::from_secs(5)).unwrap();
+         assert_eq!(server.health(), Health::Running);
+
+         let mut stream = TcpStream::connect(addr).unwrap();
+         stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+         let echo =
+             client_message::Message::EchoMessage(EchoMessage { content: "recovered".to_string() });
+         let request = ClientMessage { message: Some(echo), ..Default::default() }.encode_to_vec();
+         frame::write_frame(&mut stream, &request).unwrap();
+         let response =
+             ServerMessage::decode(frame::read_frame(&mut stream).unwrap().as_slice()).unwrap();
+         assert_eq!(response.as_echo().map(|echo| echo.content.as_str()), Some("recovered"));
+         drop(stream);
+
+         server.stop();
+         assert!(handle.join().unwrap().is_ok(), "The server must stop despite the poisoned flag");
+         server.join_workers();
+         assert_eq!(server.health(), Health::Stopped);
+         let sizes = server.message_size_stats().expect("The echo was not recorded");
+         assert_eq!((sizes.count, sizes.max), (1, request.len()));
+         assert_eq!(server.take_metrics().requests_handled, 1);
+     }
+ }
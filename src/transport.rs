@@ -0,0 +1,263 @@
+/*!
+ * \file transport.rs
+ * \author Mohamed Shaban Waaer
+ * \date 2024-12-24
+ *
+ * \brief Byte streams a connection handler can run on.
+ *
+ * The server's connection handler only needs to read, write, bound how long a read
+ * blocks and switch between blocking and non-blocking reads. `Transport` captures
+ * exactly that, so the handler runs unchanged on a `TcpStream` or on the in-process
+ * `MemoryStream` returned by `memory_pair`.
+ *
+ * The in-process transport exists for tests: both ends live in the same process and
+ * exchange bytes through shared buffers, so a test can talk to a handler without
+ * opening a socket. Its buffers are unbounded, so it does not model TCP backpressure.
+ */
+
+ use std::collections::VecDeque;
+ use std::io::{self, Read, Write};
+ use std::net::{Shutdown, TcpStream};
+ use std::sync::atomic::{AtomicBool, Ordering};
+ use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+ use std::time::{Duration, Instant};
+
+ /// A bidirectional byte stream a connection handler can serve.
+ pub trait Transport: Read + Write + Send {
+     /*
+      * \brief Bounds how long a blocking read waits for data.
+      *
+      * \param timeout The longest wait, or `None` to wait indefinitely.
+      * \return A result indicating success or failure.
+      */
+     fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+     /*
+      * \brief Makes reads return `WouldBlock` instead of waiting when no data is available.
+      *
+      * \param nonblocking Whether reads should stop blocking.
+      * \return A result indicating success or failure.
+      */
+     fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+     /*
+      * \brief Bounds how long a blocking write waits for the peer to make room.
+      *
+      * \param timeout The longest wait, or `None` to wait indefinitely.
+      * \return A result indicating success or failure.
+      */
+     fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+     /*
+      * \brief Holds back partial packets until uncorked, so small writes are coalesced.
+      *
+      * Uncorking sends everything held back. Transports without such an option, which
+      * is the default, ignore the call.
+      *
+      * \param corked Whether to hold back partial packets.
+      * \return A result indicating success or failure.
+      */
+     fn set_corked(&self, _corked: bool) -> io::Result<()> {
+         Ok(())
+     }
+ }
+
+ impl Transport for TcpStream {
+     fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+         TcpStream::set_read_timeout(self, timeout)
+     }
+
+     fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+         TcpStream::set_nonblocking(self, nonblocking)
+     }
+
+     fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+         TcpStream::set_write_timeout(self, timeout)
+     }
+
+     #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+     fn set_corked(&self, corked: bool) -> io::Result<()> {
+         socket2::SockRef::from(self).set_tcp_cork(corked)
+     }
+ }
+
+ /// One direction of an in-process connection.
+ #[derive(Default)]
+ struct Pipe {
+     state: Mutex<PipeState>,
+     readable: Condvar,
+ }
+
+ #[derive(Default)]
+ struct PipeState {
+     buffer: VecDeque<u8>,
+     closed: bool,
+ }
+
+ impl Pipe {
+     fn lock(&self) -> MutexGuard<'_, PipeState> {
+         self.state.lock().unwrap_or_else(|e| e.into_inner())
+     }
+
+     /// Marks the pipe closed and wakes any reader waiting on it.
+     fn close(&self) {
+         self.lock().closed = true;
+         self.readable.notify_all();
+     }
+ }
+
+ /// \brief One end of an in-process connection created by `memory_pair`.
+ pub struct MemoryStream {
+     incoming: Arc<Pipe>,
+     outgoing: Arc<Pipe>,
+     read_timeout: Mutex<Option<Duration>>,
+     nonblocking: AtomicBool,
+ }
+
+ /*
+  * \brief Creates two connected in-process streams.
+  *
+  * Bytes written to one end are read from the other. Dropping an end closes the
+  * connection: the other end reads end-of-file and its writes fail with `BrokenPipe`.
+  *
+  * \return The two ends of the connection.
+  */
+ pub fn memory_pair() -> (MemoryStream, MemoryStream) {
+     let forward = Arc::new(Pipe::default());
+     let backward = Arc::new(Pipe::default());
+     (MemoryStream::new(backward.clone(), forward.clone()), MemoryStream::new(forward, backward))
+ }
+
+ impl MemoryStream {
+     fn new(incoming: Arc<Pipe>, outgoing: Arc<Pipe>) -> Self {
+         MemoryStream {
+             incoming,
+             outgoing,
+             read_timeout: Mutex::new(None),
+             nonblocking: AtomicBool::new(false),
+         }
+     }
+
+     /*
+      * \brief Reads received bytes without removing them, like `TcpStream::peek`.
+      *
+      * Unlike a read, this never waits: with nothing received on an open connection it
+      * fails with `WouldBlock`.
+      *
+      * \param buf Where to copy the bytes.
+      * \return The number of bytes copied; zero means the other end closed the connection.
+      */
+     pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+         let state = self.incoming.lock();
+         if state.buffer.is_empty() && !state.closed {
+             return Err(io::Error::from(io::ErrorKind::WouldBlock));
+         }
+         let len = buf.len().min(state.buffer.len());
+         for (slot, byte) in buf.iter_mut().zip(state.buffer.iter()) {
+             *slot = *byte;
+         }
+         Ok(len)
+     }
+
+     /*
+      * \brief Closes one or both directions of the connection, like `TcpStream::shutdown`.
+      *
+      * After closing the write direction the other end reads end-of-file; after closing
+      * the read direction its writes fail with `BrokenPipe`.
+      *
+      * \param how The directions to close.
+      * \return A result indicating success or failure.
+      */
+     pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+         if matches!(how, Shutdown::Read | Shutdown::Both) {
+             self.incoming.close();
+         }
+         if matches!(how, Shutdown::Write | Shutdown::Both) {
+             self.outgoing.close();
+         }
+         Ok(())
+     }
+ }
+
+ impl Read for MemoryStream {
+     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+         if buf.is_empty() {
+             return Ok(0);
+         }
+         let timeout = *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner());
+         let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+         let mut state = self.incoming.lock();
+         loop {
+             if !state.buffer.is_empty() {
+                 let len = buf.len().min(state.buffer.len());
+                 for (slot, byte) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+                     *slot = byte;
+                 }
+                 return Ok(len);
+             }
+             if state.closed {
+                 return Ok(0);
+             }
+             if self.nonblocking.load(Ordering::SeqCst) {
+                 return Err(io::Error::from(io::ErrorKind::WouldBlock));
+             }
+             state = match deadline {
+                 None => self.incoming.readable.wait(state).unwrap_or_else(|e| e.into_inner()),
+                 Some(deadline) => {
+                     let remaining = deadline.saturating_duration_since(Instant::now());
+                     if remaining.is_zero() {
+                         // Same kind a timed-out `TcpStream` read reports on Unix
+                         return Err(io::Error::from(io::ErrorKind::WouldBlock));
+                     }
+                     self.incoming
+                         .readable
+                         .wait_timeout(state, remaining)
+                         .unwrap_or_else(|e| e.into_inner())
+                         .0
+                 }
+             };
+         }
+     }
+ }
+
+ impl Write for MemoryStream {
+     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+         let mut state = self.outgoing.lock();
+         if state.closed {
+             return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+         }
+         state.buffer.extend(buf);
+         drop(state);
+         self.outgoing.readable.notify_all();
+         Ok(buf.len())
+     }
+
+     fn flush(&mut self) -> io::Result<()> {
+         Ok(())
+     }
+ }
+
+ impl Transport for MemoryStream {
+     fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+         *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+         Ok(())
+     }
+
+     fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+         self.nonblocking.store(nonblocking, Ordering::SeqCst);
+         Ok(())
+     }
+
+     fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+         // Writes never block: the buffers are unbounded
+         Ok(())
+     }
+ }
+
+ impl Drop for MemoryStream {
+     fn drop(&mut self) {
+         self.incoming.close();
+         self.outgoing.close();
+     }
+ }
@@ -6,167 +6,1608 @@
  *
  * This module defines a `Client` struct that can connect to a server via TCP, send messages,
  * receive messages with retries, and disconnect. It uses the `prost` crate for message encoding
- * and decoding, and includes basic error handling.
+ * and decoding, and includes basic error handling. The same client also runs over one end of
+ * an in-process `transport::memory_pair`, which lets tests skip the socket entirely.
  *
  */
 
- use embedded_recruitment_task::message::{client_message, ServerMessage};
- use log::{error, info};
+ use embedded_recruitment_task::frame::{self, FrameDecoder};
+ use embedded_recruitment_task::message::{
+     client_message, server_message, AddRequest, AddResponse, BatchRequest, BatchResponse,
+     BinaryEcho, CapabilitiesRequest, CapabilitiesResponse, ClientMessage, DivideRequest,
+     DivideResponse, EchoMessage, Greeting, HelloRequest, HelloResponse, PingRequest, PongResponse,
+     Redirect, ServerMessage,
+ };
+ use embedded_recruitment_task::server::PROTOCOL_VERSION;
+ use embedded_recruitment_task::transport::{MemoryStream, Transport};
+ use log::{debug, error, info, warn};
  use prost::Message;
- use std::io::{self, Read, Write};
+ use std::io::{self, Read};
  use std::{
-     net::{SocketAddr, TcpStream, ToSocketAddrs},
-     time::Duration,
+     net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+     time::{Duration, Instant},
  };
  use std::thread;
  
- /// \brief Represents a TCP client that communicates with a server.
- pub struct Client {
+ /// Timeouts longer than this are accepted but almost certainly a mistake, such as seconds passed
+ /// as milliseconds.
+ const MAX_SENSIBLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+ 
+ /// Pause between two attempts of `ping_until_ready`.
+ const READY_PROBE_INTERVAL: Duration = Duration::from_millis(50);
+ 
+ /// Payload bytes `measure_throughput` sends before waiting for their responses.
+ const THROUGHPUT_WINDOW: usize = 64 * 1024;
+
+ /// \brief Error carried by `receive_with_retry` when the server announces its shutdown.
+ #[derive(Debug)]
+ pub struct ServerShutdown {
+     pub reason: String,
+ }
+ 
+ impl std::fmt::Display for ServerShutdown {
+     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+         write!(f, "Server is shutting down: {}", self.reason)
+     }
+ }
+ 
+ impl std::error::Error for ServerShutdown {}
+ 
+ /// \brief Error carried by `receive_with_retry` when the server sends the client to another
+ /// server.
+ #[derive(Debug)]
+ pub struct ServerRedirect {
+     pub host: String,
+     pub port: u32,
+ }
+ 
+ impl std::fmt::Display for ServerRedirect {
+     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+         write!(f, "Server redirected the client to {}:{}", self.host, self.port)
+     }
+ }
+ 
+ impl std::error::Error for ServerRedirect {}
+ 
+ /// \brief Error carried by `request_expect` when the server answers with another variant.
+ #[derive(Debug)]
+ pub struct UnexpectedResponse {
+     pub expected: &'static str,
+     pub received: server_message::Message,
+ }
+ 
+ impl std::fmt::Display for UnexpectedResponse {
+     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+         write!(f, "Expected {}, but received {:?}", self.expected, self.received)
+     }
+ }
+ 
+ impl std::error::Error for UnexpectedResponse {}
+ 
+ /// \brief A `server_message::Message` variant `request_expect` can wait for.
+ pub trait Response: Sized {
+     /// Name of the variant, for error messages.
+     const VARIANT: &'static str;
+ 
+     /*
+      * \brief Takes the payload out of `message` if it is this variant.
+      *
+      * \param message The received message.
+      * \return The payload, or `message` unchanged if it is another variant.
+      */
+     fn from_message(message: server_message::Message) -> Result<Self, server_message::Message>;
+ }
+
+ /// Implements `Response` for the payload of each listed variant.
+ macro_rules! impl_response {
+     ($($variant:ident($payload:ty)),* $(,)?) => {
+         $(impl Response for $payload {
+             const VARIANT: &'static str = stringify!($payload);
+
+             fn from_message(
+                 message: server_message::Message,
+             ) -> Result<Self, server_message::Message> {
+                 match message {
+                     server_message::Message::$variant(payload) => Ok(payload),
+                     other => Err(other),
+                 }
+             }
+         })*
+     };
+ }
+ 
+ impl_response!(
+     EchoMessage(EchoMessage),
+     AddResponse(AddResponse),
+     PongResponse(PongResponse),
+     BinaryEcho(BinaryEcho),
+     CapabilitiesResponse(CapabilitiesResponse),
+     HelloResponse(HelloResponse),
+     DivideResponse(DivideResponse),
+     BatchResponse(BatchResponse),
+ );
+ 
+ /// \brief A request `request_typed` can send.
+ pub trait Request {
+     /*
+      * \brief Wraps the request in the `client_message::Message` variant that carries it.
+      */
+     fn into_message(self) -> client_message::Message;
+ }
+ 
+ /// Implements `Request` for the payload of each listed variant.
+ macro_rules! impl_request {
+     ($($variant:ident($payload:ty)),* $(,)?) => {
+         $(impl Request for $payload {
+             fn into_message(self) -> client_message::Message {
+                 client_message::Message::$variant(self)
+             }
+         })*
+     };
+ }
+ 
+ impl_request!(
+     EchoMessage(EchoMessage),
+     AddRequest(AddRequest),
+     PingRequest(PingRequest),
+     BinaryEcho(BinaryEcho),
+     CapabilitiesRequest(CapabilitiesRequest),
+     HelloRequest(HelloRequest),
+     DivideRequest(DivideRequest),
+     BatchRequest(BatchRequest),
+ );
+ 
+ /// \brief Rates measured by `measure_throughput`.
+ #[derive(Debug, Clone, Copy, PartialEq)]
+ pub struct ThroughputStats {
+     /// Messages sent and answered.
+     pub messages: usize,
+     /// Payload bytes sent, each echoed back once.
+     pub bytes: usize,
+     /// Time from the first send to the last response.
+     pub elapsed: Duration,
+     pub messages_per_sec: f64,
+     pub bytes_per_sec: f64,
+ }
+ 
+ /// \brief Bytes a client exchanged with the server, reported by `disconnect_with_stats`.
+ #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+ pub struct SessionStats {
+     /// Bytes received, framing included.
+     pub bytes_read: u64,
+     /// Bytes sent, framing and handshake included.
+     pub bytes_written: u64,
+ }
+ 
+ /// \brief How a client re-establishes a connection it lost.
+ #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+ pub struct ReconnectPolicy {
+     /// Connection attempts made before giving up; at least one is always made.
+     pub max_attempts: u32,
+     /// Delay after the first failed attempt; it doubles after every further failure.
+     pub backoff: Duration,
+ }
+ 
+ impl Default for ReconnectPolicy {
+     /// A single immediate attempt.
+     fn default() -> Self {
+         ReconnectPolicy { max_attempts: 1, backoff: Duration::ZERO }
+     }
+ }
+
+ /// \brief A stream the client can reach the server over.
+ pub trait ClientStream: Transport + Sized {
+     /*
+      * \brief Connects to the first of `socket_addrs` that accepts the connection.
+      *
+      * \param socket_addrs The addresses to try, in order.
+      * \param timeout How long each attempt may take, or `None` for the system default.
+      * \param nodelay Whether to disable Nagle's algorithm on the new connection.
+      * \return The connected stream, or an error if none could be opened.
+      */
+     fn open(
+         socket_addrs: &[SocketAddr],
+         timeout: Option<Duration>,
+         nodelay: bool,
+     ) -> io::Result<Self>;
+
+     /*
+      * \brief Reads received bytes without removing them and without waiting for more.
+      *
+      * \param buf Where to copy the bytes.
+      * \return The number of bytes copied; zero means the server closed the connection.
+      */
+     fn peek(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+     /*
+      * \brief Closes one or both directions of the connection.
+      *
+      * \param how The directions to close.
+      * \return A result indicating success or failure.
+      */
+     fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+ }
+
+ impl ClientStream for TcpStream {
+     fn open(
+         socket_addrs: &[SocketAddr],
+         timeout: Option<Duration>,
+         nodelay: bool,
+     ) -> io::Result<Self> {
+         let stream = connect_any(socket_addrs, timeout)?;
+         stream.set_nodelay(nodelay)?;
+         Ok(stream)
+     }
+
+     fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+         TcpStream::peek(self, buf)
+     }
+
+     fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+         TcpStream::shutdown(self, how)
+     }
+ }
+
+ impl ClientStream for MemoryStream {
+     fn open(
+         _socket_addrs: &[SocketAddr],
+         _timeout: Option<Duration>,
+         _nodelay: bool,
+     ) -> io::Result<Self> {
+         Err(io::Error::new(
+             io::ErrorKind::Unsupported,
+             "An in-process connection cannot be reopened; \
+              pass a new one to Client::from_transport",
+         ))
+     }
+
+     fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+         MemoryStream::peek(self, buf)
+     }
+
+     fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+         MemoryStream::shutdown(self, how)
+     }
+ }
+
+ /// \brief Represents a client that communicates with a server over TCP or an in-process stream.
+ pub struct Client<S = TcpStream> {
+     ip: String,
+     port: u32,
+     connect_timeout: Option<Duration>,
+     timeout: Duration,
+     write_timeout: Option<Duration>,
+     nodelay: bool,
+     reconnect: Option<ReconnectPolicy>,
+     raw_framing: bool,
+     tenant: String,
+     magic: Option<[u8; 2]>,
+     verbose: bool,
+     stream: Option<S>,
+     /// Bytes received on `stream` that do not make up a whole frame yet.
+     decoder: FrameDecoder,
+     send_buffer: Vec<u8>,
+     /// Request `receive_with_retry` resends after reconnecting; only `send_idempotent` sets it.
+     idempotent_request: Option<client_message::Message>,
+     protocol_version: Option<u32>,
+     greeting: Option<Greeting>,
+     /// Session the server opened for this connection, or the one it controls.
+     session_id: Option<String>,
+     /// Whether the handshake attaches this connection to `session_id` as a control channel.
+     control: bool,
+     /// Set by `disconnect` so that auto-reconnect does not revive a connection closed on purpose.
+     disconnected: bool,
+     /// Bytes exchanged since the last disconnect, reconnections included.
+     session_stats: SessionStats,
+ }
+ 
+ /// \brief Step-by-step configuration of a `Client`.
+ pub struct ClientBuilder {
      ip: String,
      port: u32,
+     connect_timeout: Option<Duration>,
      timeout: Duration,
-     stream: Option<TcpStream>,
+     nodelay: bool,
+     reconnect: Option<ReconnectPolicy>,
+     raw_framing: bool,
+     tenant: String,
+     magic: Option<[u8; 2]>,
+ }
+ 
+ impl Default for ClientBuilder {
+     fn default() -> Self {
+         ClientBuilder {
+             ip: "localhost".to_string(),
+             port: 0,
+             connect_timeout: None,
+             timeout: Duration::from_secs(30),
+             nodelay: false,
+             reconnect: None,
+             raw_framing: true,
+             tenant: String::new(),
+             magic: None,
+         }
+     }
  }
  
+ impl ClientBuilder {
+     /*
+      * \brief Starts a builder targeting `localhost` with a 30 second read timeout.
+      */
+     pub fn new() -> Self {
+         Self::default()
+     }
+ 
+     /*
+      * \brief Sets the host name or IP address of the server.
+      */
+     pub fn host(mut self, ip: &str) -> Self {
+         self.ip = ip.to_string();
+         self
+     }
+ 
+     /*
+      * \brief Sets the port number of the server.
+      */
+     pub fn port(mut self, port: u32) -> Self {
+         self.port = port;
+         self
+     }
+ 
+     /*
+      * \brief Limits how long `connect` waits for the TCP handshake.
+      *
+      * Without it, `connect` waits for as long as the operating system allows. A zero
+      * timeout is rejected by `connect`.
+      */
+     pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+         self.connect_timeout = Some(timeout);
+         self
+     }
+ 
+     /*
+      * \brief Sets how long each read waits for a response.
+      *
+      * A zero timeout is rejected by `connect`.
+      */
+     pub fn read_timeout(mut self, timeout: Duration) -> Self {
+         self.timeout = timeout;
+         self
+     }
+ 
+     /*
+      * \brief Enables or disables Nagle's algorithm (`TCP_NODELAY`) on the connection.
+      */
+     pub fn nodelay(mut self, nodelay: bool) -> Self {
+         self.nodelay = nodelay;
+         self
+     }
+ 
+     /*
+      * \brief Lets the client reconnect once, without delay, when the connection was lost.
+      *
+      * See `Client::set_auto_reconnect` for a configurable policy.
+      */
+     pub fn auto_reconnect(mut self, auto_reconnect: bool) -> Self {
+         self.reconnect = auto_reconnect.then(ReconnectPolicy::default);
+         self
+     }
+ 
+     /*
+      * \brief Chooses whether `send_raw` adds a length prefix and `receive_raw` reads whole frames.
+      *
+      * Enabled by default.
+      */
+     pub fn raw_framing(mut self, raw_framing: bool) -> Self {
+         self.raw_framing = raw_framing;
+         self
+     }
+ 
+     /*
+      * \brief Sets the tenant label sent in the Hello handshake.
+      *
+      * The server counts the connection's requests under this label.
+      */
+     pub fn tenant(mut self, tenant: &str) -> Self {
+         self.tenant = tenant.to_string();
+         self
+     }
+ 
+     /*
+      * \brief Sends `magic` at the start of every connection, for servers that require it.
+      */
+     pub fn magic(mut self, magic: [u8; 2]) -> Self {
+         self.magic = Some(magic);
+         self
+     }
+ 
+     /*
+      * \brief Creates the configured, not yet connected, `Client`.
+      */
+     pub fn build(self) -> Client {
+         self.build_for()
+     }
+
+     /// Builds a client for any kind of stream; `build` picks TCP.
+     fn build_for<S>(self) -> Client<S> {
+         Client {
+             ip: self.ip,
+             port: self.port,
+             connect_timeout: self.connect_timeout,
+             timeout: self.timeout,
+             write_timeout: None,
+             nodelay: self.nodelay,
+             reconnect: self.reconnect,
+             raw_framing: self.raw_framing,
+             tenant: self.tenant,
+             magic: self.magic,
+             verbose: false,
+             stream: None,
+             decoder: FrameDecoder::new(),
+             send_buffer: Vec::new(),
+             idempotent_request: None,
+             protocol_version: None,
+             greeting: None,
+             session_id: None,
+             control: false,
+             disconnected: false,
+             session_stats: SessionStats::default(),
+         }
+     }
+ }
+
  impl Client {
      /*
-      * \brief Creates a new instance of the Client.
+      * \brief Creates a new instance of the Client.
+      *
+      * This function initializes the client with the specified IP address, port, and timeout
+      * duration.
+      * It is a shortcut for the equivalent `ClientBuilder` chain.
+      *
+      * \param ip The IP address of the server.
+      * \param port The port number of the server.
+      * \param timeout_ms The timeout duration in milliseconds for socket operations; `connect`
+      *        rejects zero.
+      * \return A new `Client` instance.
+      */
+     pub fn new(ip: &str, port: u32, timeout_ms: u64) -> Self {
+         ClientBuilder::new()
+             .host(ip)
+             .port(port)
+             .read_timeout(Duration::from_millis(timeout_ms))
+             .build()
+     }
+
+     /*
+      * \brief Returns a `ClientBuilder` for configuring a client option by option.
+      */
+     pub fn builder() -> ClientBuilder {
+         ClientBuilder::new()
+     }
+
+     /*
+      * \brief Wraps a stream that is already connected to the server.
+      *
+      * The TCP connect is skipped, but the Hello handshake runs as in `connect`, so the
+      * returned client is ready to send. The stream's peer address becomes the client's
+      * server address, which `send` uses if auto-reconnect is later needed.
+      *
+      * \param stream The connected stream.
+      * \return The connected client, or an error if the handshake fails.
+      */
+     pub fn from_stream(stream: TcpStream) -> io::Result<Self> {
+         let peer = stream.peer_addr()?;
+         let mut client = ClientBuilder::new()
+             .host(&peer.ip().to_string())
+             .port(peer.port().into())
+             .build();
+         client.stream = Some(stream);
+         client.decoder = FrameDecoder::new();
+         client.handshake()?;
+         Ok(client)
+     }
+
+     /*
+      * \brief Returns the local address of the connection, which the server sees as the peer.
+      *
+      * \return The address, or `None` while not connected.
+      */
+     pub fn local_addr(&self) -> Option<SocketAddr> {
+         self.stream
+             .as_ref()
+             .and_then(|stream| stream.local_addr().ok())
+     }
+
+     /*
+      * \brief Splits the connected client into a sending and a receiving half.
+      *
+      * Each half owns its own handle to the socket, so one thread can send while another
+      * waits for responses without either blocking the other, which a `Client` shared
+      * behind a single lock cannot offer. Messages still buffered by `send_buffered`
+      * are flushed first. Reconnection is not available on the halves.
+      *
+      * \return The two halves, or an error if the client is not connected.
+      */
+     pub fn split(mut self) -> io::Result<(Sender, Receiver)> {
+         self.flush()?;
+         let Some(stream) = self.stream.take() else {
+             return Err(io::Error::new(
+                 io::ErrorKind::NotConnected,
+                 "No active connection",
+             ));
+         };
+         let sender = Sender {
+             stream: stream.try_clone()?,
+             verbose: self.verbose,
+         };
+         let receiver = Receiver {
+             stream,
+             decoder: self.decoder,
+             timeout: self.timeout,
+             verbose: self.verbose,
+         };
+         Ok((sender, receiver))
+     }
+ }
+
+ impl<S: ClientStream> Client<S> {
+     /*
+      * \brief Creates a client talking over an already connected stream of any kind.
+      *
+      * Meant for the in-process transport: pass the client's end of
+      * `transport::memory_pair` while a server handles the other end. The Hello handshake
+      * runs as in `connect`. Such a client cannot reconnect, since the stream cannot be
+      * reopened.
+      *
+      * \param stream The connected stream.
+      * \return The connected client, or an error if the handshake fails.
+      */
+     pub fn from_transport(stream: S) -> io::Result<Self> {
+         let mut client = ClientBuilder::new().build_for();
+         client.stream = Some(stream);
+         client.handshake()?;
+         Ok(client)
+     }
+
+     /*
+      * \brief Connects the client to the server.
+      *
+      * This function resolves the address and attempts to establish a TCP connection
+      * with the server at the specified IP and port, honouring the connect timeout and
+      * `TCP_NODELAY` setting. If successful, the connection is saved in the `stream` field
+      * and the Hello handshake negotiates the protocol version. A greeting sent by the
+      * server ahead of the handshake is kept for `greeting`.
+      *
+      * \return A result indicating success or failure of the connection attempt; an
+      *         `InvalidInput` error if a configured timeout is zero.
+      */
+     pub fn connect(&mut self) -> io::Result<()> {
+         check_timeout("Read timeout", self.timeout)?;
+         if let Some(timeout) = self.connect_timeout {
+             check_timeout("Connect timeout", timeout)?;
+         }
+         if self.verbose {
+             debug!("Connecting to {}:{}", self.ip, self.port);
+         }
+
+         // Resolve the address; a (host, port) pair takes IPv6 literals such as `::1` unbracketed
+         let host = self.ip.trim_start_matches('[').trim_end_matches(']');
+         let port = u16::try_from(self.port)
+             .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid IP or port"))?;
+         let socket_addrs = resolve(host, port)?;
+
+         if socket_addrs.is_empty() {
+             return Err(io::Error::new(
+                 io::ErrorKind::InvalidInput,
+                 "Invalid IP or port",
+             ));
+         }
+         let mut stream = S::open(&socket_addrs, self.connect_timeout, self.nodelay)?;
+         stream.set_write_timeout(self.write_timeout)?;
+         if let Some(magic) = self.magic {
+             stream.write_all(&magic)?;
+             self.session_stats.bytes_written += magic.len() as u64;
+         }
+         self.stream = Some(stream);
+         self.decoder = FrameDecoder::new();
+         self.disconnected = false;
+         self.handshake()?;
+
+         if self.verbose {
+             debug!("Connected to the server!");
+         }
+         Ok(())
+     }
+
+     /// Negotiates the protocol version over the current stream, keeping any greeting sent first.
+     fn handshake(&mut self) -> io::Result<()> {
+         self.protocol_version = None;
+         self.greeting = None;
+         if !self.control {
+             self.session_id = None;
+         }
+
+         self.send_once(&client_message::Message::HelloRequest(HelloRequest {
+             protocol_version: PROTOCOL_VERSION,
+             tenant: self.tenant.clone(),
+             session_id: self.session_id.clone().unwrap_or_default(),
+             control: self.control,
+         }))?;
+         let mut response = self.receive_once(1)?;
+         if let Some(server_message::Message::Greeting(greeting)) = response.message {
+             self.greeting = Some(greeting);
+             response = self.receive_once(1)?;
+         }
+         match response.message {
+             Some(server_message::Message::HelloResponse(hello)) => {
+                 self.protocol_version = Some(hello.protocol_version);
+                 self.session_id = Some(hello.session_id).filter(|id| !id.is_empty());
+             }
+             other => {
+                 return Err(io::Error::new(
+                     io::ErrorKind::InvalidData,
+                     format!("Expected HelloResponse, but received {:?}", other),
+                 ));
+             }
+         }
+         Ok(())
+     }
+
+     /*
+      * \brief Returns the protocol version agreed with the server during `connect`.
+      *
+      * \return The negotiated version, or `None` while not connected.
+      */
+     pub fn protocol_version(&self) -> Option<u32> {
+         self.protocol_version
+     }
+
+     /*
+      * \brief Returns the session the server opened for this connection during `connect`.
+      *
+      * \return The session id, or `None` if the server does not offer control channels.
+      */
+     pub fn session_id(&self) -> Option<&str> {
+         self.session_id.as_deref()
+     }
+
+     /*
+      * \brief Opens the control channel of this connection's session.
+      *
+      * The control connection uses the same server address and settings, but its
+      * handshake attaches it to the session instead of opening a new one. The server
+      * pushes notifications such as broadcasts on it, apart from the responses to the
+      * requests sent here, and closes it once this connection ends.
+      *
+      * \return The connected control client, or an error if there is no session or the
+      *         server refuses to attach to it.
+      */
+     pub fn connect_control(&self) -> io::Result<Client<S>> {
+         let Some(ref session_id) = self.session_id else {
+             return Err(io::Error::new(
+                 io::ErrorKind::NotConnected,
+                 "No session to open a control channel for",
+             ));
+         };
+         let mut builder = ClientBuilder::new()
+             .host(&self.ip)
+             .port(self.port)
+             .read_timeout(self.timeout)
+             .nodelay(self.nodelay)
+             .raw_framing(self.raw_framing);
+         if let Some(timeout) = self.connect_timeout {
+             builder = builder.connect_timeout(timeout);
+         }
+         if let Some(magic) = self.magic {
+             builder = builder.magic(magic);
+         }
+         let mut control = builder.build_for();
+         control.session_id = Some(session_id.clone());
+         control.control = true;
+         control.write_timeout = self.write_timeout;
+         control.connect()?;
+         Ok(control)
+     }
+
+     /*
+      * \brief Returns the greeting the server sent on `connect`, if it sent one.
+      */
+     pub fn greeting(&self) -> Option<&Greeting> {
+         self.greeting.as_ref()
+     }
+
+     /*
+      * \brief Checks whether the server is still reachable over the current connection.
+      *
+      * Having a socket is not enough: the peer may have closed it. This function peeks at
+      * the socket without blocking; end-of-file or an error means the connection is gone,
+      * while pending data or an empty receive queue means it is still alive.
+      *
+      * \return `true` if the connection is open on both ends.
+      */
+     pub fn is_connected(&self) -> bool {
+         let Some(ref stream) = self.stream else {
+             return false;
+         };
+         if stream.set_nonblocking(true).is_err() {
+             return false;
+         }
+
+         let mut probe = [0u8; 1];
+         let alive = match stream.peek(&mut probe) {
+             Ok(0) => false,
+             Ok(_) => true,
+             Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+         };
+
+         stream.set_nonblocking(false).is_ok() && alive
+     }
+
+     /*
+      * \brief Waits until the server answers a ping, connecting first if needed.
+      *
+      * Unlike polling the port, this confirms the server speaks the protocol: a listening
+      * socket whose handler refuses requests, answers with an error or fails the handshake
+      * does not count as ready. Attempts repeat every 50 ms; a single attempt can run past
+      * the deadline by up to the read timeout. The connection is left open on success.
+      *
+      * \param timeout How long to keep trying.
+      * \return `Ok` once a `PongResponse` arrives, or a `TimedOut` error naming the last failure.
+      */
+     pub fn ping_until_ready(&mut self, timeout: Duration) -> io::Result<()> {
+         let deadline = Instant::now() + timeout;
+         loop {
+             let connected = self.protocol_version.is_some() && self.is_connected();
+             let attempt = if connected { Ok(()) } else { self.connect() }.and_then(|()| {
+                 self.request_expect::<PongResponse>(client_message::Message::PingRequest(
+                     PingRequest {},
+                 ))
+             });
+             let e = match attempt {
+                 Ok(_) => return Ok(()),
+                 Err(e) => e,
+             };
+             if Instant::now() + READY_PROBE_INTERVAL >= deadline {
+                 return Err(io::Error::new(
+                     io::ErrorKind::TimedOut,
+                     format!("Server not ready after {:?}: {}", timeout, e),
+                 ));
+             }
+             debug!("Server not ready yet: {}", e);
+             thread::sleep(READY_PROBE_INTERVAL);
+         }
+     }
+
+     /*
+      * \brief Turns the per-message debug logs of this client on or off.
+      *
+      * Off by default, so tests exchanging many messages keep their output readable.
+      * Errors are logged either way.
+      *
+      * \param verbose Whether to log every connect, send and receive.
+      */
+     pub fn set_verbose(&mut self, verbose: bool) {
+         self.verbose = verbose;
+     }
+
+     /*
+      * \brief Changes how long each read waits for a response.
+      *
+      * \param timeout The new read timeout, applied from the next receive on.
+      */
+     pub fn set_read_timeout(&mut self, timeout: Duration) {
+         self.timeout = timeout;
+     }
+
+     /*
+      * \brief Bounds how long a write may wait for a server that stopped reading.
+      *
+      * The timeout applies to the current connection and to every later `connect`. A
+      * send that stalls longer fails with `TimedOut`; part of its frame may have been
+      * written, so the connection should be dropped afterwards.
+      *
+      * \param timeout The longest wait, or `None` to wait indefinitely, the default.
+      * \return A result indicating success, or an `InvalidInput` error for a zero timeout.
+      */
+     pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+         if let Some(ref stream) = self.stream {
+             stream.set_write_timeout(timeout)?;
+         }
+         self.write_timeout = timeout;
+         Ok(())
+     }
+
+     /*
+      * \brief Enables or disables automatic reconnection.
+      *
+      * With a policy set, `send` reconnects before writing when the server has closed
+      * the connection, and again if the write fails because the connection was lost.
+      * When the connection is lost while `receive_with_retry` waits for a response, the
+      * client reconnects, but it only resends the request if it was sent with
+      * `send_idempotent`; otherwise the lost connection is reported as an error.
+      *
+      * \param policy How to reconnect, or `None` to report lost connections as errors.
+      */
+     pub fn set_auto_reconnect(&mut self, policy: Option<ReconnectPolicy>) {
+         self.reconnect = policy;
+     }
+
+     /*
+      * \brief Connects again following `policy`, backing off between failed attempts.
+      *
+      * \param policy The attempt limit and backoff to apply.
+      * \return The error of the last attempt if none succeeded.
+      */
+     fn reconnect(&mut self, policy: ReconnectPolicy) -> io::Result<()> {
+         let mut delay = policy.backoff;
+         let mut attempt = 1;
+         loop {
+             match self.connect() {
+                 Ok(()) => return Ok(()),
+                 Err(e) if attempt >= policy.max_attempts => return Err(e),
+                 Err(e) => {
+                     warn!(
+                         "Reconnect attempt {} failed ({}), retrying in {:?}",
+                         attempt, e, delay
+                     );
+                     thread::sleep(delay);
+                     delay *= 2;
+                     attempt += 1;
+                 }
+             }
+         }
+     }
+
+     /*
+      * \brief Disconnects the client from the server.
+      *
+      * This function shuts down the TCP connection, ensuring that both send and receive
+      * channels are closed properly. The client is left disconnected even if the shutdown
+      * fails: every later `send` or `receive` returns `NotConnected`, with auto-reconnect
+      * enabled too, until `connect` is called again.
+      *
+      * \return A result indicating success or failure of the disconnection process.
+      */
+     pub fn disconnect(&mut self) -> io::Result<()> {
+         self.send_buffer.clear();
+         self.idempotent_request = None;
+         self.protocol_version = None;
+         self.greeting = None;
+         if !self.control {
+             self.session_id = None;
+         }
+         self.disconnected = true;
+         self.session_stats = SessionStats::default();
+         if let Some(stream) = self.stream.take() {
+             match stream.shutdown(Shutdown::Both) {
+                 // The server already closed the connection
+                 Err(e) if e.kind() == io::ErrorKind::NotConnected => {}
+                 result => result?,
+             }
+         }
+
+         if self.verbose {
+             debug!("Disconnected from the server!");
+         }
+         Ok(())
+     }
+
+     /*
+      * \brief Disconnects like `disconnect`, reporting the bytes exchanged beforehand.
+      *
+      * The counts cover everything sent and received since the client was last
+      * disconnected, across reconnections, including framing and the handshake.
+      *
+      * \return The bytes read and written, or an error if the disconnection failed.
+      */
+     pub fn disconnect_with_stats(&mut self) -> io::Result<SessionStats> {
+         let stats = self.session_stats;
+         self.disconnect()?;
+         Ok(stats)
+     }
+
+     /*
+      * \brief Disconnects after collecting the responses still on their way.
+      *
+      * Unlike `disconnect`, this first flushes buffered messages and shuts down only the
+      * sending side, so the server sees end-of-file but can still answer what it has
+      * received. Responses are read until the server closes the connection or `timeout`
+      * elapses, then the connection is closed for good.
+      *
+      * \param timeout How long to wait for the server to finish answering.
+      * \return The responses received after the write side was closed, in order.
+      */
+     pub fn disconnect_graceful(&mut self, timeout: Duration) -> io::Result<Vec<ServerMessage>> {
+         self.flush()?;
+         let Some(mut stream) = self.stream.take() else {
+             return Err(io::Error::new(
+                 io::ErrorKind::NotConnected,
+                 "No active connection",
+             ));
+         };
+         let mut decoder = std::mem::take(&mut self.decoder);
+         self.disconnect()?;
+         stream.shutdown(Shutdown::Write)?;
+
+         let deadline = Instant::now() + timeout;
+         let mut responses = Vec::new();
+         loop {
+             let remaining = deadline.saturating_duration_since(Instant::now());
+             if remaining.is_zero() {
+                 warn!("Server still sending after {:?}, closing anyway", timeout);
+                 break;
+             }
+             stream.set_read_timeout(Some(remaining))?;
+             match read_message(&mut decoder, &mut stream) {
+                 Ok(message) => responses.push(message),
+                 Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                 Err(e)
+                     if matches!(
+                         e.kind(),
+                         io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                     ) =>
+                 {
+                     continue
+                 }
+                 Err(e) => return Err(e),
+             }
+         }
+         // The server may already have closed its side
+         let _ = stream.shutdown(Shutdown::Both);
+         Ok(responses)
+     }
+
+     /*
+      * \brief Sends a message to the server.
+      *
+      * This function wraps the provided `client_message::Message` in a `ClientMessage`,
+      * encodes it and sends it to the server as a single frame via the established TCP connection.
+      * With auto-reconnect enabled, a lost connection is re-established following the
+      * reconnect policy and the send retried, unless the client was disconnected on purpose.
+      *
+      * \param message The message to send to the server.
+      * \return A result indicating success or failure of the sending process.
+      */
+     pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
+         self.idempotent_request = None;
+         let Some(policy) = self.reconnect.filter(|_| !self.disconnected) else {
+             return self.send_once(&message);
+         };
+         if !self.is_connected() {
+             info!("Connection closed by the server, reconnecting");
+             self.reconnect(policy)?;
+         }
+         match self.send_once(&message) {
+             Err(e) if is_connection_lost(&e) => {
+                 info!("Connection lost ({}), reconnecting", e);
+                 self.reconnect(policy)?;
+                 self.send_once(&message)
+             }
+             result => result,
+         }
+     }
+
+     /*
+      * \brief Sends a request that is safe to repeat, so it can be resent after a reconnect.
+      *
+      * Like `send`, but with auto-reconnect enabled, a connection lost while
+      * `receive_with_retry` waits for the response is re-established and this request
+      * sent once more. Delivery is at-least-once: the server may have handled the request
+      * before the connection broke, so it sees it twice. Only this single request is
+      * covered; requests pipelined before it are not resent, and any later `send` cancels
+      * the replay.
+      *
+      * \param message The message to send to the server.
+      * \return A result indicating success or failure of the sending process.
+      */
+     pub fn send_idempotent(&mut self, message: client_message::Message) -> io::Result<()> {
+         self.send(message.clone())?;
+         self.idempotent_request = Some(message);
+         Ok(())
+     }
+
+     /*
+      * \brief Queues a message to be sent by the next `flush`.
       *
-      * This function initializes the client with the specified IP address, port, and timeout duration.
+      * Nothing is written to the socket, so a batch of requests costs one write instead
+      * of one per message. A later `send` flushes the queue first to keep requests in order.
       *
-      * \param ip The IP address of the server.
-      * \param port The port number of the server.
-      * \param timeout_ms The timeout duration in milliseconds for socket operations.
-      * \return A new `Client` instance.
+      * \param message The message to queue.
       */
-     pub fn new(ip: &str, port: u32, timeout_ms: u64) -> Self {
-         Client {
-             ip: ip.to_string(),
-             port,
-             timeout: Duration::from_millis(timeout_ms),
-             stream: None,
+     pub fn send_buffered(&mut self, message: client_message::Message) {
+         let buffer = ClientMessage {
+             message: Some(message),
+             ..Default::default()
          }
+         .encode_to_vec();
+         self.send_buffer.extend(frame::encode_frame(&buffer));
      }
- 
+
      /*
-      * \brief Connects the client to the server.
+      * \brief Writes every message queued by `send_buffered` in a single write.
       *
-      * This function resolves the address and attempts to establish a TCP connection
-      * with the server at the specified IP and port. If successful, the connection is
-      * saved in the `stream` field.
+      * \return A result indicating success or failure of the write.
+      */
+     pub fn flush(&mut self) -> io::Result<()> {
+         if self.send_buffer.is_empty() {
+             return Ok(());
+         }
+         let Some(ref mut stream) = self.stream else {
+             return Err(io::Error::new(
+                 io::ErrorKind::NotConnected,
+                 "No active connection",
+             ));
+         };
+         stream.write_all(&self.send_buffer).map_err(write_stalled)?;
+         stream.flush()?;
+         self.session_stats.bytes_written += self.send_buffer.len() as u64;
+         self.send_buffer.clear();
+         Ok(())
+     }
+
+     /// Sends `message` over the current connection without any reconnection attempt.
+     fn send_once(&mut self, message: &client_message::Message) -> io::Result<()> {
+         self.flush()?;
+         if let Some(ref mut stream) = self.stream {
+             // Encode the message to a buffer
+             let buffer = ClientMessage {
+                 message: Some(message.clone()),
+                 ..Default::default()
+             }
+             .encode_to_vec();
+
+             // Send the buffer to the server
+             frame::write_frame(stream, &buffer).map_err(write_stalled)?;
+             self.session_stats.bytes_written += (frame::HEADER_LEN + buffer.len()) as u64;
+
+             if self.verbose {
+                 debug!("Sent message: {:?}", message);
+             }
+             Ok(())
+         } else {
+             Err(io::Error::new(
+                 io::ErrorKind::NotConnected,
+                 "No active connection",
+             ))
+         }
+     }
+
+     /*
+      * \brief Sends arbitrary bytes to the server, bypassing message encoding.
+      *
+      * With raw framing enabled the bytes are sent as the payload of a single frame;
+      * otherwise they are written to the socket exactly as given, header included.
       *
-      * \return A result indicating success or failure of the connection attempt.
+      * \param bytes The bytes to send.
+      * \return A result indicating success or failure of the write.
       */
-     pub fn connect(&mut self) -> io::Result<()> {
-         println!("Connecting to {}:{}", self.ip, self.port);
- 
-         // Resolve the address
-         let address = format!("{}:{}", self.ip, self.port);
-         let socket_addrs: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
- 
-         if socket_addrs.is_empty() {
+     pub fn send_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+         let Some(ref mut stream) = self.stream else {
              return Err(io::Error::new(
-                 io::ErrorKind::InvalidInput,
-                 "Invalid IP or port",
+                 io::ErrorKind::NotConnected,
+                 "No active connection",
              ));
+         };
+         if self.raw_framing {
+             frame::write_frame(stream, bytes).map_err(write_stalled)?;
+             self.session_stats.bytes_written += frame::HEADER_LEN as u64;
+         } else {
+             stream.write_all(bytes).map_err(write_stalled)?;
+             stream.flush()?;
+         }
+         self.session_stats.bytes_written += bytes.len() as u64;
+         if self.verbose {
+             debug!("Sent {} raw bytes", bytes.len());
          }
-         let stream = TcpStream::connect(format!("localhost:{}", self.port));
-         self.stream = Some(stream?);
-         println!("Connected to the server!");
          Ok(())
      }
- 
+
      /*
-      * \brief Disconnects the client from the server.
+      * \brief Receives bytes from the server without decoding them.
       *
-      * This function shuts down the TCP connection, ensuring that both send and receive
-      * channels are closed properly.
+      * With raw framing enabled this waits for one complete frame and returns it,
+      * length prefix included; otherwise it returns whatever a single read yields.
       *
-      * \return A result indicating success or failure of the disconnection process.
+      * \return The received bytes; an empty vector means the server closed the connection.
       */
-     pub fn disconnect(&mut self) -> io::Result<()> {
-         if let Some(stream) = self.stream.take() {
-             stream.shutdown(std::net::Shutdown::Both)?;
+     pub fn receive_raw(&mut self) -> io::Result<Vec<u8>> {
+         let Some(ref mut stream) = self.stream else {
+             return Err(io::Error::new(
+                 io::ErrorKind::NotConnected,
+                 "No active connection",
+             ));
+         };
+         stream.set_read_timeout(Some(self.timeout))?;
+         if self.raw_framing {
+             return match self.decoder.read_frame(stream) {
+                 Ok(payload) => {
+                     self.session_stats.bytes_read += (frame::HEADER_LEN + payload.len()) as u64;
+                     Ok(frame::encode_frame(&payload))
+                 }
+                 Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(Vec::new()),
+                 Err(e) => Err(e),
+             };
          }
- 
-         println!("Disconnected from the server!");
-         Ok(())
+
+         // Bytes already buffered by an earlier receive come first
+         if let Some(buffered) = self
+             .decoder
+             .take_raw(self.decoder.buffered_len())
+             .filter(|bytes| !bytes.is_empty())
+         {
+             return Ok(buffered.to_vec());
+         }
+         let mut buffer = vec![0u8; 4096];
+         let bytes = loop {
+             match stream.read(&mut buffer) {
+                 // A signal arrived before any data
+                 Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                 result => break result?,
+             }
+         };
+         self.session_stats.bytes_read += bytes as u64;
+         buffer.truncate(bytes);
+         Ok(buffer)
      }
- 
+
      /*
-      * \brief Sends a message to the server.
+      * \brief Sends an echo request and returns the content the server echoed back.
       *
-      * This function encodes the provided `client_message::Message` into a byte buffer and
-      * sends it to the server via the established TCP connection.
+      * Echoing is safe to repeat, so the request is sent with `send_idempotent`.
       *
-      * \param message The message to send to the server.
-      * \return A result indicating success or failure of the sending process.
+      * \param content The text to echo.
+      * \return The echoed text; an error if the server answered with an error or anything
+      *         other than an echo.
       */
-     pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
-         if let Some(ref mut stream) = self.stream {
-             // Encode the message to a buffer
-             let mut buffer = Vec::new();
-             message.encode(&mut buffer);
- 
-             // Send the buffer to the server
-             stream.write_all(&buffer)?;
-             stream.flush()?;
- 
-             println!("Sent message: {:?}", message);
-             Ok(())
+     pub fn echo(&mut self, content: &str) -> io::Result<String> {
+         let request = client_message::Message::EchoMessage(EchoMessage {
+             content: content.to_string(),
+         });
+         Ok(self.expect_response::<EchoMessage>(request, true)?.content)
+     }
+
+     /*
+      * \brief Echoes binary data and checks that it came back unaltered.
+      *
+      * The response is compared byte for byte, so a framing bug or corruption anywhere
+      * between the client and the handler is reported instead of passing unnoticed. The
+      * CRC-32 of both sides is included in the error to tell corruption patterns apart.
+      * Like `echo`, the request is sent with `send_idempotent`.
+      *
+      * \param data The payload to echo.
+      * \return The CRC-32 of the verified payload; an `InvalidData` error naming the first
+      *         differing byte if the echo does not match.
+      */
+     pub fn echo_verified(&mut self, data: &[u8]) -> io::Result<u32> {
+         let request = client_message::Message::BinaryEcho(BinaryEcho {
+             data: data.to_vec().into(),
+         });
+         let echoed = self.expect_response::<BinaryEcho>(request, true)?.data;
+         if echoed != data {
+             let offset = data
+                 .iter()
+                 .zip(&echoed)
+                 .position(|(sent, received)| sent != received);
+             return Err(io::Error::new(
+                 io::ErrorKind::InvalidData,
+                 format!(
+                     "Echo mismatch: sent {} bytes (CRC-32 {:08x}), received {} bytes \
+                      (CRC-32 {:08x}), first difference at byte {}",
+                     data.len(),
+                     crc32(data),
+                     echoed.len(),
+                     crc32(&echoed),
+                     offset.unwrap_or(data.len().min(echoed.len())),
+                 ),
+             ));
+         }
+         Ok(crc32(data))
+     }
+
+     /*
+      * \brief Asks the server to add two numbers.
+      *
+      * Adding is safe to repeat, so the request is sent with `send_idempotent`.
+      *
+      * \param a The first operand.
+      * \param b The second operand.
+      * \return The sum, which wraps around on overflow; an error if the server answered
+      *         with an error or anything other than an addition result.
+      */
+     pub fn add(&mut self, a: i64, b: i64) -> io::Result<i64> {
+         Ok(self
+             .expect_response::<AddResponse>(
+                 client_message::Message::AddRequest(AddRequest { a, b }),
+                 true,
+             )?
+             .result64)
+     }
+
+     /*
+      * \brief Sends a request and returns its response, provided it is of variant `T`.
+      *
+      * This replaces matching on the response and panicking on anything unexpected:
+      * `client.request_expect::<PongResponse>(ping)?` either yields the pong or an error.
+      *
+      * \param message The request to send.
+      * \return The response payload; an `InvalidData` error wrapping `UnexpectedResponse`
+      *         if the server answered with another variant, or a plain `InvalidData` error
+      *         if it answered with an error.
+      */
+     pub fn request_expect<T: Response>(
+         &mut self,
+         message: client_message::Message,
+     ) -> io::Result<T> {
+         self.expect_response(message, false)
+     }
+
+     /// Like `request_expect`; an `idempotent` request is sent with `send_idempotent`.
+     fn expect_response<T: Response>(
+         &mut self,
+         message: client_message::Message,
+         idempotent: bool,
+     ) -> io::Result<T> {
+         T::from_message(self.call(message, idempotent)?).map_err(|other| {
+             io::Error::new(
+                 io::ErrorKind::InvalidData,
+                 UnexpectedResponse {
+                     expected: T::VARIANT,
+                     received: other,
+                 },
+             )
+         })
+     }
+
+     /*
+      * \brief Sends a typed request and returns its typed response.
+      *
+      * Like `request_expect`, but the request is built by `Req` as well, so a new
+      * message type only needs `Request` and `Response` implementations to get a call
+      * site of its own: `client.request_typed::<_, AddResponse>(AddRequest { a, b })?`.
+      *
+      * \param request The request to send.
+      * \return The response payload, or the errors of `request_expect`.
+      */
+     pub fn request_typed<Req: Request, Resp: Response>(
+         &mut self,
+         request: Req,
+     ) -> io::Result<Resp> {
+         self.request_expect(request.into_message())
+     }
+
+     /*
+      * \brief Sends a request answered by a stream of responses and iterates over them.
+      *
+      * Responses are read lazily, one per call to `next`, until the server's `StreamEnd`
+      * message, which is not yielded. An `ErrorResponse` in place of the first response
+      * ends the stream after being yielded, since a rejected request is answered by that
+      * error alone; later in the stream it is an ordinary item. Any error ends the stream.
+      *
+      * \param message The request to send.
+      * \return The responses, in the order the server sent them.
+      */
+     pub fn request_stream(
+         &mut self,
+         message: client_message::Message,
+     ) -> impl Iterator<Item = io::Result<ServerMessage>> + '_ {
+         let mut failure = self.send(message).err();
+         let mut finished = false;
+         let mut first = true;
+         std::iter::from_fn(move || {
+             if finished {
+                 return None;
+             }
+             if let Some(e) = failure.take() {
+                 finished = true;
+                 return Some(Err(e));
+             }
+             match self.receive_with_retry(1) {
+                 Ok(ServerMessage {
+                     message: Some(server_message::Message::StreamEnd(_)),
+                     ..
+                 }) => {
+                     finished = true;
+                     None
+                 }
+                 Ok(response) => {
+                     finished = first && response.is_error();
+                     first = false;
+                     Some(Ok(response))
+                 }
+                 Err(e) => {
+                     finished = true;
+                     Some(Err(e))
+                 }
+             }
+         })
+     }
+
+     /*
+      * \brief Sends a request and waits for its response.
+      *
+      * \param message The request to send.
+      * \param idempotent Whether the request may be resent after a reconnect.
+      * \return The response; an `ErrorResponse` from the server or a response without a
+      *         message is reported as an `InvalidData` error.
+      */
+     fn call(
+         &mut self,
+         message: client_message::Message,
+         idempotent: bool,
+     ) -> io::Result<server_message::Message> {
+         if idempotent {
+             self.send_idempotent(message)?;
          } else {
-             Err(io::Error::new(
+             self.send(message)?;
+         }
+         let response = self.receive_with_retry(1)?.into_result().map_err(|error| {
+             io::Error::new(
+                 io::ErrorKind::InvalidData,
+                 format!(
+                     "Server returned an error ({:?}): {}",
+                     error.code(),
+                     error.message
+                 ),
+             )
+         })?;
+         response.message.ok_or_else(|| {
+             io::Error::new(
+                 io::ErrorKind::InvalidData,
+                 "Server response carried no message",
+             )
+         })
+     }
+
+     /*
+      * \brief Reads up to `n` responses, giving up once `timeout` has elapsed.
+      *
+      * Unlike `receive_with_retry`, running out of time is not an error: the responses
+      * received so far are returned, as they are when the server closes the connection.
+      * Every message is returned as received, including a `Shutdown` notice; only chunked
+      * responses are reassembled.
+      *
+      * \param n The number of responses wanted.
+      * \param timeout How long to wait for all of them.
+      * \return The responses received, in order; possibly fewer than `n`.
+      */
+     pub fn collect_responses(
+         &mut self,
+         n: usize,
+         timeout: Duration,
+     ) -> io::Result<Vec<ServerMessage>> {
+         let Some(ref mut stream) = self.stream else {
+             return Err(io::Error::new(
                  io::ErrorKind::NotConnected,
                  "No active connection",
-             ))
+             ));
+         };
+         let deadline = Instant::now() + timeout;
+         let mut responses = Vec::with_capacity(n);
+
+         while responses.len() < n {
+             let remaining = deadline.saturating_duration_since(Instant::now());
+             if remaining.is_zero() {
+                 break;
+             }
+             stream.set_read_timeout(Some(remaining))?;
+             match read_message(
+                 &mut self.decoder,
+                 &mut CountingReader {
+                     inner: stream,
+                     count: &mut self.session_stats.bytes_read,
+                 },
+             ) {
+                 Ok(message) => responses.push(message),
+                 Err(e)
+                     if matches!(
+                         e.kind(),
+                         io::ErrorKind::WouldBlock
+                             | io::ErrorKind::TimedOut
+                             | io::ErrorKind::UnexpectedEof
+                     ) =>
+                 {
+                     break;
+                 }
+                 Err(e) => return Err(e),
+             }
          }
+         Ok(responses)
      }
- 
+
+     /*
+      * \brief Reads messages until one matches `predicate`, discarding the others.
+      *
+      * Meant for connections where pushed messages such as broadcasts interleave with
+      * responses. Every message is offered to the predicate as received, including a
+      * `Shutdown` notice; only chunked responses are reassembled.
+      *
+      * \param predicate Returns `true` for the message being waited for.
+      * \param timeout How long to wait for a matching message.
+      * \return The first matching message, or a `TimedOut` error if none arrived in time.
+      */
+     pub fn wait_for(
+         &mut self,
+         predicate: impl Fn(&ServerMessage) -> bool,
+         timeout: Duration,
+     ) -> io::Result<ServerMessage> {
+         let Some(ref mut stream) = self.stream else {
+             return Err(io::Error::new(
+                 io::ErrorKind::NotConnected,
+                 "No active connection",
+             ));
+         };
+         let deadline = Instant::now() + timeout;
+         loop {
+             let remaining = deadline.saturating_duration_since(Instant::now());
+             if remaining.is_zero() {
+                 return Err(io::Error::new(
+                     io::ErrorKind::TimedOut,
+                     format!("No matching message within {:?}", timeout),
+                 ));
+             }
+             stream.set_read_timeout(Some(remaining))?;
+             let message = match read_message(
+                 &mut self.decoder,
+                 &mut CountingReader {
+                     inner: stream,
+                     count: &mut self.session_stats.bytes_read,
+                 },
+             ) {
+                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                 result => result?,
+             };
+             if predicate(&message) {
+                 return Ok(message);
+             }
+             if self.verbose {
+                 debug!("Discarded message: {:?}", message);
+             }
+         }
+     }
+
+     /*
+      * \brief Measures how fast the server echoes messages of a given size.
+      *
+      * `message_count` binary echoes of `payload_size` bytes are pipelined with
+      * `send_buffered` and their responses read with `collect_responses`. Messages go out
+      * in windows of about `THROUGHPUT_WINDOW` bytes, each flushed in one write and fully
+      * answered before the next: a single unbounded burst would deadlock once the socket
+      * buffers in both directions are full.
+      *
+      * \param message_count The number of messages to send.
+      * \param payload_size The payload size of each message, in bytes.
+      * \return The measured rates, or an error if a response is missing or is not an echo.
+      */
+     pub fn measure_throughput(
+         &mut self,
+         message_count: usize,
+         payload_size: usize,
+     ) -> io::Result<ThroughputStats> {
+         let echo = client_message::Message::BinaryEcho(BinaryEcho {
+             data: vec![0xa5; payload_size].into(),
+         });
+         let window = (THROUGHPUT_WINDOW / payload_size.max(1)).max(1);
+         let started = Instant::now();
+         let mut answered = 0;
+         while answered < message_count {
+             let batch = window.min(message_count - answered);
+             for _ in 0..batch {
+                 self.send_buffered(echo.clone());
+             }
+             self.flush()?;
+             let responses = self.collect_responses(batch, self.timeout)?;
+             if responses.len() < batch {
+                 return Err(io::Error::new(
+                     io::ErrorKind::TimedOut,
+                     format!(
+                         "Only {} of {} echoes were answered",
+                         answered + responses.len(),
+                         message_count
+                     ),
+                 ));
+             }
+             for response in responses {
+                 match response.message {
+                     Some(server_message::Message::BinaryEcho(_)) => answered += 1,
+                     other => {
+                         return Err(io::Error::new(
+                             io::ErrorKind::InvalidData,
+                             format!("Expected BinaryEcho, but received {:?}", other),
+                         ))
+                     }
+                 }
+             }
+         }
+         let elapsed = started.elapsed();
+
+         let seconds = elapsed.as_secs_f64().max(f64::MIN_POSITIVE);
+         let bytes = message_count * payload_size;
+         Ok(ThroughputStats {
+             messages: message_count,
+             bytes,
+             elapsed,
+             messages_per_sec: message_count as f64 / seconds,
+             bytes_per_sec: bytes as f64 / seconds,
+         })
+     }
+
      /*
       * \brief Receives a message from the server with retries.
       *
-      * This function attempts to read a message from the server with the specified number of retries.
+      * This function attempts to read a message from the server with the specified number of
+      * retries.
       * If the read operation fails, the function will retry the specified number of times before
       * returning an error.
       *
       * \param retries The number of retries in case of failure.
       * \return The received `ServerMessage` if successful.
-      * \throws io::Error if no message is received after retries or other errors occur.
+      * \throws io::Error if no message is received after retries or other errors occur. A
+      *         `Shutdown` notice from the server is reported as a `ConnectionAborted` error
+      *         wrapping `ServerShutdown`. A `Redirect` moves the client to the server it
+      *         names and is reported as a `ConnectionAborted` error wrapping `ServerRedirect`.
+      *         With auto-reconnect enabled, a lost or redirected connection is re-established
+      *         and a request sent with `send_idempotent` resent before giving up; after any
+      *         other request the lost connection is reported as is.
       */
      pub fn receive_with_retry(&mut self, retries: u32) -> io::Result<ServerMessage> {
+         match self.receive_once(retries) {
+             Err(e) if is_connection_lost(&e) && !is_shutdown(&e) => {
+                 let policy = self.reconnect.filter(|_| !self.disconnected);
+                 let (Some(policy), Some(request)) = (policy, self.idempotent_request.clone())
+                 else {
+                     return Err(e);
+                 };
+                 info!(
+                     "Connection lost awaiting a response ({}), reconnecting to resend the request",
+                     e
+                 );
+                 self.reconnect(policy)?;
+                 self.send_once(&request)?;
+                 self.receive_once(retries)
+             }
+             result => result,
+         }
+     }
+
+     /// Receives a message over the current connection without any reconnection attempt.
+     fn receive_once(&mut self, retries: u32) -> io::Result<ServerMessage> {
          if let Some(ref mut stream) = self.stream {
-             let timeout = Duration::from_secs(30);
-             stream.set_read_timeout(Some(timeout))?;
- 
-             let mut buffer = vec![0u8; 4096];
- 
+             stream.set_read_timeout(Some(self.timeout))?;
+
              for _ in 0..retries {
-                 match stream.read(&mut buffer) {
-                     Ok(bytes_read) => {
-                         if bytes_read == 0 {
-                             info!("Server disconnected.");
-                             return Err(io::Error::new(
-                                 io::ErrorKind::ConnectionAborted,
-                                 "Server disconnected",
-                             ));
-                         }
- 
-                         info!("Received {} bytes from the server", bytes_read);
-                         return ServerMessage::decode(&buffer[..bytes_read]).map_err(|e| {
-                             io::Error::new(
-                                 io::ErrorKind::InvalidData,
-                                 format!("Failed to decode ServerMessage: {}", e),
-                             )
-                         });
+                 match read_message(
+                     &mut self.decoder,
+                     &mut CountingReader {
+                         inner: stream,
+                         count: &mut self.session_stats.bytes_read,
                      },
+                 ) {
+                     Ok(message) => {
+                         if self.verbose {
+                             debug!("Received {} bytes from the server", message.encoded_len());
+                         }
+                         return follow_redirect(message, &mut self.ip, &mut self.port)
+                             .and_then(reject_shutdown);
+                     }
+                     Err(e) if e.kind() == io::ErrorKind::InvalidData => return Err(e),
+                     Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                         info!("Server disconnected.");
+                         return Err(io::Error::new(
+                             io::ErrorKind::ConnectionAborted,
+                             "Server disconnected",
+                         ));
+                     }
                      Err(e) => {
                          error!("Error reading from server: {}", e);
-                         thread::sleep(Duration::from_secs(2));  // Retry delay
+                         thread::sleep(Duration::from_secs(2)); // Retry delay
                      }
                  }
              }
- 
-             Err(io::Error::new(io::ErrorKind::TimedOut, "Failed to receive message after retries"))
+
+             Err(io::Error::new(
+                 io::ErrorKind::TimedOut,
+                 "Failed to receive message after retries",
+             ))
          } else {
              error!("No active connection");
              Err(io::Error::new(
@@ -176,4 +1617,263 @@
          }
      }
  }
- 
\ No newline at end of file
+ 
+ /// \brief Sending half of a client, created by `Client::split`.
+ pub struct Sender {
+     stream: TcpStream,
+     verbose: bool,
+ }
+
+ impl Sender {
+     /*
+      * \brief Sends a message to the server as a single frame.
+      *
+      * \param message The message to send.
+      * \return A result indicating success or failure of the write.
+      */
+     pub fn send(&mut self, message: client_message::Message) -> io::Result<()> {
+         let buffer = ClientMessage {
+             message: Some(message),
+             ..Default::default()
+         }
+         .encode_to_vec();
+         frame::write_frame(&mut self.stream, &buffer)?;
+         if self.verbose {
+             debug!("Sent {} bytes to the server", buffer.len());
+         }
+         Ok(())
+     }
+ }
+ 
+ /// \brief Receiving half of a client, created by `Client::split`.
+ pub struct Receiver {
+     stream: TcpStream,
+     decoder: FrameDecoder,
+     timeout: Duration,
+     verbose: bool,
+ }
+ 
+ impl Receiver {
+     /*
+      * \brief Waits for the next message from the server.
+      *
+      * \return The message; a `Shutdown` notice is reported as a `ConnectionAborted` error
+      *         wrapping `ServerShutdown`, and a timeout as the read error it caused.
+      */
+     pub fn receive(&mut self) -> io::Result<ServerMessage> {
+         self.stream.set_read_timeout(Some(self.timeout))?;
+         let message = read_message(&mut self.decoder, &mut self.stream)?;
+         if self.verbose {
+             debug!("Received {} bytes from the server", message.encoded_len());
+         }
+         reject_shutdown(message)
+     }
+ }
+
+ /*
+  * \brief Reads the next message from the server, reassembling a chunked response.
+  *
+  * Frames are read through the connection's decoder, which retries reads interrupted by
+  * a signal and keeps a partly received frame when a read times out.
+  *
+  * \param decoder The bytes already received on the connection.
+  * \param stream The connection to read from.
+  * \return The decoded message, or an `InvalidData` error if it cannot be decoded.
+  */
+ fn read_message<R: Read>(decoder: &mut FrameDecoder, stream: &mut R) -> io::Result<ServerMessage> {
+     let mut assembled = Vec::new();
+     loop {
+         let buffer = decoder.read_frame(stream)?;
+         let message = decode_server_message(&buffer)?;
+         match message.message {
+             Some(server_message::Message::Chunk(chunk)) => {
+                 assembled.extend_from_slice(&chunk.data);
+                 if !chunk.more {
+                     return decode_server_message(&assembled);
+                 }
+             }
+             _ if !assembled.is_empty() => {
+                 return Err(io::Error::new(
+                     io::ErrorKind::InvalidData,
+                     "Chunked response was interrupted",
+                 ));
+             }
+             _ => return Ok(message),
+         }
+     }
+ }
+ 
+ /// Reader adding every byte it reads to a counter.
+ struct CountingReader<'a, R> {
+     inner: &'a mut R,
+     count: &'a mut u64,
+ }
+ 
+ impl<R: Read> Read for CountingReader<'_, R> {
+     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+         let bytes = self.inner.read(buf)?;
+         *self.count += bytes as u64;
+         Ok(bytes)
+     }
+ }
+
+ /*
+  * \brief Resolves the server address, covering both loopback families for `localhost`.
+  *
+  * On a dual-stack machine `localhost` may resolve to `127.0.0.1`, `::1` or both, in an
+  * order that differs between systems, while the server listens on only one of them.
+  * Since `localhost` always means the loopback interface, both loopback addresses are
+  * tried for it, after whatever the resolver returned.
+  *
+  * \param host The host name or address, without brackets.
+  * \param port The server port.
+  * \return The addresses to try, in order.
+  */
+ fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+     let mut socket_addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+     if host.eq_ignore_ascii_case("localhost") {
+         for loopback in [IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)] {
+             let addr = SocketAddr::new(loopback, port);
+             if !socket_addrs.contains(&addr) {
+                 socket_addrs.push(addr);
+             }
+         }
+     }
+     Ok(socket_addrs)
+ }
+
+ /*
+  * \brief Connects to the first of `socket_addrs` that accepts the connection.
+  *
+  * \param socket_addrs The addresses to try, in order; must not be empty.
+  * \param timeout How long each attempt may take, or `None` for the system default.
+  * \return The connected stream, or the error of the last attempt.
+  */
+ fn connect_any(socket_addrs: &[SocketAddr], timeout: Option<Duration>) -> io::Result<TcpStream> {
+     let mut last_error = None;
+     for addr in socket_addrs {
+         let attempt = match timeout {
+             Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+             None => TcpStream::connect(addr),
+         };
+         match attempt {
+             Ok(stream) => return Ok(stream),
+             Err(e) => {
+                 debug!("Could not connect to {}: {}", addr, e);
+                 last_error = Some(e);
+             }
+         }
+     }
+     Err(last_error.unwrap_or_else(|| {
+         io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to")
+     }))
+ }
+
+ /// Passes `message` through unless it is a `Shutdown` notice, which becomes a `ServerShutdown`
+ /// error.
+ fn reject_shutdown(message: ServerMessage) -> io::Result<ServerMessage> {
+     match message.message {
+         Some(server_message::Message::Shutdown(shutdown)) => Err(io::Error::new(
+             io::ErrorKind::ConnectionAborted,
+             ServerShutdown {
+                 reason: shutdown.reason,
+             },
+         )),
+         _ => Ok(message),
+     }
+ }
+
+ /*
+  * \brief Passes `message` through unless it is a `Redirect`, which retargets the client.
+  *
+  * \param message The received message.
+  * \param ip The client's server host, replaced by the redirect target.
+  * \param port The client's server port, replaced by the redirect target.
+  * \return The message, or a `ConnectionAborted` error wrapping `ServerRedirect`.
+  */
+ fn follow_redirect(
+     message: ServerMessage,
+     ip: &mut String,
+     port: &mut u32,
+ ) -> io::Result<ServerMessage> {
+     let Some(server_message::Message::Redirect(Redirect { host, port: target })) = message.message
+     else {
+         return Ok(message);
+     };
+     info!("Server redirected the client to {}:{}", host, target);
+     ip.clone_from(&host);
+     *port = target;
+     Err(io::Error::new(
+         io::ErrorKind::ConnectionAborted,
+         ServerRedirect { host, port: target },
+     ))
+ }
+
+ /// Decodes a `ServerMessage`, reporting failures as `InvalidData`.
+ fn decode_server_message(bytes: &[u8]) -> io::Result<ServerMessage> {
+     ServerMessage::decode(bytes).map_err(|e| {
+         io::Error::new(
+             io::ErrorKind::InvalidData,
+             format!("Failed to decode ServerMessage: {}", e),
+         )
+     })
+ }
+
+ /*
+  * \brief Rejects a zero timeout and warns about an implausibly long one.
+  *
+  * \param name The setting being checked, for messages.
+  * \param timeout The configured value.
+  * \return An `InvalidInput` error if `timeout` is zero.
+  */
+ fn check_timeout(name: &str, timeout: Duration) -> io::Result<()> {
+     if timeout.is_zero() {
+         return Err(io::Error::new(
+             io::ErrorKind::InvalidInput,
+             format!("{} must not be zero", name),
+         ));
+     }
+     if timeout > MAX_SENSIBLE_TIMEOUT {
+         warn!("{} of {:?} is unusually long", name, timeout);
+     }
+     Ok(())
+ }
+
+ /// Computes the CRC-32 (IEEE 802.3) checksum of `data`.
+ fn crc32(data: &[u8]) -> u32 {
+     let mut crc = !0u32;
+     for &byte in data {
+         crc ^= u32::from(byte);
+         for _ in 0..8 {
+             crc = (crc >> 1) ^ (0xedb8_8320 & 0u32.wrapping_sub(crc & 1));
+         }
+     }
+     !crc
+ }
+
+ /// Reports a write cut short by the write timeout as `TimedOut`, whatever the platform's error
+ /// kind.
+ fn write_stalled(e: io::Error) -> io::Error {
+     match e.kind() {
+         io::ErrorKind::WouldBlock => {
+             io::Error::new(io::ErrorKind::TimedOut, "Write to the server timed out")
+         }
+         _ => e,
+     }
+ }
+ 
+ /// Returns true for the error `receive_with_retry` reports for a `Shutdown` notice.
+ fn is_shutdown(e: &io::Error) -> bool {
+     e.get_ref().is_some_and(|inner| inner.is::<ServerShutdown>())
+ }
+ 
+ /// Returns true for errors meaning the peer is gone rather than the request being bad.
+ fn is_connection_lost(e: &io::Error) -> bool {
+     matches!(
+         e.kind(),
+         io::ErrorKind::NotConnected
+             | io::ErrorKind::BrokenPipe
+             | io::ErrorKind::ConnectionReset
+             | io::ErrorKind::ConnectionAborted
+     )
+ }
@@ -2,40 +2,59 @@
  * \file client_test.rs
  * \author Mohamed Shaban Waaer
  * \date 2024-12-24
- * 
- * \brief This file contains tests for the client-server communication in the embedded recruitment task.
- * 
- * This file contains a series of tests that simulate client-server interactions using the 
+ *
+ * \brief This file contains tests for the client-server communication in the embedded recruitment
+ * task.
+ *
+ * This file contains a series of tests that simulate client-server interactions using the
  * `client::Client` class and the `Server` class from the `server` module. These tests verify the
- * functionality of the server's ability to handle multiple types of requests and client connections.
- * 
+ * functionality of the server's ability to handle multiple types of requests and client
+ * connections.
+ *
  * The tests include:
  * - Connecting a client to the server.
  * - Sending and receiving simple echo messages.
  * - Handling multiple clients.
  * - Performing addition operations through the server.
- * 
- * The file uses the `prost` library to encode and decode messages, and uses `TcpListener` and `TcpStream`
- * from the standard library to establish TCP connections for communication.
- * 
- * The tests ensure that the server behaves as expected under various scenarios, including handling multiple clients
- * and different types of requests.
+ *
+ * Every other test covers one server or client feature and is named after the behaviour it checks.
+ * Most of them run against a server started by `with_running_server` or `with_configured_server`.
+ *
+ * The file uses the `prost` library to encode and decode messages, and uses `TcpListener` and
+ * `TcpStream` from the standard library to establish TCP connections for communication.
+ *
+ * The tests ensure that the server behaves as expected under various scenarios, including handling
+ * multiple clients and different types of requests.
  */
 
- use std::sync::{Arc, Mutex};
+ use std::sync::{Arc, Barrier, Mutex};
  use std::thread::{self, JoinHandle};
- use std::time::Duration;
- use std::net::{TcpListener, TcpStream};
- use std::io::{Write, Read};
- use embedded_recruitment_task::{message::{client_message, server_message, AddRequest, EchoMessage}, server::Server};
- use prost::Message;  // Add this import to bring the `Message` trait into scope
- 
+ use std::time::{Duration, Instant};
+ use std::io::{Read, Write};
+ use std::net::{SocketAddr, TcpListener, TcpStream};
+ use embedded_recruitment_task::{
+     frame,
+     message::{
+         client_message, server_message, AddRequest, AddResponse, BatchRequest, BatchResponse,
+         BinaryEcho, Broadcast, CapabilitiesRequest, ClientMessage, DivideRequest, DivideResponse,
+         EchoMessage, ErrorCode, ErrorResponse, HelloRequest, HelloResponse, PingRequest,
+         ServerMessage,
+     },
+     server::{
+         AlreadyRunning, EmptyEchoPolicy, Health, MessageKind, Middleware, Server, ServerMetrics,
+         SlowClientPolicy, UnknownMessagePolicy, MAX_QUEUED_BYTES, MAX_TENANT_LEN,
+         PROTOCOL_VERSION,
+     },
+     transport,
+ };
+ use prost::Message;
+
  mod client;
- 
+ mod log_capture;
+
  /// Sets up a server to run in a separate thread.
- fn setup_server_thread(server: Arc<Mutex<Server>>) -> JoinHandle<()> {
+ fn setup_server_thread(server: Arc<Server>) -> JoinHandle<()> {
      thread::spawn(move || {
-         let mut server = server.lock().unwrap();
          if let Err(e) = server.run() {
              eprintln!("Server encountered an error: {}", e);
          }
@@ -43,15 +62,123 @@
  }
  
  /// Creates a new server, binds it to a random port, and returns the server and port.
- fn create_server() -> Result<(Arc<Mutex<Server>>, u16), std::io::Error> {
-     let listener = TcpListener::bind("localhost:0")?;  // Bind to an ephemeral port
+ fn create_server() -> Result<(Arc<Server>, u16), std::io::Error> {
+     create_server_with(|server| server)
+ }
+
+ /// Like `create_server`, but lets the caller apply `with_*` settings before sharing the server.
+ fn create_server_with(
+     configure: impl FnOnce(Server) -> Server,
+ ) -> Result<(Arc<Server>, u16), std::io::Error> {
+     let listener = TcpListener::bind("localhost:0")?; // Bind to an ephemeral port
      let port = listener.local_addr()?.port();
+     drop(listener); // Release the port so the server can bind it
      println!("Server is running on port {}", port);
- 
-     let server = Arc::new(Mutex::new(Server::new(&format!("localhost:{}", port), 10000)?));
-     Ok((server, port))  // Return both the Arc<Mutex<Server>> and port
+
+     let server = Arc::new(configure(Server::new(
+         &format!("localhost:{}", port),
+         10000,
+     )?));
+     Ok((server, port)) // Return both the Arc<Server> and port
  }
  
+ /// Runs `test` against a default server; see `with_configured_server`.
+ fn with_running_server<T>(test: impl FnOnce(&Arc<Server>, u16) -> T) -> T {
+     with_configured_server(|server| server, test)
+ }
+
+ /*
+  * \brief Runs `test` against a server configured by `configure` and running on its own thread.
+  *
+  * The server is ready to accept connections when `test` starts, and it is stopped and its
+  * thread joined when `test` returns or panics.
+  *
+  * \param configure Applies `with_*` settings to the server.
+  * \param test Receives the server and the port it listens on.
+  * \return Whatever `test` returns.
+  */
+ fn with_configured_server<T>(
+     configure: impl FnOnce(Server) -> Server,
+     test: impl FnOnce(&Arc<Server>, u16) -> T,
+ ) -> T {
+     let (running, port) = start_server(configure);
+     test(&running.server, port)
+ }
+
+ /// Starts a server configured by `configure` on its own thread and waits until it is ready.
+ fn start_server(configure: impl FnOnce(Server) -> Server) -> (RunningServer, u16) {
+     let (server, port) = create_server_with(configure).expect("Failed to create server");
+     let running = RunningServer { handle: Some(setup_server_thread(server.clone())), server };
+     running.server.wait_ready(Duration::from_secs(5)).expect("Server did not become ready");
+     (running, port)
+ }
+
+ /// Stops a server started by `start_server` and joins its thread when dropped.
+ struct RunningServer {
+     server: Arc<Server>,
+     handle: Option<JoinHandle<()>>,
+ }
+
+ impl RunningServer {
+     /// Waits for the server thread; only for tests that stopped the server themselves.
+     fn join(&mut self) {
+         if let Some(handle) = self.handle.take() {
+             assert!(handle.join().is_ok(), "Server thread panicked or failed to join");
+         }
+     }
+ }
+
+ impl Drop for RunningServer {
+     fn drop(&mut self) {
+         self.server.stop();
+         if let Some(handle) = self.handle.take() {
+             let joined = handle.join();
+             if !thread::panicking() {
+                 assert!(joined.is_ok(), "Server thread panicked or failed to join");
+             }
+         }
+     }
+ }
+
+ /// Creates a client with a 5 second timeout and connects it to the server on `port`.
+ fn connect_client(port: u16) -> client::Client {
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+     client
+ }
+
+ /// Connects a client to `server` through an in-process stream; no socket is opened.
+ fn connect_in_process(
+     server: Server,
+ ) -> (
+     client::Client<transport::MemoryStream>,
+     JoinHandle<std::io::Result<()>>,
+ ) {
+     let (client_end, server_end) = transport::memory_pair();
+     let handler = thread::spawn(move || server.handle_stream(server_end));
+     let client = client::Client::from_transport(client_end).expect("Failed to connect in process");
+     (client, handler)
+ }
+
+ /// A `ClientMessage` holding only field 100, which no version of the protocol defines.
+ const UNKNOWN_VARIANT: [u8; 3] = [0xa0, 0x06, 0x01];
+
+ /// Connects a client to a server using `policy`, sends it an unknown message variant and
+ /// hands the client to `test`.
+ fn with_unknown_variant_sent(
+     policy: UnknownMessagePolicy,
+     test: impl FnOnce(&mut client::Client),
+ ) {
+     with_configured_server(
+         |server| server.with_unknown_message_policy(policy),
+         |_, port| {
+             let mut client = connect_client(port);
+             assert!(client.send_raw(&UNKNOWN_VARIANT).is_ok(), "Failed to send raw bytes");
+             test(&mut client);
+         },
+     );
+ }
+
  /// Waits for the server to start by attempting to connect to it multiple times.
  fn wait_for_server_to_start(port: u16) {
      let mut attempts = 0;
@@ -70,51 +197,52 @@
      }
      panic!("Server did not start within the expected time.");
  }
- 
+
  /// Test case for establishing a connection between the client and the server.
  #[test]
  fn test_client_connection() {
      // Start the server
      let (server, port) = create_server().expect("Failed to create server");
-     let handle = setup_server_thread(server.clone());
- 
+     let _handle = setup_server_thread(server.clone());
+
      // Get the dynamically assigned port
      println!("Server is listening on port: {}", port); // Directly print the port number
- 
+
      // Create and connect the client
      let mut client = client::Client::new("localhost", port.into(), 1000);
      match client.connect() {
          Ok(_) => println!("Client successfully connected to the server."),
          Err(e) => panic!("Failed to connect to the server: {}", e),
      }
- 
+
      // Send a simple message to confirm communication
-     let message = client_message::Message::EchoMessage(EchoMessage { content: "Test Echo".to_string() });
+     let message = client_message::Message::EchoMessage(EchoMessage {
+         content: "Test Echo".to_string(),
+     });
      match client.send(message) {
          Ok(_) => println!("Message sent to the server."),
          Err(e) => panic!("Failed to send message to the server: {}", e),
      }
- 
+
      // Receive a response from the server
      let response = client.receive_with_retry(3);
      match response {
          Ok(res) => println!("Received response from server: {:?}", res),
          Err(e) => panic!("Failed to receive response from server: {}", e),
      }
- 
+
      // Disconnect the client
      match client.disconnect() {
          Ok(_) => println!("Client successfully disconnected."),
          Err(e) => panic!("Failed to disconnect from the server: {}", e),
      }
- 
  }
  
  /// Test case for sending and receiving an echo message.
  #[test]
  fn test_client_echo_message() {
      let (server, port) = create_server().expect("Failed to create server");
-     let handle = setup_server_thread(server.clone());
+     let _handle = setup_server_thread(server.clone());
  
      // Wait for the server to be ready
      wait_for_server_to_start(port);
@@ -127,8 +255,7 @@
      }
  
      // Prepare the message
-     let mut echo_message = EchoMessage::default();
-     echo_message.content = "Hello, World!".to_string();
+     let echo_message = EchoMessage { content: "Hello, World!".to_string() };
      let message = client_message::Message::EchoMessage(echo_message.clone());
  
      // Send the message to the server
@@ -159,40 +286,42 @@
      }
  
  }
- 
+
  /// Test case for sending and receiving multiple echo messages.
  #[test]
  fn test_multiple_echo_messages() {
      // Set up the server in a separate thread
      let (server, port) = create_server().expect("Failed to create server");
-     let handle = setup_server_thread(server.clone());
- 
+     let _handle = setup_server_thread(server.clone());
+
      // Create and connect the client
      let mut client = client::Client::new("localhost", port.into(), 1000000);
      assert!(client.connect().is_ok(), "Failed to connect to the server");
- 
+
      // Prepare multiple messages
-     let messages = vec![
+     let messages = [
          "Hello, World!".to_string(),
          "How are you?".to_string(),
          "Goodbye!".to_string(),
      ];
- 
+
      for message_content in messages {
-         let message = client_message::Message::EchoMessage(EchoMessage { content: message_content });
+         let message = client_message::Message::EchoMessage(EchoMessage {
+             content: message_content,
+         });
          match client.send(message) {
              Ok(_) => println!("Message sent to the server."),
              Err(e) => panic!("Failed to send message to the server: {}", e),
          }
- 
+
          // Retry receiving the message up to 3 times if there's a failure
          let response = client.receive_with_retry(3);
          match response {
              Ok(res) => println!("Received response from server: {:?}", res),
              Err(e) => panic!("Failed to receive response from server: {}", e),
          }
- 
-         thread::sleep(Duration::from_secs(3));  // Increased sleep duration between messages
+
+         thread::sleep(Duration::from_secs(3)); // Increased sleep duration between messages
      }
  }
  
@@ -201,10 +330,10 @@
  fn test_multiple_clients() {
      // Set up the server in a separate thread
      let (server, port) = create_server().expect("Failed to create server");
-     let handle = setup_server_thread(server.clone());
+     let _handle = setup_server_thread(server.clone());
  
      // Create and connect multiple clients
-     let mut clients = vec![
+     let mut clients = [
          client::Client::new("localhost", port.into(), 1000),
          client::Client::new("localhost", port.into(), 1000),
          client::Client::new("localhost", port.into(), 1000),
@@ -215,7 +344,7 @@
      }
  
      // Prepare multiple messages
-     let messages = vec![
+     let messages = [
          "Hello, World!".to_string(),
          "How are you?".to_string(),
          "Goodbye!".to_string(),
@@ -223,9 +352,8 @@
  
      // Send and receive multiple messages for each client
      for message_content in messages {
-         let mut echo_message = EchoMessage::default();
-         echo_message.content = message_content.clone();
-         let message = client_message::Message::EchoMessage(echo_message.clone());
+         let echo_message = EchoMessage { content: message_content.clone() };
+         let message = client_message::Message::EchoMessage(echo_message);
  
          for client in clients.iter_mut() {
              // Send the message to the server
@@ -265,52 +393,4730 @@
  
  /// Test case for sending an addition request to the server.
  #[test]
- #[ignore = "Will Be Fixed in Next Relese "]
  fn test_client_add_request() {
      // Set up the server in a separate thread
-     let (server, port) = create_server().expect("Failed to create server");
-     let handle = setup_server_thread(server.clone());
+     with_running_server(|_, port| {
+         // Create and connect the client
+         let mut client = client::Client::new("localhost", port.into(), 1000);
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
  
-     // Create and connect the client
-     let mut client = client::Client::new("localhost", port.into(), 1000);
-     assert!(client.connect().is_ok(), "Failed to connect to the server");
+         // Prepare the message
+         let add_request = AddRequest { a: 10, b: 20 };
+         let message = client_message::Message::AddRequest(add_request);
+         // Send the message to the server
+         assert!(client.send(message).is_ok(), "Failed to send message");
  
-     // Prepare the message
-     let mut add_request = AddRequest::default();
-     add_request.a = 10;
-     add_request.b = 20;
-     let message = client_message::Message::AddRequest(add_request.clone());
-     // Send the message to the server
-     assert!(client.send(message).is_ok(), "Failed to send message");
+         // Receive the response
+         let response = client.receive_with_retry(1);
+         assert!(
+             response.is_ok(),
+             "Failed to receive response for AddRequest"
+         );
+         match response.unwrap().message {
+             Some(server_message::Message::AddResponse(add_response)) => {
+                 assert_eq!(
+                     add_response.result64,
+                     add_request.a + add_request.b,
+                     "AddResponse result does not match"
+                 );
+             }
+             _ => panic!("Expected AddResponse, but received a different message"),
+         }
  
-     // Receive the response
-     let response = client.receive_with_retry(1);
-     assert!(
-         response.is_ok(),
-         "Failed to receive response for AddRequest"
+         // Disconnect the client
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for a ping answered promptly while other connections flood a single worker.
+ #[test]
+ fn test_ping_prioritized_over_echo_flood() {
+     const FLOODERS: usize = 8;
+     const ECHO_COUNT: usize = 200;
+
+     with_configured_server(
+         |server| server.with_worker_pool(1),
+         |_, port| {
+             let mut flood = Vec::new();
+             for i in 0..ECHO_COUNT {
+                 let echo = client_message::Message::EchoMessage(EchoMessage {
+                     content: format!("bulk {}", i),
+                 });
+                 flood.extend(frame::encode_frame(
+                     &ClientMessage {
+                         message: Some(echo),
+                         ..Default::default()
+                     }
+                     .encode_to_vec(),
+                 ));
+             }
+             let flooders: Vec<TcpStream> = (0..FLOODERS)
+                 .map(|_| {
+                     let mut stream = TcpStream::connect(("localhost", port))
+                         .expect("Failed to connect to the server");
+                     stream.write_all(&flood).expect("Failed to send the flood");
+                     stream
+                 })
+                 .collect();
+
+             let mut stream =
+                 TcpStream::connect(("localhost", port)).expect("Failed to connect to the server");
+             stream
+                 .set_read_timeout(Some(Duration::from_secs(5)))
+                 .unwrap();
+             let started = Instant::now();
+             let ping = client_message::Message::PingRequest(PingRequest {});
+             stream
+                 .write_all(&frame::encode_frame(
+                     &ClientMessage {
+                         message: Some(ping),
+                         ..Default::default()
+                     }
+                     .encode_to_vec(),
+                 ))
+                 .expect("Failed to send ping");
+             let payload = frame::read_frame(&mut stream).expect("Failed to receive response");
+             match ServerMessage::decode(payload.as_slice())
+                 .expect("Failed to decode response")
+                 .message
+             {
+                 Some(server_message::Message::PongResponse(_)) => {}
+                 other => panic!("Expected PongResponse, but received {:?}", other),
+             }
+             assert!(
+                 started.elapsed() < Duration::from_secs(1),
+                 "Ping was not answered promptly"
+             );
+             drop(flooders);
+         },
      );
-     match response.unwrap().message {
-         Some(server_message::Message::AddResponse(add_response)) => {
-             assert_eq!(
-                 add_response.result,
-                 add_request.a + add_request.b,
-                 "AddResponse result does not match"
+ }
+
+ /// Test case for a ping pipelined behind echoes on one connection being answered in request order.
+ #[test]
+ fn test_pipelined_ping_answered_in_order() {
+     const ECHO_COUNT: usize = 100;
+
+     with_configured_server(
+         |server| server.with_worker_pool(1),
+         |_, port| {
+             let mut burst = Vec::new();
+             for i in 0..ECHO_COUNT {
+                 let echo = client_message::Message::EchoMessage(EchoMessage {
+                     content: format!("bulk {}", i),
+                 });
+                 burst.extend(frame::encode_frame(
+                     &ClientMessage {
+                         message: Some(echo),
+                         ..Default::default()
+                     }
+                     .encode_to_vec(),
+                 ));
+             }
+             let ping = client_message::Message::PingRequest(PingRequest {});
+             burst.extend(frame::encode_frame(
+                 &ClientMessage {
+                     message: Some(ping),
+                     ..Default::default()
+                 }
+                 .encode_to_vec(),
+             ));
+
+             let mut stream =
+                 TcpStream::connect(("localhost", port)).expect("Failed to connect to the server");
+             stream
+                 .set_read_timeout(Some(Duration::from_secs(5)))
+                 .unwrap();
+             stream.write_all(&burst).expect("Failed to send the burst");
+
+             for i in 0..ECHO_COUNT {
+                 let payload = frame::read_frame(&mut stream).expect("Failed to receive response");
+                 match ServerMessage::decode(payload.as_slice())
+                     .expect("Failed to decode response")
+                     .message
+                 {
+                     Some(server_message::Message::EchoMessage(echo)) => {
+                         assert_eq!(echo.content, format!("bulk {}", i))
+                     }
+                     other => panic!("Expected EchoMessage, but received {:?}", other),
+                 }
+             }
+             let payload = frame::read_frame(&mut stream).expect("Failed to receive response");
+             match ServerMessage::decode(payload.as_slice())
+                 .expect("Failed to decode response")
+                 .message
+             {
+                 Some(server_message::Message::PongResponse(_)) => {}
+                 other => panic!("Expected PongResponse, but received {:?}", other),
+             }
+         },
+     );
+ }
+
+ /// Test case for a zero-length keepalive frame followed by a real request.
+ #[test]
+ fn test_zero_length_frame_is_keepalive() {
+     with_running_server(|_, port| {
+         let mut stream = TcpStream::connect(format!("localhost:{}", port))
+             .expect("Failed to connect to the server");
+         stream
+             .set_read_timeout(Some(Duration::from_secs(5)))
+             .unwrap();
+
+         // Send the keepalive and the echo back to back
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "after keepalive".to_string(),
+         });
+         let mut burst = frame::encode_frame(&[]);
+         burst.extend(frame::encode_frame(
+             &ClientMessage {
+                 message: Some(echo),
+                 ..Default::default()
+             }
+             .encode_to_vec(),
+         ));
+         stream.write_all(&burst).expect("Failed to send frames");
+
+         // The first response must be the echo; the keepalive is not answered
+         let payload = frame::read_frame(&mut stream).expect("Failed to receive response");
+         match ServerMessage::decode(payload.as_slice())
+             .expect("Failed to decode response")
+             .message
+         {
+             Some(server_message::Message::EchoMessage(echo)) => {
+                 assert_eq!(echo.content, "after keepalive")
+             }
+             other => panic!("Expected EchoMessage, but received {:?}", other),
+         }
+     });
+ }
+
+ /// Test case for building a client with `ClientBuilder` and exchanging a message.
+ #[test]
+ fn test_client_builder_connect() {
+     with_running_server(|_, port| {
+         let mut client = client::Client::builder()
+             .host("localhost")
+             .port(port.into())
+             .connect_timeout(Duration::from_secs(1))
+             .read_timeout(Duration::from_secs(5))
+             .nodelay(true)
+             .auto_reconnect(false)
+             .build();
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+         let message = client_message::Message::EchoMessage(EchoMessage {
+             content: "built".to_string(),
+         });
+         assert!(client.send(message).is_ok(), "Failed to send message");
+         match client
+             .receive_with_retry(1)
+             .expect("Failed to receive response")
+             .message
+         {
+             Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "built"),
+             other => panic!("Expected EchoMessage, but received {:?}", other),
+         }
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for a graceful shutdown answering every pipelined request.
+ #[test]
+ fn test_stop_graceful_answers_pipelined_requests() {
+     const REQUEST_COUNT: usize = 10;
+
+     with_running_server(|server, port| {
+         let mut client = connect_client(port);
+
+         // Make sure the connection has been accepted before shutting down
+         let warmup = client_message::Message::PingRequest(PingRequest {});
+         assert!(client.send(warmup).is_ok(), "Failed to send ping");
+         assert!(
+             client.receive_with_retry(1).is_ok(),
+             "Failed to receive pong"
+         );
+
+         for i in 0..REQUEST_COUNT {
+             let message = client_message::Message::EchoMessage(EchoMessage {
+                 content: format!("pipelined {}", i),
+             });
+             assert!(client.send(message).is_ok(), "Failed to send message");
+         }
+         server.stop_graceful();
+
+         for i in 0..REQUEST_COUNT {
+             match client
+                 .receive_with_retry(1)
+                 .expect("Response lost during shutdown")
+                 .message
+             {
+                 Some(server_message::Message::EchoMessage(echo)) => {
+                     assert_eq!(echo.content, format!("pipelined {}", i))
+                 }
+                 other => panic!("Expected EchoMessage, but received {:?}", other),
+             }
+         }
+     });
+ }
+
+ /// Test case for handler threads being named after the configured prefix and peer.
+ #[test]
+ fn test_handler_thread_name_in_panic() {
+     // Record the names of this test's panicking handler threads, then defer to the previous hook
+     let panicked_threads = Arc::new(Mutex::new(Vec::new()));
+     let recorded = panicked_threads.clone();
+     let previous_hook: Arc<dyn Fn(&std::panic::PanicHookInfo<'_>) + Send + Sync> =
+         Arc::from(std::panic::take_hook());
+     let chained_hook = previous_hook.clone();
+     std::panic::set_hook(Box::new(move |info| {
+         if let Some(name) = thread::current()
+             .name()
+             .filter(|name| name.starts_with("named-handler-"))
+         {
+             recorded.lock().unwrap().push(name.to_string());
+         }
+         chained_hook(info);
+     }));
+
+     with_configured_server(
+         |server| {
+             server
+                 .with_handler_thread_name("named-handler")
+                 .with_handler(|message| match message {
+                     client_message::Message::EchoMessage(echo) if echo.content == "panic" => {
+                         panic!("handler failure")
+                     }
+                     _ => None,
+                 })
+         },
+         |_, port| {
+             let mut client = connect_client(port);
+             let message = client_message::Message::EchoMessage(EchoMessage {
+                 content: "panic".to_string(),
+             });
+             assert!(client.send(message).is_ok(), "Failed to send message");
+
+             // The handler dies without answering, closing the connection
+             assert!(
+                 client.receive_with_retry(1).is_err(),
+                 "Expected the handler to panic"
+             );
+         },
+     );
+     std::panic::set_hook(Box::new(move |info| previous_hook(info)));
+
+     let names = panicked_threads.lock().unwrap();
+     assert!(!names.is_empty(), "No handler thread named in panics");
+ }
+
+ /// Test case for the server continuing to serve clients after a handler panics.
+ #[test]
+ fn test_handler_panic_keeps_server_serving() {
+     with_configured_server(
+         |server| {
+             server.with_handler(|message| match message {
+                 client_message::Message::EchoMessage(echo) if echo.content == "panic" => {
+                     panic!("handler failure")
+                 }
+                 _ => None,
+             })
+         },
+         |_, port| {
+             let mut healthy = client::Client::new("localhost", port.into(), 5000);
+             let mut faulty = client::Client::new("localhost", port.into(), 5000);
+             assert!(
+                 healthy.connect().is_ok(),
+                 "Failed to connect the healthy client"
+             );
+             assert!(
+                 faulty.connect().is_ok(),
+                 "Failed to connect the faulty client"
+             );
+
+             // The panicking handler closes only its own connection
+             let message = client_message::Message::EchoMessage(EchoMessage {
+                 content: "panic".to_string(),
+             });
+             assert!(faulty.send(message).is_ok(), "Failed to send message");
+             assert!(
+                 faulty.receive_with_retry(1).is_err(),
+                 "Expected the faulty connection to close"
              );
+
+             let message = client_message::Message::EchoMessage(EchoMessage {
+                 content: "still here".to_string(),
+             });
+             assert!(healthy.send(message).is_ok(), "Failed to send message");
+             match healthy
+                 .receive_with_retry(1)
+                 .expect("Healthy client lost its connection")
+                 .message
+             {
+                 Some(server_message::Message::EchoMessage(echo)) => {
+                     assert_eq!(echo.content, "still here")
+                 }
+                 other => panic!("Expected EchoMessage, but received {:?}", other),
+             }
+
+             // New connections are still accepted
+             let mut late = client::Client::new("localhost", port.into(), 5000);
+             assert!(late.connect().is_ok(), "Failed to connect after the panic");
+             assert!(
+                 late.send(client_message::Message::PingRequest(PingRequest {}))
+                     .is_ok(),
+                 "Failed to send ping"
+             );
+             assert!(
+                 late.receive_with_retry(1).is_ok(),
+                 "Failed to receive pong after the panic"
+             );
+         },
+     );
+ }
+
+ /// Test case for a delayed echo triggering a client timeout until the timeout is raised.
+ #[test]
+ fn test_echo_delay_triggers_client_timeout() {
+     with_configured_server(
+         |server| server.with_echo_delay(Duration::from_millis(300)),
+         |_, port| {
+             let mut client = client::Client::new("localhost", port.into(), 100);
+             assert!(client.connect().is_ok(), "Failed to connect to the server");
+             let message = client_message::Message::EchoMessage(EchoMessage {
+                 content: "slow".to_string(),
+             });
+             assert!(client.send(message).is_ok(), "Failed to send message");
+
+             match client.receive_with_retry(1) {
+                 Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+                 Ok(res) => panic!("Expected a timeout, but received {:?}", res),
+             }
+
+             // With a longer timeout the delayed echo arrives
+             client.set_read_timeout(Duration::from_secs(2));
+             match client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive delayed echo")
+                 .message
+             {
+                 Some(server_message::Message::EchoMessage(echo)) => {
+                     assert_eq!(echo.content, "slow")
+                 }
+                 other => panic!("Expected EchoMessage, but received {:?}", other),
+             }
+         },
+     );
+ }
+
+ /// Test case for round-tripping a binary payload with null bytes and invalid UTF-8.
+ #[test]
+ fn test_binary_echo_round_trip() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+
+         let data = vec![0x00, 0xff, 0xfe, 0x00, 0xc3, 0x28, b'o', b'k', 0x00, 0x80];
+         assert!(std::str::from_utf8(&data).is_err(), "Payload should not be valid UTF-8");
+         let message = client_message::Message::BinaryEcho(BinaryEcho {
+             data: data.clone().into(),
+         });
+         assert!(client.send(message).is_ok(), "Failed to send message");
+
+         match client.receive_with_retry(1).expect("Failed to receive response").message {
+             Some(server_message::Message::BinaryEcho(echo)) => assert_eq!(echo.data, data),
+             other => panic!("Expected BinaryEcho, but received {:?}", other),
          }
-         _ => panic!("Expected AddResponse, but received a different message"),
-     }
- 
-     // Disconnect the client
+     });
+ }
+
+ /// Test case for `is_connected` noticing that the server closed the connection.
+ #[test]
+ fn test_is_connected_detects_server_close() {
+     let (mut running, port) = start_server(|server| server);
+
+     let mut client = client::Client::new("localhost", port.into(), 5000);
      assert!(
-         client.disconnect().is_ok(),
-         "Failed to disconnect from the server"
+         !client.is_connected(),
+         "Client reports a connection before connecting"
      );
- 
-     // Stop the server and wait for thread to finish
-     server.lock().unwrap().stop();
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+     // Round trip once so the server has accepted the connection
      assert!(
-         handle.join().is_ok(),
-         "Server thread panicked or failed to join"
+         client
+             .send(client_message::Message::PingRequest(PingRequest {}))
+             .is_ok(),
+         "Failed to send ping"
+     );
+     assert!(
+         client.receive_with_retry(1).is_ok(),
+         "Failed to receive pong"
+     );
+     assert!(client.is_connected(), "Live connection reported as closed");
+
+     running.server.stop_graceful();
+     running.join();
+
+     let deadline = Instant::now() + Duration::from_secs(2);
+     while client.is_connected() && Instant::now() < deadline {
+         thread::sleep(Duration::from_millis(10));
+     }
+     assert!(
+         !client.is_connected(),
+         "Closed connection still reported as connected"
+     );
+ }
+
+ /// Test case for the capabilities request reporting the enabled handlers.
+ #[test]
+ fn test_capabilities_request() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+         let message = client_message::Message::CapabilitiesRequest(CapabilitiesRequest {});
+         assert!(client.send(message).is_ok(), "Failed to send message");
+
+         match client
+             .receive_with_retry(1)
+             .expect("Failed to receive response")
+             .message
+         {
+             Some(server_message::Message::CapabilitiesResponse(capabilities)) => {
+                 assert!(
+                     capabilities.features.iter().any(|f| f == "add"),
+                     "Missing add: {:?}",
+                     capabilities.features
+                 );
+                 assert!(
+                     capabilities.features.iter().any(|f| f == "ping"),
+                     "Missing ping: {:?}",
+                     capabilities.features
+                 );
+                 assert!(
+                     !capabilities.features.iter().any(|f| f == "tls"),
+                     "Unexpected tls: {:?}",
+                     capabilities.features
+                 );
+             }
+             other => panic!("Expected CapabilitiesResponse, but received {:?}", other),
+         }
+     });
+ }
+
+ /// Test case for a client that sends a truncated frame and disconnects.
+ #[test]
+ fn test_truncated_frame_then_disconnect() {
+     log_capture::install();
+     with_configured_server(
+         |server| server.with_handler_thread_name("truncated-frame"),
+         |server, port| {
+             // Promise 100 bytes, deliver 40, then drop the connection
+             let mut stream = TcpStream::connect(format!("localhost:{}", port))
+                 .expect("Failed to connect to the server");
+             let mut partial = 100u32.to_be_bytes().to_vec();
+             partial.extend_from_slice(&[0u8; 40]);
+             stream
+                 .write_all(&partial)
+                 .expect("Failed to send partial frame");
+             drop(stream);
+
+             let deadline = Instant::now() + Duration::from_secs(5);
+             while Instant::now() < deadline
+                 && !log_capture::records_from("truncated-frame")
+                     .iter()
+                     .any(|r| r.message.contains("mid-frame"))
+             {
+                 thread::sleep(Duration::from_millis(10));
+             }
+             while server.active_clients() > 0 && Instant::now() < deadline {
+                 thread::sleep(Duration::from_millis(10));
+             }
+
+             let records = log_capture::records_from("truncated-frame");
+             assert!(
+                 records.iter().any(|r| r.message.contains("mid-frame")),
+                 "Truncated frame not logged: {:?}",
+                 records
+             );
+             assert!(
+                 records.iter().all(|r| r.level > log::Level::Error),
+                 "Unexpected error logs: {:?}",
+                 records
+             );
+             assert_eq!(
+                 server.active_clients(),
+                 0,
+                 "Connection slot was not released"
+             );
+         },
      );
  }
- 
\ No newline at end of file
+
+ /// Test case for the client reporting the protocol version negotiated on connect.
+ #[test]
+ fn test_client_protocol_version_negotiated() {
+     with_running_server(|_, port| {
+         let mut client = client::Client::new("localhost", port.into(), 5000);
+         assert_eq!(client.protocol_version(), None, "Version reported before connecting");
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+         assert_eq!(client.protocol_version(), Some(PROTOCOL_VERSION));
+
+         assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+         assert_eq!(client.protocol_version(), None, "Version kept after disconnecting");
+     });
+ }
+
+ /// Test case for connected clients receiving a shutdown notice while the server drains.
+ #[test]
+ fn test_shutdown_notice_on_stop_graceful() {
+     let (mut running, port) = start_server(|server| server.with_shutdown_notice("maintenance"));
+
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+     running.server.stop_graceful();
+     running.join();
+
+     let error = client.receive_with_retry(1).expect_err("Expected the shutdown notice");
+     assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+     let shutdown = error
+         .get_ref()
+         .and_then(|inner| inner.downcast_ref::<client::ServerShutdown>())
+         .expect("Error does not carry the shutdown notice");
+     assert_eq!(shutdown.reason, "maintenance");
+ }
+
+ /// Test case for a custom handler keeping a per-connection message counter.
+ #[test]
+ fn test_context_handler_counts_per_connection() {
+     let configure = |server: Server| {
+         server.with_context_handler(
+             || 0u32,
+             |count, message, _| match message {
+                 client_message::Message::EchoMessage(echo) => {
+                     *count += 1;
+                     Some(server_message::Message::EchoMessage(EchoMessage {
+                         content: format!("{} #{}", echo.content, count),
+                     }))
+                 }
+                 _ => None,
+             },
+         )
+     };
+     with_configured_server(configure, |_, port| {
+         let mut clients = [connect_client(port), connect_client(port)];
+
+         // Each connection counts its own messages
+         for (index, expected) in [(0, "hi #1"), (0, "hi #2"), (1, "hi #1"), (0, "hi #3")] {
+             let client = &mut clients[index];
+             let message = client_message::Message::EchoMessage(EchoMessage {
+                 content: "hi".to_string(),
+             });
+             assert!(client.send(message).is_ok(), "Failed to send message");
+             match client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response")
+                 .message
+             {
+                 Some(server_message::Message::EchoMessage(echo)) => {
+                     assert_eq!(echo.content, expected)
+                 }
+                 other => panic!("Expected EchoMessage, but received {:?}", other),
+             }
+         }
+     });
+ }
+
+ /// Test case for the server answering garbage bytes with a framed error and staying usable.
+ #[test]
+ fn test_garbage_bytes_get_error_response() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+
+         // 0xff starts a field with an invalid wire type, so this can never decode
+         assert!(
+             client.send_raw(&[0xff; 16]).is_ok(),
+             "Failed to send raw bytes"
+         );
+         let response = client
+             .receive_raw()
+             .expect("Failed to receive raw response");
+         assert!(
+             response.len() >= frame::HEADER_LEN,
+             "Response is not framed"
+         );
+         let len = u32::from_be_bytes(response[..frame::HEADER_LEN].try_into().unwrap()) as usize;
+         assert_eq!(
+             len,
+             response.len() - frame::HEADER_LEN,
+             "Frame length does not match the payload"
+         );
+         match ServerMessage::decode(&response[frame::HEADER_LEN..])
+             .expect("Failed to decode response")
+             .message
+         {
+             Some(server_message::Message::ErrorResponse(error)) => {
+                 assert!(
+                     error.message.contains("decode"),
+                     "Unexpected error: {}",
+                     error.message
+                 )
+             }
+             other => panic!("Expected ErrorResponse, but received {:?}", other),
+         }
+
+         // The connection is still usable afterwards
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "still here".to_string(),
+         });
+         assert!(client.send(echo).is_ok(), "Failed to send message");
+         match client
+             .receive_with_retry(1)
+             .expect("Failed to receive response")
+             .message
+         {
+             Some(server_message::Message::EchoMessage(echo)) => {
+                 assert_eq!(echo.content, "still here")
+             }
+             other => panic!("Expected EchoMessage, but received {:?}", other),
+         }
+
+         // Unframed garbage announces an oversized frame: reported, then the connection is closed
+         let mut raw = client::Client::builder()
+             .port(port.into())
+             .read_timeout(Duration::from_secs(5))
+             .raw_framing(false)
+             .build();
+         assert!(raw.connect().is_ok(), "Failed to connect the raw client");
+         assert!(raw.send_raw(&[0xff; 8]).is_ok(), "Failed to send raw bytes");
+         let response = raw.receive_raw().expect("Failed to receive raw response");
+         assert!(response.len() > frame::HEADER_LEN, "Response is not framed");
+         match ServerMessage::decode(&response[frame::HEADER_LEN..])
+             .expect("Failed to decode response")
+             .message
+         {
+             Some(server_message::Message::ErrorResponse(error)) => {
+                 assert!(
+                     error.message.contains("exceeds"),
+                     "Unexpected error: {}",
+                     error.message
+                 )
+             }
+             other => panic!("Expected ErrorResponse, but received {:?}", other),
+         }
+         assert!(
+             raw.receive_raw()
+                 .expect("Failed to read after the error")
+                 .is_empty(),
+             "Connection was not closed"
+         );
+     });
+ }
+
+ /// Test case for the error answering an undecodable frame keeping its place among pipelined
+ /// responses.
+ #[test]
+ fn test_decode_error_answered_in_order() {
+     with_running_server(|_, port| {
+         let echo = |content: &str| {
+             let message = client_message::Message::EchoMessage(EchoMessage {
+                 content: content.to_string(),
+             });
+             frame::encode_frame(
+                 &ClientMessage {
+                     message: Some(message),
+                     ..Default::default()
+                 }
+                 .encode_to_vec(),
+             )
+         };
+         let mut burst = echo("before");
+         burst.extend(frame::encode_frame(&[0xff; 16]));
+         burst.extend(echo("after"));
+
+         let mut stream =
+             TcpStream::connect(("localhost", port)).expect("Failed to connect to the server");
+         stream
+             .set_read_timeout(Some(Duration::from_secs(5)))
+             .unwrap();
+         stream.write_all(&burst).expect("Failed to send the burst");
+
+         let mut next = || {
+             let payload = frame::read_frame(&mut stream).expect("Failed to receive response");
+             ServerMessage::decode(payload.as_slice())
+                 .expect("Failed to decode response")
+                 .message
+         };
+         match next() {
+             Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "before"),
+             other => panic!("Expected EchoMessage, but received {:?}", other),
+         }
+         match next() {
+             Some(server_message::Message::ErrorResponse(error)) => {
+                 assert_eq!(error.code(), ErrorCode::MalformedMessage)
+             }
+             other => panic!("Expected ErrorResponse, but received {:?}", other),
+         }
+         match next() {
+             Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "after"),
+             other => panic!("Expected EchoMessage, but received {:?}", other),
+         }
+     });
+ }
+
+ /// Test case for the server's byte counters growing by the framed size of a request and its
+ /// response.
+ #[test]
+ fn test_io_stats_count_framed_bytes() {
+     with_running_server(|server, port| {
+         assert_eq!(server.io_stats(), (0, 0));
+
+         // Connecting exchanges the Hello handshake, which is counted as well
+         let framed_len = |payload: Vec<u8>| (payload.len() + frame::HEADER_LEN) as u64;
+         let hello_request = ClientMessage {
+             message: Some(client_message::Message::HelloRequest(HelloRequest {
+                 protocol_version: PROTOCOL_VERSION,
+                 ..Default::default()
+             })),
+             ..Default::default()
+         };
+         let hello_response = ServerMessage {
+             message: Some(server_message::Message::HelloResponse(HelloResponse {
+                 protocol_version: PROTOCOL_VERSION,
+                 ..Default::default()
+             })),
+             ..Default::default()
+         };
+         let mut client = connect_client(port);
+
+         let message = client_message::Message::EchoMessage(EchoMessage {
+             content: "x".repeat(1000),
+         });
+         let request = ClientMessage {
+             message: Some(message.clone()),
+             ..Default::default()
+         };
+         assert!(client.send(message).is_ok(), "Failed to send message");
+         let response = client
+             .receive_with_retry(1)
+             .expect("Failed to receive response");
+
+         let expected_read =
+             framed_len(hello_request.encode_to_vec()) + framed_len(request.encode_to_vec());
+         let expected_written =
+             framed_len(hello_response.encode_to_vec()) + framed_len(response.encode_to_vec());
+
+         // The write is counted right after it completes, so give the handler a moment
+         let deadline = Instant::now() + Duration::from_secs(1);
+         while server.io_stats().1 < expected_written && Instant::now() < deadline {
+             thread::sleep(Duration::from_millis(10));
+         }
+         assert_eq!(server.io_stats(), (expected_read, expected_written));
+     });
+ }
+
+ /// Test case for accepting a connection manually and handling it on a caller-chosen thread.
+ #[test]
+ fn test_accept_one_handles_connection() {
+     let (server, port) = create_server().expect("Failed to create server");
+
+     // Connecting waits for the Hello response, so it must run while the connection is handled
+     let client_thread = thread::spawn(move || {
+         let mut client = client::Client::new("localhost", port.into(), 5000);
+         client.connect().expect("Failed to connect to the server");
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "manual".to_string(),
+         });
+         client.send(echo).expect("Failed to send message");
+         let response = client
+             .receive_with_retry(1)
+             .expect("Failed to receive response");
+         client.disconnect().expect("Failed to disconnect");
+         response
+     });
+
+     let connection = server.accept_one().expect("Failed to accept a connection");
+     assert_eq!(
+         connection.peer_addr(),
+         connection.stream().peer_addr().unwrap()
+     );
+     let handler = thread::spawn(move || connection.handle());
+
+     match client_thread
+         .join()
+         .expect("Client thread panicked")
+         .message
+     {
+         Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "manual"),
+         other => panic!("Expected EchoMessage, but received {:?}", other),
+     }
+     assert!(
+         handler.join().expect("Handler panicked").is_ok(),
+         "Handler reported an error"
+     );
+ }
+
+ /// Test case for the default policy answering an unknown message variant with an error.
+ #[test]
+ fn test_unknown_message_policy_error() {
+     with_unknown_variant_sent(UnknownMessagePolicy::Error, |client| {
+         match client
+             .receive_with_retry(1)
+             .expect("Failed to receive response")
+             .message
+         {
+             Some(server_message::Message::ErrorResponse(error)) => {
+                 assert_eq!(
+                     error.code(),
+                     ErrorCode::UnknownMessage,
+                     "Unexpected error: {}",
+                     error.message
+                 )
+             }
+             other => panic!("Expected ErrorResponse, but received {:?}", other),
+         }
+     });
+ }
+
+ /// Test case for ignoring an unknown message variant and answering the next request.
+ #[test]
+ fn test_unknown_message_policy_ignore() {
+     with_unknown_variant_sent(UnknownMessagePolicy::Ignore, |client| {
+         assert!(
+             client
+                 .send(client_message::Message::PingRequest(PingRequest {}))
+                 .is_ok(),
+             "Failed to send ping"
+         );
+         match client
+             .receive_with_retry(1)
+             .expect("Failed to receive response")
+             .message
+         {
+             Some(server_message::Message::PongResponse(_)) => {}
+             other => panic!("Expected PongResponse, but received {:?}", other),
+         }
+     });
+ }
+
+ /// Test case for closing the connection after an unknown message variant.
+ #[test]
+ fn test_unknown_message_policy_close() {
+     with_unknown_variant_sent(UnknownMessagePolicy::Close, |client| {
+         assert!(
+             client
+                 .receive_raw()
+                 .expect("Failed to read from the server")
+                 .is_empty(),
+             "Connection was not closed"
+         );
+     });
+ }
+
+ /// Test case for the client logging its messages only when verbose logging is enabled.
+ #[test]
+ fn test_client_verbose_logging() {
+     log_capture::install();
+     with_running_server(|_, port| {
+         let test_thread = thread::current()
+             .name()
+             .expect("Test thread has no name")
+             .to_string();
+
+         let mut client = client::Client::new("localhost", port.into(), 5000);
+         let exchange = |client: &mut client::Client| {
+             assert!(client.connect().is_ok(), "Failed to connect to the server");
+             let echo = client_message::Message::EchoMessage(EchoMessage {
+                 content: "quiet".to_string(),
+             });
+             assert!(client.send(echo).is_ok(), "Failed to send message");
+             assert!(
+                 client.receive_with_retry(1).is_ok(),
+                 "Failed to receive response"
+             );
+             assert!(client.disconnect().is_ok(), "Failed to disconnect");
+         };
+
+         exchange(&mut client);
+         let quiet = log_capture::records_from(&test_thread);
+         assert!(
+             quiet.is_empty(),
+             "Client logged while not verbose: {:?}",
+             quiet
+         );
+
+         client.set_verbose(true);
+         exchange(&mut client);
+         let verbose = log_capture::records_from(&test_thread);
+         assert!(
+             verbose
+                 .iter()
+                 .any(|r| r.message.starts_with("Sent message")),
+             "No send was logged: {:?}",
+             verbose
+         );
+         assert!(
+             verbose.iter().any(|r| r.message.starts_with("Received")),
+             "No receive was logged: {:?}",
+             verbose
+         );
+     });
+ }
+
+ /// Test case for connecting to a server listening on the IPv6 loopback address.
+ #[test]
+ fn test_client_connects_over_ipv6() {
+     let listener = match TcpListener::bind("[::1]:0") {
+         Ok(listener) => listener,
+         Err(e) => {
+             println!("Skipping, the IPv6 loopback address is unavailable: {}", e);
+             return;
+         }
+     };
+     let port = listener.local_addr().unwrap().port();
+     drop(listener);
+     let server =
+         Arc::new(Server::new(&format!("[::1]:{}", port), 10).expect("Failed to create server"));
+     let _running = RunningServer {
+         handle: Some(setup_server_thread(server.clone())),
+         server,
+     };
+
+     // The literal is accepted with or without brackets
+     let mut bracketed = client::Client::new("[::1]", port.into(), 5000);
+     assert!(
+         bracketed.connect().is_ok(),
+         "Failed to connect to a bracketed IPv6 literal"
+     );
+
+     let mut client = client::Client::new("::1", port.into(), 5000);
+     assert!(client.connect().is_ok(), "Failed to connect over IPv6");
+     let echo = client_message::Message::EchoMessage(EchoMessage {
+         content: "v6".to_string(),
+     });
+     assert!(client.send(echo).is_ok(), "Failed to send message");
+     match client
+         .receive_with_retry(1)
+         .expect("Failed to receive response")
+         .message
+     {
+         Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "v6"),
+         other => panic!("Expected EchoMessage, but received {:?}", other),
+     }
+ }
+
+ /// Test case for a slow handler using up the session timeout, which closes the connection.
+ #[test]
+ fn test_session_timeout_closes_slow_connection() {
+     let configure = |server: Server| {
+         server
+             .with_session_timeout(Duration::from_millis(300))
+             .with_handler(|message| {
+                 if let client_message::Message::EchoMessage(_) = message {
+                     thread::sleep(Duration::from_millis(200));
+                 }
+                 None
+             })
+     };
+     with_configured_server(configure, |_, port| {
+         let mut client = connect_client(port);
+         for i in 0..3 {
+             let echo = client_message::Message::EchoMessage(EchoMessage {
+                 content: format!("slow {}", i),
+             });
+             assert!(client.send(echo).is_ok(), "Failed to send message");
+         }
+
+         // The second echo exceeds the budget: it is answered, the third is not
+         for i in 0..2 {
+             match client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response")
+                 .message
+             {
+                 Some(server_message::Message::EchoMessage(echo)) => {
+                     assert_eq!(echo.content, format!("slow {}", i))
+                 }
+                 other => panic!("Expected EchoMessage, but received {:?}", other),
+             }
+         }
+         let error = client
+             .receive_with_retry(1)
+             .expect_err("Connection should have been closed");
+         assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+     });
+ }
+
+ /// Test case for a client that floods requests faster than they are answered being pushed back
+ /// instead of buffered.
+ #[test]
+ fn test_backpressure_bounds_queued_requests() {
+     // Answering is slow, but the responses are too small to fill the socket buffers: only the
+     // limit stops reading
+     with_configured_server(
+         |server| server.with_echo_delay(Duration::from_millis(2)),
+         |server, port| {
+             let stream =
+                 TcpStream::connect(("localhost", port)).expect("Failed to connect to the server");
+             let echo = ClientMessage {
+                 message: Some(client_message::Message::EchoMessage(EchoMessage {
+                     content: "x".repeat(64),
+                 })),
+                 ..Default::default()
+             };
+             let request = frame::encode_frame(&echo.encode_to_vec());
+             let request_len = request.len();
+
+             let mut writer = stream.try_clone().unwrap();
+             let flood = thread::spawn(move || while writer.write_all(&request).is_ok() {});
+
+             let mut most_queued = 0;
+             let deadline = Instant::now() + Duration::from_millis(1500);
+             while Instant::now() < deadline {
+                 most_queued = most_queued.max(server.queued_bytes());
+                 thread::sleep(Duration::from_millis(5));
+             }
+             stream.shutdown(std::net::Shutdown::Both).unwrap();
+             flood.join().expect("Flooding thread panicked");
+
+             assert!(
+                 most_queued > MAX_QUEUED_BYTES / 2,
+                 "The flood never filled the queue: {} bytes",
+                 most_queued
+             );
+             assert!(
+                 most_queued < MAX_QUEUED_BYTES + request_len,
+                 "Server held {} bytes of queued requests",
+                 most_queued
+             );
+         },
+     );
+ }
+
+ /// Test case for the server greeting a new connection before its first request.
+ #[test]
+ fn test_greeting_sent_on_connect() {
+     with_configured_server(
+         |server| server.with_greeting("echo-test"),
+         |_, port| {
+             // The greeting arrives without sending anything
+             let mut stream =
+                 TcpStream::connect(("localhost", port)).expect("Failed to connect to the server");
+             stream
+                 .set_read_timeout(Some(Duration::from_secs(5)))
+                 .unwrap();
+             let payload = frame::read_frame(&mut stream).expect("Failed to receive the greeting");
+             match ServerMessage::decode(payload.as_slice())
+                 .expect("Failed to decode the greeting")
+                 .message
+             {
+                 Some(server_message::Message::Greeting(greeting)) => {
+                     assert_eq!(greeting.server_name, "echo-test");
+                     assert_eq!(greeting.version, env!("CARGO_PKG_VERSION"));
+                 }
+                 other => panic!("Expected Greeting, but received {:?}", other),
+             }
+
+             // The client reads it before the handshake, then works as usual
+             let mut client = connect_client(port);
+             assert_eq!(
+                 client
+                     .greeting()
+                     .map(|greeting| greeting.server_name.as_str()),
+                 Some("echo-test")
+             );
+             let echo = client_message::Message::EchoMessage(EchoMessage {
+                 content: "after greeting".to_string(),
+             });
+             assert!(client.send(echo).is_ok(), "Failed to send message");
+             match client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response")
+                 .message
+             {
+                 Some(server_message::Message::EchoMessage(echo)) => {
+                     assert_eq!(echo.content, "after greeting")
+                 }
+                 other => panic!("Expected EchoMessage, but received {:?}", other),
+             }
+         },
+     );
+ }
+
+ /// Test case for collecting the responses to pipelined echoes in one call.
+ #[test]
+ fn test_collect_pipelined_responses() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+         for i in 0..5 {
+             let echo = client_message::Message::EchoMessage(EchoMessage {
+                 content: format!("pipelined {}", i),
+             });
+             assert!(client.send(echo).is_ok(), "Failed to send message");
+         }
+
+         let responses = client
+             .collect_responses(5, Duration::from_secs(5))
+             .expect("Failed to collect responses");
+         let contents: Vec<String> = responses
+             .into_iter()
+             .map(|response| match response.message {
+                 Some(server_message::Message::EchoMessage(echo)) => echo.content,
+                 other => panic!("Expected EchoMessage, but received {:?}", other),
+             })
+             .collect();
+         assert_eq!(
+             contents,
+             (0..5)
+                 .map(|i| format!("pipelined {}", i))
+                 .collect::<Vec<_>>()
+         );
+
+         // Nothing else is pending, so asking for more returns empty-handed after the timeout
+         let started = Instant::now();
+         let extra = client
+             .collect_responses(1, Duration::from_millis(200))
+             .expect("Failed to collect responses");
+         assert!(extra.is_empty(), "Unexpected responses: {:?}", extra);
+         assert!(started.elapsed() >= Duration::from_millis(200));
+     });
+ }
+
+ /// Test case for a client configured with a zero timeout refusing to connect.
+ #[test]
+ fn test_zero_timeout_rejected() {
+     with_running_server(|_, port| {
+         let mut client = client::Client::new("localhost", port.into(), 0);
+         let error = client
+             .connect()
+             .expect_err("A zero read timeout was accepted");
+         assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+         let mut client = client::Client::builder()
+             .port(port.into())
+             .connect_timeout(Duration::ZERO)
+             .build();
+         let error = client
+             .connect()
+             .expect_err("A zero connect timeout was accepted");
+         assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+     });
+ }
+
+ /// Test case for closing one client's connection by address while another stays connected.
+ #[test]
+ fn test_disconnect_client_by_address() {
+     with_running_server(|server, port| {
+         let mut kicked = client::Client::new("localhost", port.into(), 5000);
+         let mut kept = client::Client::new("localhost", port.into(), 5000);
+         assert!(
+             kicked.connect().is_ok(),
+             "Failed to connect the first client"
+         );
+         assert!(
+             kept.connect().is_ok(),
+             "Failed to connect the second client"
+         );
+
+         let address = kicked.local_addr().expect("Client has no local address");
+         assert!(
+             server.disconnect_client(address),
+             "Connection was not found"
+         );
+         assert!(
+             !server.disconnect_client(address),
+             "Connection was found twice"
+         );
+
+         let error = kicked
+             .receive_with_retry(1)
+             .expect_err("Connection should have been closed");
+         assert_eq!(error.kind(), std::io::ErrorKind::ConnectionAborted);
+         assert!(
+             !kicked.is_connected(),
+             "Disconnected client still looks connected"
+         );
+
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "still served".to_string(),
+         });
+         assert!(kept.send(echo).is_ok(), "Failed to send message");
+         match kept
+             .receive_with_retry(1)
+             .expect("Failed to receive response")
+             .message
+         {
+             Some(server_message::Message::EchoMessage(echo)) => {
+                 assert_eq!(echo.content, "still served")
+             }
+             other => panic!("Expected EchoMessage, but received {:?}", other),
+         }
+     });
+ }
+
+ /// Test case for an echo larger than the response frame size arriving in chunks and being
+ /// reassembled.
+ #[test]
+ fn test_large_echo_streamed_in_chunks() {
+     const FRAME_SIZE: usize = 64 * 1024;
+
+     with_configured_server(
+         |server| server.with_max_response_frame(FRAME_SIZE),
+         |_, port| {
+             let content: String = (0..300 * 1024)
+                 .map(|i| char::from(b'a' + (i % 26) as u8))
+                 .collect();
+             let echo = client_message::Message::EchoMessage(EchoMessage {
+                 content: content.clone(),
+             });
+
+             // On the wire: several chunks, each within the frame size, only the last one final
+             let mut stream =
+                 TcpStream::connect(("localhost", port)).expect("Failed to connect to the server");
+             stream
+                 .set_read_timeout(Some(Duration::from_secs(5)))
+                 .unwrap();
+             frame::write_frame(
+                 &mut stream,
+                 &ClientMessage {
+                     message: Some(echo.clone()),
+                     ..Default::default()
+                 }
+                 .encode_to_vec(),
+             )
+             .unwrap();
+             let mut assembled = Vec::new();
+             let mut chunks = 0;
+             loop {
+                 let payload = frame::read_frame(&mut stream).expect("Failed to receive a chunk");
+                 assert!(
+                     payload.len() <= FRAME_SIZE,
+                     "Chunk of {} bytes exceeds the frame size",
+                     payload.len()
+                 );
+                 match ServerMessage::decode(payload.as_slice())
+                     .expect("Failed to decode chunk")
+                     .message
+                 {
+                     Some(server_message::Message::Chunk(chunk)) => {
+                         chunks += 1;
+                         assembled.extend(chunk.data);
+                         if !chunk.more {
+                             break;
+                         }
+                     }
+                     other => panic!("Expected Chunk, but received {:?}", other),
+                 }
+             }
+             assert!(chunks > 1, "Response was not split");
+             match ServerMessage::decode(assembled.as_slice())
+                 .expect("Failed to decode reassembled response")
+                 .message
+             {
+                 Some(server_message::Message::EchoMessage(response)) => {
+                     assert_eq!(response.content, content)
+                 }
+                 other => panic!("Expected EchoMessage, but received {:?}", other),
+             }
+
+             // The client reassembles transparently
+             let mut client = connect_client(port);
+             assert!(client.send(echo).is_ok(), "Failed to send message");
+             match client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response")
+                 .message
+             {
+                 Some(server_message::Message::EchoMessage(response)) => {
+                     assert_eq!(response.content, content)
+                 }
+                 other => panic!("Expected EchoMessage, but received {:?}", other),
+             }
+         },
+     );
+ }
+
+ /// Test case for echoing through an in-process transport instead of a socket.
+ #[test]
+ fn test_echo_over_memory_transport() {
+     let (mut client, handler) = connect_in_process(Server::in_process());
+
+     let echo = client_message::Message::EchoMessage(EchoMessage {
+         content: "in-process".to_string(),
+     });
+     client.send(echo).expect("Failed to send message");
+     match client
+         .receive_with_retry(1)
+         .expect("Failed to receive response")
+         .message
+     {
+         Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "in-process"),
+         other => panic!("Expected EchoMessage, but received {:?}", other),
+     }
+
+     // Dropping our end is a disconnect; the handler finishes cleanly
+     drop(client);
+     assert!(
+         handler.join().expect("Handler panicked").is_ok(),
+         "Handler reported an error"
+     );
+ }
+
+ /// Test case for a client disappearing while its response is written, which is not an error.
+ #[test]
+ fn test_client_gone_mid_response_is_not_an_error() {
+     log_capture::install();
+     with_configured_server(
+         |server| {
+             server
+                 .with_handler_thread_name("gone-mid-response")
+                 .with_echo_delay(Duration::from_millis(200))
+                 .with_max_response_frame(1024)
+         },
+         |_, port| {
+             // Ask for a response written in hundreds of frames, then leave before it starts
+             let mut stream =
+                 TcpStream::connect(("localhost", port)).expect("Failed to connect to the server");
+             let echo = client_message::Message::EchoMessage(EchoMessage {
+                 content: "x".repeat(512 * 1024),
+             });
+             frame::write_frame(
+                 &mut stream,
+                 &ClientMessage {
+                     message: Some(echo),
+                     ..Default::default()
+                 }
+                 .encode_to_vec(),
+             )
+             .unwrap();
+             drop(stream);
+
+             let deadline = Instant::now() + Duration::from_secs(5);
+             let finished = |records: &[log_capture::CapturedRecord]| {
+                 records
+                     .iter()
+                     .any(|r| r.message.contains("before the response was sent"))
+             };
+             while Instant::now() < deadline
+                 && !finished(&log_capture::records_from("gone-mid-response"))
+             {
+                 thread::sleep(Duration::from_millis(10));
+             }
+
+             let records = log_capture::records_from("gone-mid-response");
+             assert!(
+                 finished(&records),
+                 "Disconnect was not detected: {:?}",
+                 records
+             );
+             assert!(
+                 records.iter().all(|r| r.level > log::Level::Error),
+                 "Unexpected error logs: {:?}",
+                 records
+             );
+         },
+     );
+ }
+
+ /// Test case for connections that never say Hello being capped by the pending handshake limit.
+ #[test]
+ fn test_pending_handshakes_are_limited() {
+     with_configured_server(
+         |server| server.with_max_pending_handshakes(3),
+         |server, port| {
+             let idle: Vec<TcpStream> = (0..5)
+                 .map(|_| {
+                     TcpStream::connect(("localhost", port))
+                         .expect("Failed to connect to the server")
+                 })
+                 .collect();
+
+             // Refused connections are closed by the server; the others stay open and silent
+             let refused = idle
+                 .iter()
+                 .filter(|stream| {
+                     stream
+                         .set_read_timeout(Some(Duration::from_millis(500)))
+                         .unwrap();
+                     matches!((&**stream).read(&mut [0u8; 1]), Ok(0))
+                 })
+                 .count();
+             assert_eq!(
+                 refused, 2,
+                 "Expected the connections beyond the limit to be refused"
+             );
+             assert_eq!(server.active_clients(), 3);
+
+             // Once the idle connections are gone, handshaking clients are accepted again
+             drop(idle);
+             let deadline = Instant::now() + Duration::from_secs(5);
+             while server.active_clients() > 0 && Instant::now() < deadline {
+                 thread::sleep(Duration::from_millis(10));
+             }
+             let mut client = client::Client::new("localhost", port.into(), 5000);
+             assert!(
+                 client.connect().is_ok(),
+                 "Failed to connect after the idle connections left"
+             );
+         },
+     );
+ }
+
+ /// Test case for any request, not only a Hello, ending a connection's pending handshake.
+ #[test]
+ fn test_request_without_hello_ends_pending_handshake() {
+     with_configured_server(
+         |server| server.with_max_pending_handshakes(1),
+         |_, port| {
+             // This client never says Hello; its ping alone must free the pending slot
+             let mut silent =
+                 TcpStream::connect(("localhost", port)).expect("Failed to connect to the server");
+             let ping = ClientMessage {
+                 message: Some(client_message::Message::PingRequest(PingRequest {})),
+                 ..Default::default()
+             };
+             frame::write_frame(&mut silent, &ping.encode_to_vec()).unwrap();
+             let payload = frame::read_frame(&mut silent).expect("Failed to receive the pong");
+             match ServerMessage::decode(payload.as_slice())
+                 .expect("Failed to decode response")
+                 .message
+             {
+                 Some(server_message::Message::PongResponse(_)) => {}
+                 other => panic!("Expected PongResponse, but received {:?}", other),
+             }
+
+             let mut client = client::Client::new("localhost", port.into(), 5000);
+             assert!(
+                 client.connect().is_ok(),
+                 "A connection that sent a request still counted as pending"
+             );
+         },
+     );
+ }
+
+ /// Test case for `accept_one` refusing connections beyond the pending handshake limit.
+ #[test]
+ fn test_accept_one_limits_pending_handshakes() {
+     let (server, port) = create_server_with(|server| server.with_max_pending_handshakes(1))
+         .expect("Failed to create server");
+     let _first = TcpStream::connect(("localhost", port)).expect("Failed to connect to the server");
+     let first = server.accept_one().expect("Failed to accept a connection");
+
+     // The second connection is refused while the first one is pending; once the first is
+     // gone, the third one is accepted
+     let refused =
+         TcpStream::connect(("localhost", port)).expect("Failed to connect to the server");
+     let third = thread::spawn(move || {
+         refused
+             .set_read_timeout(Some(Duration::from_secs(5)))
+             .unwrap();
+         assert!(
+             matches!((&refused).read(&mut [0u8; 1]), Ok(0)),
+             "The second connection was not refused"
+         );
+         drop(first);
+         TcpStream::connect(("localhost", port)).expect("Failed to connect to the server")
+     });
+
+     let connection = server.accept_one().expect("Failed to accept a connection");
+     let third = third.join().expect("Client thread panicked");
+     assert_eq!(connection.peer_addr(), third.local_addr().unwrap());
+ }
+
+ /// Test case for the server returning the client's send timestamp and stamping its receive time.
+ #[test]
+ fn test_request_timestamps_echoed() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+
+         let sent_at = std::time::SystemTime::now()
+             .duration_since(std::time::UNIX_EPOCH)
+             .unwrap()
+             .as_micros() as u64;
+         let request = ClientMessage {
+             message: Some(client_message::Message::PingRequest(PingRequest {})),
+             client_send_ts: sent_at,
+             ..Default::default()
+         };
+         assert!(
+             client.send_raw(&request.encode_to_vec()).is_ok(),
+             "Failed to send request"
+         );
+         let response = client
+             .receive_with_retry(1)
+             .expect("Failed to receive response");
+         assert!(matches!(
+             response.message,
+             Some(server_message::Message::PongResponse(_))
+         ));
+         assert_eq!(
+             response.client_send_ts, sent_at,
+             "Client timestamp was not echoed"
+         );
+         assert!(
+             response.server_recv_ts >= sent_at,
+             "Server timestamp is missing or before the send"
+         );
+
+         // Requests without a timestamp get none back
+         assert!(
+             client
+                 .send(client_message::Message::PingRequest(PingRequest {}))
+                 .is_ok(),
+             "Failed to send ping"
+         );
+         let response = client
+             .receive_with_retry(1)
+             .expect("Failed to receive response");
+         assert_eq!((response.client_send_ts, response.server_recv_ts), (0, 0));
+     });
+ }
+
+ /// Test case for connecting right after `wait_ready` returns, without any retries.
+ #[test]
+ fn test_wait_ready_then_connect() {
+     let (server, port) = create_server().expect("Failed to create server");
+     let error = server
+         .wait_ready(Duration::from_millis(50))
+         .expect_err("Server is ready before running");
+     assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+
+     let mut running = RunningServer {
+         handle: Some(setup_server_thread(server.clone())),
+         server,
+     };
+     assert!(
+         running.server.wait_ready(Duration::from_secs(5)).is_ok(),
+         "Server did not become ready"
+     );
+
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+     running.server.stop();
+     running.join();
+ }
+
+ /// Test case for `run` returning without accepting anything after an earlier `stop`.
+ #[test]
+ fn test_stop_before_run_returns_immediately() {
+     let (server, port) = create_server().expect("Failed to create server");
+     // Queued in the listen backlog, so an accepting server would pick it up
+     let _pending = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+
+     server.stop();
+     let started = Instant::now();
+     assert!(server.run().is_ok(), "run failed after stop");
+     assert!(
+         started.elapsed() < Duration::from_millis(100),
+         "run took {:?} to return",
+         started.elapsed()
+     );
+
+     assert_eq!(server.active_clients(), 0, "A connection was handled");
+     assert_eq!(server.io_stats(), (0, 0), "Bytes were exchanged");
+     let error = server
+         .wait_ready(Duration::ZERO)
+         .expect_err("Server reported ready");
+     assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+ }
+
+ /// Test case for echoing over a client built from an already connected stream.
+ #[test]
+ fn test_client_from_existing_stream() {
+     with_running_server(|_, port| {
+         let stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+         let mut client = client::Client::from_stream(stream).expect("Handshake failed");
+         assert_eq!(client.protocol_version(), Some(PROTOCOL_VERSION));
+
+         let echo = EchoMessage {
+             content: "Brought my own socket".to_string(),
+         };
+         assert!(
+             client
+                 .send(client_message::Message::EchoMessage(echo.clone()))
+                 .is_ok(),
+             "Failed to send message"
+         );
+         let response = client
+             .receive_with_retry(1)
+             .expect("Failed to receive response");
+         assert_eq!(
+             response.message,
+             Some(server_message::Message::EchoMessage(echo))
+         );
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for sending a batch of buffered requests with one flush.
+ #[test]
+ fn test_buffered_sends_flushed_at_once() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+
+         let echoes: Vec<EchoMessage> = (0..100)
+             .map(|i| EchoMessage {
+                 content: format!("Buffered {}", i),
+             })
+             .collect();
+         for echo in &echoes {
+             client.send_buffered(client_message::Message::EchoMessage(echo.clone()));
+         }
+         let early = client
+             .collect_responses(1, Duration::from_millis(100))
+             .expect("Failed to collect responses");
+         assert!(
+             early.is_empty(),
+             "Buffered requests were sent before the flush"
+         );
+
+         assert!(client.flush().is_ok(), "Failed to flush buffered requests");
+         let responses = client
+             .collect_responses(echoes.len(), Duration::from_secs(5))
+             .expect("Failed to collect responses");
+         let received: Vec<_> = responses
+             .into_iter()
+             .map(|response| response.message)
+             .collect();
+         let expected: Vec<_> = echoes
+             .into_iter()
+             .map(|echo| Some(server_message::Message::EchoMessage(echo)))
+             .collect();
+         assert_eq!(received, expected);
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for a validator rejecting empty echoes while letting other echoes through.
+ #[test]
+ fn test_validator_rejects_empty_echo() {
+     with_configured_server(
+         |server| {
+             server.with_validator(|message| match message {
+                 client_message::Message::EchoMessage(echo) if echo.content.is_empty() => {
+                     Err("Echo content is empty".to_string())
+                 }
+                 _ => Ok(()),
+             })
+         },
+         |_, port| {
+             let mut client = connect_client(port);
+
+             let empty = EchoMessage {
+                 content: String::new(),
+             };
+             assert!(
+                 client
+                     .send(client_message::Message::EchoMessage(empty))
+                     .is_ok(),
+                 "Failed to send message"
+             );
+             match client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response")
+                 .message
+             {
+                 Some(server_message::Message::ErrorResponse(error)) => {
+                     assert_eq!(error.code(), ErrorCode::Rejected);
+                     assert_eq!(error.message, "Echo content is empty");
+                 }
+                 other => panic!("Expected ErrorResponse, but received {:?}", other),
+             }
+
+             let echo = EchoMessage {
+                 content: "Not empty".to_string(),
+             };
+             assert!(
+                 client
+                     .send(client_message::Message::EchoMessage(echo.clone()))
+                     .is_ok(),
+                 "Failed to send message"
+             );
+             let response = client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response");
+             assert_eq!(
+                 response.message,
+                 Some(server_message::Message::EchoMessage(echo))
+             );
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// Test case for counting the requests of two tenants separately.
+ #[test]
+ fn test_tenant_metrics_counted_per_tenant() {
+     with_running_server(|server, port| {
+         for (tenant, requests) in [("acme", 3), ("globex", 5)] {
+             let mut client = client::Client::builder()
+                 .port(port.into())
+                 .read_timeout(Duration::from_secs(5))
+                 .tenant(tenant)
+                 .build();
+             assert!(client.connect().is_ok(), "Failed to connect to the server");
+             for _ in 0..requests {
+                 assert!(
+                     client
+                         .send(client_message::Message::PingRequest(PingRequest {}))
+                         .is_ok(),
+                     "Failed to send ping"
+                 );
+                 assert!(
+                     client.receive_with_retry(1).is_ok(),
+                     "Failed to receive pong"
+                 );
+             }
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         }
+
+         let metrics = server.tenant_metrics();
+         assert_eq!(metrics.len(), 2, "Unexpected tenants: {:?}", metrics);
+         assert_eq!(metrics.get("acme"), Some(&3));
+         assert_eq!(metrics.get("globex"), Some(&5));
+     });
+ }
+
+ /// Test case for a Hello with an overlong tenant label being rejected and not counted.
+ #[test]
+ fn test_overlong_tenant_label_rejected() {
+     with_running_server(|server, port| {
+         let tenant = "t".repeat(MAX_TENANT_LEN + 1);
+         let mut client = client::Client::builder()
+             .port(port.into())
+             .read_timeout(Duration::from_secs(5))
+             .tenant(&tenant)
+             .build();
+         let error = client
+             .connect()
+             .expect_err("A Hello with an overlong tenant label was accepted");
+         assert!(
+             error.to_string().contains("Tenant labels are limited"),
+             "Unexpected error: {}",
+             error
+         );
+         assert!(
+             server.tenant_metrics().is_empty(),
+             "The overlong tenant was counted"
+         );
+     });
+ }
+
+ /// Test case for a client with a reconnect policy carrying on after a server restart.
+ #[test]
+ fn test_auto_reconnect_after_server_restart() {
+     let (mut running, port) = start_server(|server| server);
+
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     client.set_auto_reconnect(Some(client::ReconnectPolicy {
+         max_attempts: 10,
+         backoff: Duration::from_millis(50),
+     }));
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+     let echo = EchoMessage {
+         content: "Before restart".to_string(),
+     };
+     assert!(
+         client
+             .send(client_message::Message::EchoMessage(echo))
+             .is_ok(),
+         "Failed to send message"
+     );
+     assert!(
+         client.receive_with_retry(1).is_ok(),
+         "Failed to receive response"
+     );
+
+     // Take the server down together with the connection, then bring it back a little later
+     running.server.stop();
+     assert!(running
+         .server
+         .disconnect_client(client.local_addr().expect("Client has no address")));
+     running.join();
+     drop(running);
+     let restart = thread::spawn(move || {
+         thread::sleep(Duration::from_millis(200));
+         let server = Arc::new(
+             Server::new(&format!("localhost:{}", port), 10000).expect("Failed to restart server"),
+         );
+         RunningServer {
+             handle: Some(setup_server_thread(server.clone())),
+             server,
+         }
+     });
+
+     let echo = EchoMessage {
+         content: "After restart".to_string(),
+     };
+     assert!(
+         client
+             .send(client_message::Message::EchoMessage(echo.clone()))
+             .is_ok(),
+         "Failed to send after restart"
+     );
+     let response = client
+         .receive_with_retry(1)
+         .expect("Failed to receive response after restart");
+     assert_eq!(
+         response.message,
+         Some(server_message::Message::EchoMessage(echo))
+     );
+
+     assert!(
+         client.disconnect().is_ok(),
+         "Failed to disconnect from the server"
+     );
+     drop(restart.join().expect("Restart thread panicked"));
+ }
+
+ /// Test case for only requests sent with `send_idempotent` being resent after a lost connection.
+ #[test]
+ fn test_only_idempotent_requests_resent() {
+     // A fake server that drops the first two connections after reading one request
+     let listener = TcpListener::bind("localhost:0").expect("Failed to bind");
+     let port = listener.local_addr().expect("No address").port();
+     let fake_server = thread::spawn(move || {
+         let mut requests = Vec::new();
+         for connection in 0..3 {
+             let (mut stream, _) = listener.accept().expect("Failed to accept");
+             frame::read_frame(&mut stream).expect("No Hello received");
+             let hello = ServerMessage {
+                 message: Some(server_message::Message::HelloResponse(HelloResponse {
+                     protocol_version: PROTOCOL_VERSION,
+                     ..Default::default()
+                 })),
+                 ..Default::default()
+             };
+             frame::write_frame(&mut stream, &hello.encode_to_vec())
+                 .expect("Failed to answer Hello");
+             let payload = frame::read_frame(&mut stream).expect("No request received");
+             let request =
+                 ClientMessage::decode(payload.as_slice()).expect("Failed to decode request");
+             requests.push(request.message.clone());
+             if connection == 2 {
+                 let echo = ServerMessage {
+                     message: request.message.map(|message| match message {
+                         client_message::Message::EchoMessage(echo) => {
+                             server_message::Message::EchoMessage(echo)
+                         }
+                         other => panic!("Expected EchoMessage, but received {:?}", other),
+                     }),
+                     ..Default::default()
+                 };
+                 frame::write_frame(&mut stream, &echo.encode_to_vec())
+                     .expect("Failed to answer the request");
+             }
+         }
+         requests
+     });
+
+     let mut client = client::Client::builder()
+         .port(port.into())
+         .read_timeout(Duration::from_secs(5))
+         .auto_reconnect(true)
+         .build();
+     assert!(
+         client.connect().is_ok(),
+         "Failed to connect to the fake server"
+     );
+
+     // A plain send is delivered at most once: the lost connection is reported
+     let once = client_message::Message::EchoMessage(EchoMessage {
+         content: "once".to_string(),
+     });
+     assert!(client.send(once.clone()).is_ok(), "Failed to send message");
+     assert!(
+         client.receive_with_retry(1).is_err(),
+         "A response arrived over a dropped connection"
+     );
+
+     // An idempotent send reconnects and is resent once the connection drops again
+     let twice = client_message::Message::EchoMessage(EchoMessage {
+         content: "twice".to_string(),
+     });
+     assert!(
+         client.send_idempotent(twice.clone()).is_ok(),
+         "Failed to send message"
+     );
+     let response = client
+         .receive_with_retry(1)
+         .expect("The idempotent request was not resent");
+     assert_eq!(
+         response.message,
+         Some(server_message::Message::EchoMessage(EchoMessage {
+             content: "twice".to_string()
+         }))
+     );
+
+     assert_eq!(
+         fake_server.join().expect("Fake server panicked"),
+         vec![Some(once), Some(twice.clone()), Some(twice)]
+     );
+ }
+
+ /// Test case for `into_result` splitting error responses from regular ones.
+ #[test]
+ fn test_server_message_into_result() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+
+         let echo = EchoMessage {
+             content: "Fine".to_string(),
+         };
+         assert!(
+             client
+                 .send(client_message::Message::EchoMessage(echo.clone()))
+                 .is_ok(),
+             "Failed to send message"
+         );
+         let response = client
+             .receive_with_retry(1)
+             .expect("Failed to receive response");
+         assert!(!response.is_error());
+         assert_eq!(
+             response.into_result().map(|ok| ok.message),
+             Ok(Some(server_message::Message::EchoMessage(echo)))
+         );
+
+         assert!(
+             client.send_raw(&[0xff, 0xff]).is_ok(),
+             "Failed to send garbage"
+         );
+         let response = client
+             .receive_with_retry(1)
+             .expect("Failed to receive response");
+         assert!(response.is_error());
+         assert_eq!(
+             response.as_error().map(|error| error.code()),
+             Some(ErrorCode::MalformedMessage)
+         );
+         let error = response
+             .into_result()
+             .expect_err("Error response was not an Err");
+         assert_eq!(error.code(), ErrorCode::MalformedMessage);
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for a flood of malformed messages producing a bounded number of log records.
+ #[test]
+ fn test_malformed_flood_logs_are_throttled() {
+     const FLOOD: usize = 200;
+
+     log_capture::install();
+     with_configured_server(
+         |server| server.with_handler_thread_name("malformed-flood"),
+         |server, port| {
+             let mut client = connect_client(port);
+             for _ in 0..FLOOD {
+                 assert!(
+                     client.send_raw(&[0xff, 0xff]).is_ok(),
+                     "Failed to send garbage"
+                 );
+             }
+             let responses = client
+                 .collect_responses(FLOOD, Duration::from_secs(5))
+                 .expect("Failed to collect responses");
+             assert_eq!(
+                 responses.len(),
+                 FLOOD,
+                 "Every malformed message must still be answered"
+             );
+             assert!(responses.iter().all(|response| response.is_error()));
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+             let deadline = Instant::now() + Duration::from_secs(5);
+             while server.active_clients() > 0 && Instant::now() < deadline {
+                 thread::sleep(Duration::from_millis(10));
+             }
+
+             let records = log_capture::records_from("malformed-flood");
+             let decode_logs: Vec<_> = records
+                 .iter()
+                 .filter(|r| r.message.contains("Failed to decode"))
+                 .collect();
+             assert!(!decode_logs.is_empty(), "Decode failures were not logged");
+             assert!(
+                 decode_logs.len() <= 3,
+                 "Logged {} decode failures: {:?}",
+                 decode_logs.len(),
+                 decode_logs
+             );
+         },
+     );
+ }
+
+ /// Test case for `serve_once` answering a single addition and reporting its kind.
+ #[test]
+ fn test_serve_once_answers_one_request() {
+     let (server, _port) = create_server().expect("Failed to create server");
+     let (mut client_end, server_end) = transport::memory_pair();
+
+     let add = client_message::Message::AddRequest(AddRequest { a: 10, b: 20 });
+     frame::write_frame(
+         &mut client_end,
+         &ClientMessage {
+             message: Some(add),
+             ..Default::default()
+         }
+         .encode_to_vec(),
+     )
+     .unwrap();
+     assert_eq!(
+         server
+             .serve_once(server_end)
+             .expect("Failed to serve the request"),
+         MessageKind::Add
+     );
+
+     let payload = frame::read_frame(&mut client_end).expect("Failed to receive response");
+     match ServerMessage::decode(payload.as_slice())
+         .expect("Failed to decode response")
+         .message
+     {
+         Some(server_message::Message::AddResponse(add_response)) => {
+             assert_eq!(add_response.result, 30)
+         }
+         other => panic!("Expected AddResponse, but received {:?}", other),
+     }
+     // The server's end was dropped after the one request
+     assert_eq!(client_end.read(&mut [0u8; 1]).expect("Failed to read"), 0);
+ }
+
+ /// Recurses `depth` times with a kilobyte of stack per call and returns the depth reached.
+ fn recurse(depth: u64) -> u64 {
+     let frame = std::hint::black_box([1u8; 1024]);
+     match depth {
+         0 => 0,
+         _ => recurse(depth - 1) + u64::from(frame[0]),
+     }
+ }
+
+ /// Test case for a deeply recursive handler running on threads with an enlarged stack.
+ #[test]
+ fn test_handler_stack_size_configurable() {
+     let configure = |server: Server| {
+         server
+             .with_handler_stack_size(64 * 1024 * 1024)
+             .with_handler(|message| match message {
+                 client_message::Message::EchoMessage(echo) => {
+                     let depth = recurse(echo.content.parse().ok()?);
+                     Some(server_message::Message::EchoMessage(EchoMessage {
+                         content: depth.to_string(),
+                     }))
+                 }
+                 _ => None,
+             })
+     };
+     with_configured_server(configure, |_, port| {
+         let mut client = client::Client::new("localhost", port.into(), 5000);
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+         // About 8 MiB of stack, more than handler threads get by default
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "8192".to_string(),
+         });
+         assert!(client.send(echo).is_ok(), "Failed to send message");
+         match client
+             .receive_with_retry(1)
+             .expect("Failed to receive response")
+             .message
+         {
+             Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "8192"),
+             other => panic!("Expected EchoMessage, but received {:?}", other),
+         }
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for echoing with `Client::echo`, including an error answer.
+ #[test]
+ fn test_client_echo_shortcut() -> std::io::Result<()> {
+     let server = Server::in_process().with_validator(|message| match message {
+         client_message::Message::EchoMessage(echo) if echo.content.is_empty() => {
+             Err("Nothing to echo".to_string())
+         }
+         _ => Ok(()),
+     });
+     let (mut client, handler) = connect_in_process(server);
+     assert_eq!(client.echo("hi")?, "hi");
+
+     let error = client
+         .echo("")
+         .expect_err("An error response was returned as an echo");
+     assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+     assert!(
+         error.to_string().contains("Nothing to echo"),
+         "Unexpected error: {}",
+         error
+     );
+
+     client.disconnect()?;
+     assert!(
+         handler.join().expect("Handler panicked").is_ok(),
+         "Handler reported an error"
+     );
+     Ok(())
+ }
+
+ /// Test case for adding with `Client::add`, including 64-bit operands and an error answer.
+ #[test]
+ fn test_client_add_shortcut() -> std::io::Result<()> {
+     let server = Server::in_process().with_validator(|message| match message {
+         client_message::Message::AddRequest(add) if add.a < 0 => {
+             Err("Negative operand".to_string())
+         }
+         _ => Ok(()),
+     });
+     let (mut client, handler) = connect_in_process(server);
+     assert_eq!(client.add(10, 20)?, 30);
+     assert_eq!(client.add(i64::from(i32::MAX), 1)?, i64::from(i32::MAX) + 1);
+
+     let error = client
+         .add(-1, 1)
+         .expect_err("An error response was returned as a sum");
+     assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+     assert!(
+         error.to_string().contains("Negative operand"),
+         "Unexpected error: {}",
+         error
+     );
+
+     client.disconnect()?;
+     assert!(
+         handler.join().expect("Handler panicked").is_ok(),
+         "Handler reported an error"
+     );
+     Ok(())
+ }
+
+ /// Test case for the original int32 sum field being filled next to the 64-bit one.
+ #[test]
+ fn test_add_response_keeps_int32_result() {
+     let (mut client, handler) = connect_in_process(Server::in_process());
+     let add = client_message::Message::AddRequest(AddRequest {
+         a: i64::from(i32::MAX),
+         b: 1,
+     });
+     let response = client
+         .request_expect::<AddResponse>(add)
+         .expect("Addition failed");
+     assert_eq!(response.result64, i64::from(i32::MAX) + 1);
+     assert_eq!(
+         response.result,
+         i32::MIN,
+         "The int32 field should hold the sum truncated to 32 bits"
+     );
+
+     assert!(client.disconnect().is_ok(), "Failed to disconnect");
+     assert!(
+         handler.join().expect("Handler panicked").is_ok(),
+         "Handler reported an error"
+     );
+ }
+
+ /// Test case for finished worker threads being reaped during connection churn.
+ #[test]
+ fn test_finished_workers_are_reaped() {
+     with_running_server(|server, port| {
+         // Each connection closes at once, so its handler finishes right after starting
+         for _ in 0..100 {
+             drop(TcpStream::connect(("localhost", port)).expect("Failed to connect"));
+         }
+
+         let deadline = Instant::now() + Duration::from_secs(5);
+         while server.worker_count() > 0 && Instant::now() < deadline {
+             thread::sleep(Duration::from_millis(10));
+         }
+         assert_eq!(server.worker_count(), 0, "Finished workers were kept");
+     });
+ }
+
+ /// Test case for `run` returning by itself once the maximum runtime has passed.
+ #[test]
+ fn test_max_runtime_stops_server() {
+     let (server, port) =
+         create_server_with(|server| server.with_max_runtime(Duration::from_millis(300)))
+             .expect("Failed to create server");
+
+     let started = Instant::now();
+     let runner = thread::spawn({
+         let server = server.clone();
+         move || server.run()
+     });
+     server
+         .wait_ready(Duration::from_secs(5))
+         .expect("Server did not become ready");
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     assert!(
+         client.connect().is_ok(),
+         "Failed to connect while the server was running"
+     );
+
+     assert!(
+         runner.join().expect("Server thread panicked").is_ok(),
+         "run failed"
+     );
+     let elapsed = started.elapsed();
+     assert!(
+         elapsed >= Duration::from_millis(300),
+         "run returned after only {:?}",
+         elapsed
+     );
+     assert!(
+         elapsed < Duration::from_secs(2),
+         "run took {:?} to return",
+         elapsed
+     );
+ }
+
+ /// Test case for the handled-message callback firing once per request with its kind.
+ #[test]
+ fn test_message_handled_callback() {
+     let observed = Arc::new(Mutex::new(Vec::new()));
+     let configure = |server: Server| {
+         let observed = observed.clone();
+         server.on_message_handled(move |kind, latency, peer| {
+             observed.lock().unwrap().push((kind, latency, peer))
+         })
+     };
+     with_configured_server(configure, |_, port| {
+         let mut client = client::Client::new("localhost", port.into(), 5000);
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+         assert_eq!(client.echo("observed").expect("Echo failed"), "observed");
+         assert_eq!(client.add(1, 2).expect("Addition failed"), 3);
+         assert!(
+             client
+                 .send(client_message::Message::PingRequest(PingRequest {}))
+                 .is_ok(),
+             "Failed to send ping"
+         );
+         assert!(
+             client.receive_with_retry(1).is_ok(),
+             "Failed to receive pong"
+         );
+         let local_addr = client.local_addr().expect("Client has no address");
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+
+         // The callback runs after the response is written, so it may trail the client slightly
+         let deadline = Instant::now() + Duration::from_secs(5);
+         while observed.lock().unwrap().len() < 4 && Instant::now() < deadline {
+             thread::sleep(Duration::from_millis(10));
+         }
+         let observed = observed.lock().unwrap().clone();
+         let kinds: Vec<MessageKind> = observed.iter().map(|(kind, _, _)| *kind).collect();
+         assert_eq!(
+             kinds,
+             [
+                 MessageKind::Hello,
+                 MessageKind::Echo,
+                 MessageKind::Add,
+                 MessageKind::Ping
+             ]
+         );
+         for (kind, latency, peer) in observed {
+             assert_eq!(peer, local_addr, "Wrong peer reported for {:?}", kind);
+             assert!(
+                 latency < Duration::from_secs(5),
+                 "Implausible latency {:?} for {:?}",
+                 latency,
+                 kind
+             );
+         }
+     });
+ }
+
+ /// Test case for one thread sending while another receives on a split client.
+ #[test]
+ fn test_split_client_concurrent_send_and_receive() {
+     const MESSAGES: usize = 50;
+
+     with_running_server(|_, port| {
+         let client = connect_client(port);
+         let (mut sender, mut receiver) = client.split().expect("Failed to split the client");
+
+         let receiving = thread::spawn(move || {
+             (0..MESSAGES)
+                 .map(|_| {
+                     match receiver
+                         .receive()
+                         .expect("Failed to receive response")
+                         .message
+                     {
+                         Some(server_message::Message::EchoMessage(echo)) => echo.content,
+                         other => panic!("Expected EchoMessage, but received {:?}", other),
+                     }
+                 })
+                 .collect::<Vec<_>>()
+         });
+         let sending = thread::spawn(move || {
+             for i in 0..MESSAGES {
+                 let echo = EchoMessage {
+                     content: format!("Split {}", i),
+                 };
+                 sender
+                     .send(client_message::Message::EchoMessage(echo))
+                     .expect("Failed to send message");
+             }
+         });
+
+         assert!(sending.join().is_ok(), "Sending thread panicked");
+         let received = receiving.join().expect("Receiving thread panicked");
+         let expected: Vec<String> = (0..MESSAGES).map(|i| format!("Split {}", i)).collect();
+         assert_eq!(received, expected);
+     });
+ }
+
+ /// Test case for the shutdown report accounting for every request in flight or queued.
+ #[test]
+ fn test_drain_and_report_counts_requests() {
+     with_configured_server(
+         |server| {
+             server
+                 .with_echo_delay(Duration::from_millis(100))
+                 .with_validator(|message| match message {
+                     client_message::Message::EchoMessage(echo) if echo.content.is_empty() => {
+                         Err("Empty".to_string())
+                     }
+                     _ => Ok(()),
+                 })
+         },
+         |server, port| {
+             let mut client = client::Client::builder()
+                 .port(port.into())
+                 .read_timeout(Duration::from_secs(5))
+                 .raw_framing(false)
+                 .build();
+             assert!(client.connect().is_ok(), "Failed to connect to the server");
+             // The handshake is counted right after its response is written; keep it out of the
+             // report
+             thread::sleep(Duration::from_millis(50));
+             // Handled, rejected, handled
+             for content in ["first", "", "second"] {
+                 client.send_buffered(client_message::Message::EchoMessage(EchoMessage {
+                     content: content.to_string(),
+                 }));
+             }
+             assert!(client.flush().is_ok(), "Failed to send the requests");
+             // A request whose frame is only half sent is cut off by the drain
+             let cut_off = ClientMessage {
+                 message: Some(client_message::Message::EchoMessage(EchoMessage {
+                     content: "cut off".to_string(),
+                 })),
+                 ..Default::default()
+             }
+             .encode_to_vec();
+             let mut partial = (cut_off.len() as u32).to_be_bytes().to_vec();
+             partial.extend_from_slice(&cut_off[..cut_off.len() / 2]);
+             assert!(
+                 client.send_raw(&partial).is_ok(),
+                 "Failed to send the partial request"
+             );
+
+             let report = server.drain_and_report();
+             assert_eq!(
+                 (report.handled, report.rejected, report.dropped),
+                 (2, 1, 1),
+                 "Unexpected report: {:?}",
+                 report
+             );
+             assert!(
+                 report.duration >= Duration::from_millis(100),
+                 "Drain took only {:?}",
+                 report.duration
+             );
+         },
+     );
+ }
+
+ /// Test case for a connection with the wrong magic being closed while the right one is served.
+ #[test]
+ fn test_wrong_frame_magic_rejected() {
+     with_configured_server(
+         |server| server.with_frame_magic(*b"ER"),
+         |_, port| {
+             let mut stray = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+             stray
+                 .set_read_timeout(Some(Duration::from_secs(1)))
+                 .unwrap();
+             let hello = ClientMessage {
+                 message: Some(client_message::Message::HelloRequest(HelloRequest {
+                     protocol_version: PROTOCOL_VERSION,
+                     ..Default::default()
+                 })),
+                 ..Default::default()
+             };
+             stray.write_all(b"GE").unwrap();
+             frame::write_frame(&mut stray, &hello.encode_to_vec()).unwrap();
+             let started = Instant::now();
+             match stray.read(&mut [0u8; 16]) {
+                 Ok(0) => {}
+                 Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => {}
+                 other => panic!("Expected the connection to be closed, got {:?}", other),
+             }
+             assert!(
+                 started.elapsed() < Duration::from_millis(500),
+                 "Rejection took {:?}",
+                 started.elapsed()
+             );
+
+             let mut client = client::Client::builder()
+                 .port(port.into())
+                 .read_timeout(Duration::from_secs(5))
+                 .magic(*b"ER")
+                 .build();
+             assert!(
+                 client.connect().is_ok(),
+                 "Failed to connect with the right magic"
+             );
+             assert_eq!(client.echo("magic").expect("Echo failed"), "magic");
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// Test case for collecting a stream of three responses terminated by `StreamEnd`.
+ #[test]
+ fn test_request_stream_collects_all_responses() {
+     with_configured_server(
+         |server| {
+             server.with_stream_handler(|message| match message {
+                 client_message::Message::EchoMessage(echo) => Some(
+                     (1..=3)
+                         .map(|i| {
+                             server_message::Message::EchoMessage(EchoMessage {
+                                 content: format!("{} {}", echo.content, i),
+                             })
+                         })
+                         .collect(),
+                 ),
+                 _ => None,
+             })
+         },
+         |_, port| {
+             let mut client = connect_client(port);
+             let request = client_message::Message::EchoMessage(EchoMessage {
+                 content: "part".to_string(),
+             });
+             let contents: Vec<String> = client
+                 .request_stream(request)
+                 .map(
+                     |response| match response.expect("Failed to receive response").message {
+                         Some(server_message::Message::EchoMessage(echo)) => echo.content,
+                         other => panic!("Expected EchoMessage, but received {:?}", other),
+                     },
+                 )
+                 .collect();
+             assert_eq!(contents, ["part 1", "part 2", "part 3"]);
+
+             // Requests the stream handler declines are answered as usual
+             assert!(
+                 client
+                     .send(client_message::Message::PingRequest(PingRequest {}))
+                     .is_ok(),
+                 "Failed to send ping"
+             );
+             match client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response")
+                 .message
+             {
+                 Some(server_message::Message::PongResponse(_)) => {}
+                 other => panic!("Expected PongResponse, but received {:?}", other),
+             }
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// Test case for an error inside a stream being yielded without ending it, unlike a rejection.
+ #[test]
+ fn test_request_stream_continues_past_error_item() {
+     with_configured_server(
+         |server| {
+             server
+                 .with_stream_handler(|message| match message {
+                     client_message::Message::EchoMessage(echo) => Some(vec![
+                         server_message::Message::EchoMessage(echo.clone()),
+                         server_message::Message::ErrorResponse(ErrorResponse {
+                             message: "Item failed".to_string(),
+                             code: ErrorCode::InvalidArgument.into(),
+                         }),
+                         server_message::Message::EchoMessage(echo.clone()),
+                     ]),
+                     _ => None,
+                 })
+                 .with_validator(|message| match message {
+                     client_message::Message::EchoMessage(echo) if echo.content.is_empty() => {
+                         Err("Empty".to_string())
+                     }
+                     _ => Ok(()),
+                 })
+         },
+         |_, port| {
+             let mut client = connect_client(port);
+             let request = client_message::Message::EchoMessage(EchoMessage {
+                 content: "item".to_string(),
+             });
+             let errors: Vec<bool> = client
+                 .request_stream(request)
+                 .map(|response| response.expect("Failed to receive response").is_error())
+                 .collect();
+             assert_eq!(
+                 errors,
+                 [false, true, false],
+                 "The stream should go on past the failed item"
+             );
+
+             // A rejected request gets no StreamEnd, so its error alone ends the stream
+             let rejected = client_message::Message::EchoMessage(EchoMessage {
+                 content: String::new(),
+             });
+             let errors: Vec<bool> = client
+                 .request_stream(rejected)
+                 .map(|response| response.expect("Failed to receive response").is_error())
+                 .collect();
+             assert_eq!(errors, [true]);
+             assert_eq!(
+                 client.echo("after").expect("Echo after the streams failed"),
+                 "after"
+             );
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// Test case for a connection that never sends Hello being closed after the handshake timeout.
+ #[test]
+ fn test_handshake_timeout_drops_silent_client() {
+     with_configured_server(
+         |server| server.with_handshake_timeout(Duration::from_millis(200)),
+         |server, port| {
+             let mut silent = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+             silent
+                 .set_read_timeout(Some(Duration::from_secs(2)))
+                 .unwrap();
+             let started = Instant::now();
+             assert_eq!(
+                 silent
+                     .read(&mut [0u8; 16])
+                     .expect("Connection was not closed in time"),
+                 0
+             );
+             let elapsed = started.elapsed();
+             assert!(
+                 elapsed >= Duration::from_millis(150),
+                 "Closed after only {:?}",
+                 elapsed
+             );
+             assert!(
+                 elapsed < Duration::from_secs(1),
+                 "Closed only after {:?}",
+                 elapsed
+             );
+
+             let deadline = Instant::now() + Duration::from_secs(5);
+             while server.active_clients() > 0 && Instant::now() < deadline {
+                 thread::sleep(Duration::from_millis(10));
+             }
+             assert_eq!(
+                 server.active_clients(),
+                 0,
+                 "Connection slot was not released"
+             );
+
+             // A client completing the handshake in time is served past the deadline
+             let mut client = connect_client(port);
+             thread::sleep(Duration::from_millis(300));
+             assert_eq!(
+                 client.echo("still here").expect("Echo failed"),
+                 "still here"
+             );
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// In-memory tee sink whose contents the test can inspect.
+ #[derive(Clone, Default)]
+ struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+ impl Write for SharedSink {
+     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+         self.0.lock().unwrap().extend_from_slice(buf);
+         Ok(buf.len())
+     }
+
+     fn flush(&mut self) -> std::io::Result<()> {
+         Ok(())
+     }
+ }
+
+ /// Test case for a client that never says Hello being served past the handshake timeout.
+ #[test]
+ fn test_handshake_timeout_spares_client_without_hello() {
+     with_configured_server(
+         |server| server.with_handshake_timeout(Duration::from_millis(200)),
+         |_, port| {
+             let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+             stream
+                 .set_read_timeout(Some(Duration::from_secs(5)))
+                 .unwrap();
+             let ping = ClientMessage {
+                 message: Some(client_message::Message::PingRequest(PingRequest {})),
+                 ..Default::default()
+             };
+             // Idle between requests, with an empty queue, well past the deadline
+             for _ in 0..4 {
+                 frame::write_frame(&mut stream, &ping.encode_to_vec()).unwrap();
+                 let payload = frame::read_frame(&mut stream)
+                     .expect("Connection closed although requests were sent");
+                 match ServerMessage::decode(payload.as_slice())
+                     .expect("Failed to decode response")
+                     .message
+                 {
+                     Some(server_message::Message::PongResponse(_)) => {}
+                     other => panic!("Expected PongResponse, but received {:?}", other),
+                 }
+                 thread::sleep(Duration::from_millis(150));
+             }
+         },
+     );
+ }
+
+ /// Test case for every request and response of a connection appearing in the tee sink.
+ #[test]
+ fn test_tee_copies_all_traffic() {
+     let sink = SharedSink::default();
+     let peer = with_configured_server(
+         |server| server.with_tee(sink.clone()),
+         |_, port| {
+             let mut client = client::Client::new("localhost", port.into(), 5000);
+             assert!(client.connect().is_ok(), "Failed to connect to the server");
+             assert_eq!(client.echo("teed echo").expect("Echo failed"), "teed echo");
+             assert_eq!(client.add(2, 3).expect("Addition failed"), 5);
+             let peer = client.local_addr().expect("Client has no address");
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+             peer
+         },
+     );
+
+     let deadline = Instant::now() + Duration::from_secs(5);
+     let lines = loop {
+         let captured =
+             String::from_utf8(sink.0.lock().unwrap().clone()).expect("Tee output is not UTF-8");
+         let lines: Vec<String> = captured.lines().map(String::from).collect();
+         if lines.len() >= 6 || Instant::now() >= deadline {
+             break lines;
+         }
+         thread::sleep(Duration::from_millis(10));
+     };
+     assert_eq!(lines.len(), 6, "Unexpected tee output: {:#?}", lines);
+     let expected = [
+         ("->", "HelloRequest"),
+         ("<-", "HelloResponse"),
+         ("->", "teed echo"),
+         ("<-", "teed echo"),
+         ("->", "AddRequest"),
+         ("<-", "result: 5"),
+     ];
+     for (line, (direction, content)) in lines.iter().zip(expected) {
+         assert!(
+             line.starts_with(&format!("{} {} ", peer, direction)),
+             "Unexpected line: {}",
+             line
+         );
+         assert!(line.contains(content), "Expected {:?} in {}", content, line);
+     }
+ }
+
+ /// Tee sink whose writes block until `release` is dropped.
+ struct StalledSink {
+     release: std::sync::mpsc::Receiver<()>,
+ }
+
+ impl Write for StalledSink {
+     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+         let _ = self.release.recv();
+         Ok(buf.len())
+     }
+
+     fn flush(&mut self) -> std::io::Result<()> {
+         Ok(())
+     }
+ }
+
+ /// Test case for a blocked tee sink neither stalling the handlers nor buffering without bound.
+ #[test]
+ fn test_stalled_tee_does_not_block_clients() {
+     let (release, stalled) = std::sync::mpsc::channel();
+     with_configured_server(|server| server.with_tee(StalledSink { release: stalled }), |_, port| {
+         // Far more lines than the tee queue holds; the surplus is dropped instead of waited for
+         let (done, finished) = std::sync::mpsc::channel();
+         thread::spawn(move || {
+             let mut client = connect_client(port);
+             let result = (0..1500).try_for_each(|i| client.echo(&i.to_string()).map(drop));
+             let _ = done.send(result);
+         });
+         match finished.recv_timeout(Duration::from_secs(20)) {
+             Ok(result) => assert!(result.is_ok(), "Echo failed: {:?}", result),
+             Err(_) => panic!("The client was held up by the stalled tee sink"),
+         }
+         drop(release);
+     });
+ }
+
+ /// Test case for a response still arriving when the client disconnects right after sending.
+ #[test]
+ fn test_disconnect_graceful_receives_pending_response() {
+     with_configured_server(
+         |server| server.with_echo_delay(Duration::from_millis(200)),
+         |_, port| {
+             let mut client = connect_client(port);
+             let echo = client_message::Message::EchoMessage(EchoMessage {
+                 content: "last words".to_string(),
+             });
+             assert!(client.send(echo).is_ok(), "Failed to send message");
+
+             let responses = client
+                 .disconnect_graceful(Duration::from_secs(5))
+                 .expect("Graceful disconnect failed");
+             assert!(!client.is_connected(), "Client should be disconnected");
+             match responses.as_slice() {
+                 [ServerMessage {
+                     message: Some(server_message::Message::EchoMessage(echo)),
+                     ..
+                 }] => {
+                     assert_eq!(echo.content, "last words");
+                 }
+                 other => panic!("Expected exactly the echo response, got {:?}", other),
+             }
+         },
+     );
+ }
+
+ /// Test case for the message size statistics reflecting the frames a client sent.
+ #[test]
+ fn test_message_size_stats_track_frame_lengths() {
+     with_running_server(|server, port| {
+         assert!(
+             server.message_size_stats().is_none(),
+             "No frame has been received yet"
+         );
+
+         let mut client = client::Client::new("localhost", port.into(), 5000);
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+         let contents = ["a".repeat(10), "b".repeat(1000), "c".repeat(100_000)];
+         for content in &contents {
+             assert_eq!(&client.echo(content).expect("Echo failed"), content);
+         }
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+
+         let frame_len = |content: &str| {
+             let echo = client_message::Message::EchoMessage(EchoMessage {
+                 content: content.to_string(),
+             });
+             ClientMessage {
+                 message: Some(echo),
+                 ..Default::default()
+             }
+             .encoded_len()
+         };
+         let stats = server.message_size_stats().expect("Frames were received");
+         assert_eq!(stats.count, 4, "Hello plus three echoes");
+         assert!(
+             stats.min <= frame_len(&contents[0]),
+             "Smallest frame is at most the short echo"
+         );
+         assert!(
+             stats.max >= frame_len(&contents[2]),
+             "Largest frame is at least the long echo"
+         );
+         assert!(
+             stats.max < frame_len(&contents[2]) + 32,
+             "Largest frame is the long echo"
+         );
+         assert_eq!(stats.p99, stats.max);
+         assert!(stats.mean > stats.min as f64 && stats.mean < stats.max as f64);
+     });
+ }
+
+ /// Number of large echoes a slow client sends, enough to fill the socket buffers.
+ const SLOW_CLIENT_ECHOES: usize = 80;
+
+ /*
+  * \brief Connects a client that sends large echoes from a background thread.
+  *
+  * The echoes total about 20 MiB, more than the socket buffers of both ends hold, so
+  * the server blocks writing responses as long as nobody reads them.
+  *
+  * \param port The server port.
+  * \return The receiving half and the sending thread, which may fail once the server
+  *         closes the connection.
+  */
+ fn connect_slow_client(port: u16) -> (client::Receiver, JoinHandle<std::io::Result<()>>) {
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+     let (mut sender, receiver) = client.split().expect("Failed to split the client");
+     let flood = thread::spawn(move || {
+         for _ in 0..SLOW_CLIENT_ECHOES {
+             sender.send(client_message::Message::EchoMessage(EchoMessage {
+                 content: "s".repeat(256 * 1024),
+             }))?;
+         }
+         Ok(())
+     });
+     (receiver, flood)
+ }
+
+ /// Test case for the disconnect policy closing the connection of a client that does not read.
+ #[test]
+ fn test_slow_client_disconnected() {
+     with_configured_server(
+         |server| {
+             server
+                 .with_slow_client_policy(Duration::from_millis(100), SlowClientPolicy::Disconnect)
+         },
+         |_, port| {
+             let (mut receiver, flood) = connect_slow_client(port);
+             thread::sleep(Duration::from_secs(1));
+
+             let mut received = 0;
+             while receiver.receive().is_ok() {
+                 received += 1;
+             }
+             assert!(
+                 received < SLOW_CLIENT_ECHOES,
+                 "The slow client should have been disconnected"
+             );
+             let _ = flood.join().expect("Sending thread panicked");
+         },
+     );
+ }
+
+ /// Test case for the throttle policy keeping a slow client connected until it catches up.
+ #[test]
+ fn test_slow_client_throttled() {
+     log_capture::install();
+     with_configured_server(
+         |server| {
+             server
+                 .with_handler_thread_name("slow-throttled")
+                 .with_slow_client_policy(
+                     Duration::from_millis(100),
+                     SlowClientPolicy::Throttle(Duration::from_millis(200)),
+                 )
+         },
+         |_, port| {
+             let (mut receiver, flood) = connect_slow_client(port);
+             thread::sleep(Duration::from_secs(1));
+
+             for i in 0..SLOW_CLIENT_ECHOES {
+                 let response = receiver
+                     .receive()
+                     .unwrap_or_else(|e| panic!("Response {} missing: {}", i, e));
+                 assert!(
+                     matches!(
+                         response.message,
+                         Some(server_message::Message::EchoMessage(_))
+                     ),
+                     "Expected an echo"
+                 );
+             }
+             flood
+                 .join()
+                 .expect("Sending thread panicked")
+                 .expect("Failed to send the echoes");
+             assert!(
+                 log_capture::records_from("slow-throttled")
+                     .iter()
+                     .any(|record| record.message.starts_with("Slow client")),
+                 "The slow client should have been logged"
+             );
+         },
+     );
+ }
+
+ /*
+  * \brief Sends an echo carrying the unknown field 100 to a server with the given decoding mode.
+  *
+  * \param strict Whether the server rejects unknown fields.
+  * \return The server's answer to the echo.
+  */
+ fn echo_with_unknown_field(strict: bool) -> ServerMessage {
+     with_configured_server(
+         |server| server.with_strict_decoding(strict),
+         |_, port| {
+             let mut client = client::Client::new("localhost", port.into(), 5000);
+             assert!(client.connect().is_ok(), "Failed to connect to the server");
+             let echo = client_message::Message::EchoMessage(EchoMessage {
+                 content: "extra".to_string(),
+             });
+             let mut payload = ClientMessage {
+                 message: Some(echo),
+                 ..Default::default()
+             }
+             .encode_to_vec();
+             payload.extend_from_slice(&UNKNOWN_VARIANT);
+             assert!(
+                 client.send_raw(&payload).is_ok(),
+                 "Failed to send raw bytes"
+             );
+             let response = client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response");
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+             response
+         },
+     )
+ }
+
+ /// Test case for strict decoding rejecting a request with an unknown field.
+ #[test]
+ fn test_strict_decoding_rejects_unknown_fields() {
+     let response = echo_with_unknown_field(true);
+     assert_eq!(response.as_error().map(|error| error.code()), Some(ErrorCode::MalformedMessage));
+ }
+
+ /// Test case for strict decoding judging field numbers, not how canonical the encoding is.
+ #[test]
+ fn test_strict_decoding_checks_field_numbers() {
+     with_configured_server(
+         |server| server.with_strict_decoding(true),
+         |_, port| {
+             let mut client = connect_client(port);
+
+             // A ping followed by an explicit `dry_run = false`, which a canonical encoding omits
+             assert!(
+                 client.send_raw(&[0x1a, 0x00, 0x70, 0x00]).is_ok(),
+                 "Failed to send raw bytes"
+             );
+             match client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response")
+                 .message
+             {
+                 Some(server_message::Message::PongResponse(_)) => {}
+                 other => panic!("Expected PongResponse, but received {:?}", other),
+             }
+
+             // An echo whose nested message carries the undefined field 2
+             assert!(
+                 client
+                     .send_raw(&[0x0a, 0x05, 0x0a, 0x01, b'x', 0x10, 0x01])
+                     .is_ok(),
+                 "Failed to send raw bytes"
+             );
+             let response = client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response");
+             assert_eq!(
+                 response.as_error().map(|error| error.code()),
+                 Some(ErrorCode::MalformedMessage)
+             );
+
+             // The same field inside a batch operation is found too
+             let operation = [0x0a, 0x05, 0x0a, 0x01, b'x', 0x10, 0x01];
+             let mut batch = vec![0x42, operation.len() as u8 + 2, 0x0a, operation.len() as u8];
+             batch.extend_from_slice(&operation);
+             assert!(client.send_raw(&batch).is_ok(), "Failed to send raw bytes");
+             let response = client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response");
+             assert_eq!(
+                 response.as_error().map(|error| error.code()),
+                 Some(ErrorCode::MalformedMessage)
+             );
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// Test case for lenient decoding, the default, ignoring an unknown field.
+ #[test]
+ fn test_lenient_decoding_ignores_unknown_fields() {
+     match echo_with_unknown_field(false).message {
+         Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, "extra"),
+         other => panic!("Expected EchoMessage, but received {:?}", other),
+     }
+ }
+
+ /// Test case for each metrics snapshot covering only the activity since the previous one.
+ #[test]
+ fn test_take_metrics_reports_deltas() {
+     with_running_server(|server, port| {
+         let mut client = client::Client::builder()
+             .port(port.into())
+             .read_timeout(Duration::from_secs(5))
+             .tenant("scraped")
+             .build();
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+         assert_eq!(client.echo("first").expect("Echo failed"), "first");
+         assert_eq!(client.echo("second").expect("Echo failed"), "second");
+         // Requests are counted just after their response is written
+         thread::sleep(Duration::from_millis(50));
+         let first = server.take_metrics();
+
+         assert_eq!(client.echo("third").expect("Echo failed"), "third");
+         thread::sleep(Duration::from_millis(50));
+         let second = server.take_metrics();
+
+         assert_eq!(
+             first.requests_handled, 3,
+             "Hello and two echoes: {:?}",
+             first
+         );
+         assert_eq!(first.tenant_messages.get("scraped"), Some(&2));
+         assert_eq!(
+             second.requests_handled, 1,
+             "Only the third echo: {:?}",
+             second
+         );
+         assert_eq!(second.tenant_messages.get("scraped"), Some(&1));
+         assert!(second.bytes_read > 0 && second.bytes_read < first.bytes_read);
+         assert!(second.bytes_written > 0 && second.bytes_written < first.bytes_written);
+         assert_eq!(
+             server.tenant_metrics().get("scraped"),
+             Some(&3),
+             "Totals are not reset"
+         );
+         assert_eq!(
+             server.take_metrics(),
+             ServerMetrics::default(),
+             "Nothing happened since the last snapshot"
+         );
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for event-driven accepting taking a burst of connections without delay.
+ #[test]
+ fn test_event_driven_accept_takes_burst() {
+     with_configured_server(|server| server.with_event_driven_accept(true), |server, port| {
+         // Let the accept loop go idle, which is where the sleeping loop loses time
+         thread::sleep(Duration::from_millis(200));
+
+         let started = Instant::now();
+         let mut clients = Vec::new();
+         for _ in 0..20 {
+             let client = connect_client(port);
+             clients.push(client);
+         }
+         let elapsed = started.elapsed();
+
+         // Each handshake needs the connection accepted; sleeping 100 ms per idle poll would take
+         // seconds
+         assert!(elapsed < Duration::from_secs(1), "Accepting 20 clients took {:?}", elapsed);
+         assert_eq!(server.active_clients(), 20);
+
+         for client in &mut clients {
+             assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+         }
+     });
+ }
+
+ /// Test case for `localhost` reaching servers bound to the IPv4 or the IPv6 loopback address.
+ #[test]
+ fn test_localhost_reaches_both_loopback_families() {
+     for loopback in ["127.0.0.1", "[::1]"] {
+         let port = match TcpListener::bind(format!("{}:0", loopback)) {
+             Ok(listener) => listener
+                 .local_addr()
+                 .expect("Listener has no address")
+                 .port(),
+             Err(e) => {
+                 println!(
+                     "Skipping {}, the loopback address is unavailable: {}",
+                     loopback, e
+                 );
+                 continue;
+             }
+         };
+         let server = Arc::new(
+             Server::new(&format!("{}:{}", loopback, port), 10).expect("Failed to create server"),
+         );
+         let _running = RunningServer {
+             handle: Some(setup_server_thread(server.clone())),
+             server: server.clone(),
+         };
+         server
+             .wait_ready(Duration::from_secs(5))
+             .expect("Server did not become ready");
+
+         // With a connect timeout every resolved address is tried as well
+         let mut client = client::Client::builder()
+             .host("localhost")
+             .port(port.into())
+             .connect_timeout(Duration::from_secs(1))
+             .build();
+         assert!(
+             client.connect().is_ok(),
+             "Failed to reach the server on {} through localhost",
+             loopback
+         );
+         assert_eq!(
+             client.echo("dual stack").expect("Echo failed"),
+             "dual stack"
+         );
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     }
+ }
+
+ /// Test case for `request_expect` returning matching responses and reporting a mismatch.
+ #[test]
+ fn test_request_expect_checks_variant() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "expected".to_string(),
+         });
+         let response = client
+             .request_expect::<EchoMessage>(echo)
+             .expect("Echo failed");
+         assert_eq!(response.content, "expected");
+
+         let add = client_message::Message::AddRequest(AddRequest { a: 40, b: 2 });
+         let response = client
+             .request_expect::<AddResponse>(add)
+             .expect("Addition failed");
+         assert_eq!(response.result, 42);
+
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "not a sum".to_string(),
+         });
+         let error = client
+             .request_expect::<AddResponse>(echo)
+             .expect_err("An echo is not an AddResponse");
+         assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+         let mismatch = error
+             .get_ref()
+             .and_then(|inner| inner.downcast_ref::<client::UnexpectedResponse>())
+             .expect("The error should carry the mismatch");
+         assert_eq!(mismatch.expected, "AddResponse");
+         assert!(matches!(
+             mismatch.received,
+             server_message::Message::EchoMessage(_)
+         ));
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for dropping the server closing the connections of its clients.
+ #[test]
+ fn test_dropped_server_disconnects_clients() {
+     let (mut running, port) = start_server(|server| server);
+
+     let mut client = client::Client::new("localhost", port.into(), 30_000);
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+
+     running.server.stop();
+     running.join();
+     drop(running);
+
+     let started = Instant::now();
+     let error = client
+         .receive_with_retry(1)
+         .expect_err("The server is gone");
+     assert_eq!(
+         error.kind(),
+         std::io::ErrorKind::ConnectionAborted,
+         "Unexpected error: {}",
+         error
+     );
+     assert!(
+         started.elapsed() < Duration::from_secs(5),
+         "Noticing the drop took {:?}",
+         started.elapsed()
+     );
+ }
+
+ /// Test case for the per-connection frame limit bounding the queue of a pipelining client.
+ #[test]
+ fn test_max_queued_frames_bounds_pipelining() {
+     const LIMIT: usize = 4;
+     const REQUESTS: usize = 200;
+     with_configured_server(
+         |server| {
+             server
+                 .with_max_queued_frames(LIMIT)
+                 .with_echo_delay(Duration::from_millis(2))
+         },
+         |server, port| {
+             let client = connect_client(port);
+             let (mut sender, mut receiver) = client.split().expect("Failed to split the client");
+             let echo = || {
+                 client_message::Message::EchoMessage(EchoMessage {
+                     content: "p".repeat(100),
+                 })
+             };
+             let frame_size = ClientMessage {
+                 message: Some(echo()),
+                 ..Default::default()
+             }
+             .encoded_len();
+             let pipeline = thread::spawn(move || {
+                 for _ in 0..REQUESTS {
+                     sender.send(echo()).expect("Failed to send message");
+                 }
+             });
+
+             let mut most_queued = 0;
+             for i in 0..REQUESTS {
+                 most_queued = most_queued.max(server.queued_bytes());
+                 let response = receiver
+                     .receive()
+                     .unwrap_or_else(|e| panic!("Response {} missing: {}", i, e));
+                 assert!(
+                     matches!(
+                         response.message,
+                         Some(server_message::Message::EchoMessage(_))
+                     ),
+                     "Expected an echo"
+                 );
+             }
+             pipeline.join().expect("Sending thread panicked");
+
+             assert!(
+                 most_queued <= LIMIT * frame_size,
+                 "{} bytes were queued, over {} frames",
+                 most_queued,
+                 LIMIT
+             );
+         },
+     );
+ }
+
+ /// Test case for a dry-run request getting the real response while the handler state is kept.
+ #[test]
+ fn test_dry_run_leaves_handler_state_unchanged() {
+     let configure = |server: Server| {
+         server.with_context_handler(
+             || 0i64,
+             |total, message, dry_run| match message {
+                 client_message::Message::AddRequest(add) => {
+                     let sum = *total + add.a + add.b;
+                     if !dry_run {
+                         *total = sum;
+                     }
+                     Some(server_message::Message::AddResponse(AddResponse {
+                         result: sum as i32,
+                         result64: sum,
+                     }))
+                 }
+                 _ => None,
+             },
+         )
+     };
+     with_configured_server(configure, |_, port| {
+         let mut client = client::Client::new("localhost", port.into(), 5000);
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+         assert_eq!(client.add(1, 2).expect("Addition failed"), 3);
+
+         let dry_run = ClientMessage {
+             message: Some(client_message::Message::AddRequest(AddRequest {
+                 a: 10,
+                 b: 0,
+             })),
+             dry_run: true,
+             ..Default::default()
+         };
+         assert!(
+             client.send_raw(&dry_run.encode_to_vec()).is_ok(),
+             "Failed to send raw bytes"
+         );
+         match client
+             .receive_with_retry(1)
+             .expect("Failed to receive response")
+             .message
+         {
+             Some(server_message::Message::AddResponse(sum)) => {
+                 assert_eq!(sum.result, 13, "Dry run sees the running total")
+             }
+             other => panic!("Expected AddResponse, but received {:?}", other),
+         }
+
+         // The dry run's 10 was not kept
+         assert_eq!(client.add(0, 1).expect("Addition failed"), 4);
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for `health` following the server through pause, resume and a graceful stop.
+ #[test]
+ fn test_health_transitions() {
+     let (server, port) =
+         create_server_with(|server| server.with_echo_delay(Duration::from_millis(500)))
+             .expect("Failed to create server");
+     assert_eq!(server.health(), Health::Stopped, "Not started yet");
+     let mut running = RunningServer {
+         handle: Some(setup_server_thread(server.clone())),
+         server: server.clone(),
+     };
+     server
+         .wait_ready(Duration::from_secs(5))
+         .expect("Server did not become ready");
+     assert_eq!(server.health(), Health::Running);
+
+     server.pause();
+     assert_eq!(server.health(), Health::Paused);
+     server.resume();
+     assert_eq!(server.health(), Health::Running);
+
+     // A slow echo keeps the drain busy long enough to observe it
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+     let echo = client_message::Message::EchoMessage(EchoMessage {
+         content: "slow".to_string(),
+     });
+     assert!(client.send(echo).is_ok(), "Failed to send message");
+     thread::sleep(Duration::from_millis(50));
+
+     let drain = thread::spawn({
+         let server = server.clone();
+         move || server.stop_graceful()
+     });
+     let deadline = Instant::now() + Duration::from_secs(5);
+     while server.health() != Health::Draining && Instant::now() < deadline {
+         thread::sleep(Duration::from_millis(5));
+     }
+     assert_eq!(server.health(), Health::Draining);
+
+     drain.join().expect("Drain panicked");
+     running.join();
+     assert_eq!(server.health(), Health::Stopped);
+ }
+
+ /// Test case for `stop` releasing the port of a server whose `run` was never called.
+ #[test]
+ fn test_stop_without_run_releases_port() {
+     let (server, port) = create_server().expect("Failed to create server");
+     assert!(
+         TcpListener::bind(format!("localhost:{}", port)).is_err(),
+         "The server should hold the port"
+     );
+
+     server.stop();
+     assert!(
+         TcpListener::bind(format!("localhost:{}", port)).is_ok(),
+         "The port should be free after stop"
+     );
+     assert!(
+         server.run().is_ok(),
+         "Running a stopped server returns at once"
+     );
+ }
+
+ /// Test case for `ping_until_ready` waiting until the server answers pings.
+ #[test]
+ fn test_ping_until_ready_waits_for_pong() {
+     const WARM_UP: Duration = Duration::from_millis(300);
+     let started = Instant::now();
+     with_configured_server(
+         |server| {
+             // The handler refuses pings until it has warmed up
+             server.with_handler(move |message| match message {
+                 client_message::Message::PingRequest(_) if started.elapsed() < WARM_UP => {
+                     Some(server_message::Message::ErrorResponse(ErrorResponse {
+                         message: "warming up".to_string(),
+                         code: ErrorCode::Rejected.into(),
+                     }))
+                 }
+                 _ => None,
+             })
+         },
+         |_, port| {
+             let mut client = client::Client::new("localhost", port.into(), 5000);
+             client
+                 .ping_until_ready(Duration::from_secs(5))
+                 .expect("Server never became ready");
+             assert!(
+                 started.elapsed() >= WARM_UP,
+                 "Ready after {:?}, before the warm-up ended",
+                 started.elapsed()
+             );
+             assert!(
+                 client.is_connected(),
+                 "The probe leaves the connection open"
+             );
+             assert_eq!(client.echo("ready").expect("Echo failed"), "ready");
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// Test case for `ping_until_ready` giving up when nothing listens.
+ #[test]
+ fn test_ping_until_ready_times_out() {
+     let port = TcpListener::bind("localhost:0")
+         .expect("Failed to bind")
+         .local_addr()
+         .expect("No address")
+         .port();
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     let error = client
+         .ping_until_ready(Duration::from_millis(200))
+         .expect_err("Nothing is listening");
+     assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+ }
+
+ /// Test case for a broadcast reaching the control channel of a session, not its data connection.
+ #[test]
+ fn test_broadcast_arrives_on_control_channel_only() {
+     with_configured_server(
+         |server| server.with_control_channels(true),
+         |server, port| {
+             let mut data = connect_client(port);
+             assert!(
+                 data.session_id().is_some(),
+                 "The handshake should open a session"
+             );
+             let mut control = data
+                 .connect_control()
+                 .expect("Failed to open the control channel");
+             assert_eq!(control.session_id(), data.session_id());
+
+             assert_eq!(
+                 server.broadcast("maintenance at noon"),
+                 1,
+                 "One control channel is open"
+             );
+             let pushed = control
+                 .collect_responses(1, Duration::from_secs(5))
+                 .expect("Failed to read the control channel");
+             let expected = server_message::Message::Broadcast(Broadcast {
+                 content: "maintenance at noon".to_string(),
+             });
+             assert_eq!(
+                 pushed
+                     .into_iter()
+                     .map(|message| message.message)
+                     .collect::<Vec<_>>(),
+                 vec![Some(expected)]
+             );
+
+             let stray = data
+                 .collect_responses(1, Duration::from_millis(300))
+                 .expect("Failed to read the data connection");
+             assert!(stray.is_empty(), "The data connection received {:?}", stray);
+             assert_eq!(
+                 data.echo("still answered").expect("Echo failed"),
+                 "still answered"
+             );
+
+             assert!(
+                 data.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// Number of concurrent clients in the stress test unless `STRESS_CLIENTS` says otherwise.
+ const DEFAULT_STRESS_CLIENTS: usize = 1000;
+
+ /// Test case for many clients connected at once, each getting its echo answered.
+ #[test]
+ fn test_many_concurrent_clients() {
+     // Lower the count on machines with tight thread or descriptor limits
+     let count = std::env::var("STRESS_CLIENTS")
+         .ok()
+         .and_then(|count| count.parse().ok())
+         .unwrap_or(DEFAULT_STRESS_CLIENTS);
+     with_running_server(|_, port| {
+         // Every client stays connected until all have been answered
+         let all_answered = Arc::new(Barrier::new(count));
+         let clients: Vec<_> = (0..count)
+             .map(|i| {
+                 let all_answered = all_answered.clone();
+                 thread::Builder::new()
+                     .stack_size(64 * 1024)
+                     .spawn(move || {
+                         let mut client = client::Client::new("localhost", port.into(), 30_000);
+                         let content = format!("client {}", i);
+                         let result = client
+                             .connect()
+                             .and_then(|()| client.echo(&content))
+                             .map(|echo| echo == content);
+                         all_answered.wait();
+                         result
+                     })
+                     .expect("Failed to spawn client thread")
+             })
+             .collect();
+
+         let failures: Vec<String> = clients
+             .into_iter()
+             .enumerate()
+             .filter_map(
+                 |(i, client)| match client.join().expect("Client thread panicked") {
+                     Ok(true) => None,
+                     Ok(false) => Some(format!("client {}: wrong echo", i)),
+                     Err(e) => Some(format!("client {}: {}", i, e)),
+                 },
+             )
+             .collect();
+         assert!(
+             failures.is_empty(),
+             "{} of {} clients failed: {:?}",
+             failures.len(),
+             count,
+             &failures[..failures.len().min(5)]
+         );
+     });
+ }
+
+ /// Test case for `measure_throughput` reporting the rates of a pipelined echo run.
+ #[test]
+ fn test_measure_throughput() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+         // 500 KiB spans several windows, more than the socket buffers hold at once
+         let stats = client
+             .measure_throughput(500, 1024)
+             .expect("Throughput run failed");
+         assert_eq!(stats.messages, 500);
+         assert_eq!(stats.bytes, 500 * 1024);
+         assert!(stats.elapsed > Duration::ZERO, "No time was measured");
+         assert!(
+             stats.messages_per_sec > 0.0 && stats.bytes_per_sec > 0.0,
+             "Rates must be positive: {:?}",
+             stats
+         );
+         assert_eq!(
+             client.echo("after").expect("Echo failed"),
+             "after",
+             "No response may be left over"
+         );
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Tee sink that panics when asked to write a line mentioning `trigger`.
+ #[derive(Clone)]
+ struct PanickingSink {
+     inner: SharedSink,
+     trigger: &'static str,
+ }
+
+ impl Write for PanickingSink {
+     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+         if String::from_utf8_lossy(buf).contains(self.trigger) {
+             panic!("Sink refuses {:?}", self.trigger);
+         }
+         self.inner.write(buf)
+     }
+
+     fn flush(&mut self) -> std::io::Result<()> {
+         Ok(())
+     }
+ }
+
+ /// Test case for a panicking handler and tee sink leaving the server usable.
+ #[test]
+ fn test_handler_panic_leaves_server_usable() {
+     let sink = SharedSink::default();
+     let panicking = PanickingSink {
+         inner: sink.clone(),
+         trigger: "poison pill",
+     };
+     let configure = |server: Server| {
+         server
+             .with_tee(panicking)
+             .with_handler(|message| match message {
+                 client_message::Message::EchoMessage(echo) if echo.content == "poison pill" => {
+                     panic!("Handler refuses the pill")
+                 }
+                 _ => None,
+             })
+     };
+     with_configured_server(configure, |server, port| {
+         // Both the handler and the tee writer panic on the pill
+         let mut victim = connect_client(port);
+         assert!(
+             victim.echo("poison pill").is_err(),
+             "The panicking handler cannot answer"
+         );
+         let deadline = Instant::now() + Duration::from_secs(5);
+         while server.active_clients() > 0 && Instant::now() < deadline {
+             thread::sleep(Duration::from_millis(10));
+         }
+         assert_eq!(
+             server.active_clients(),
+             0,
+             "The panicking handler should release its slot"
+         );
+
+         // Other handlers still use the tee and the server's counters
+         let mut client = connect_client(port);
+         assert_eq!(
+             client.echo("still alive").expect("Echo failed"),
+             "still alive"
+         );
+         assert!(
+             server.take_metrics().requests_handled >= 2,
+             "Requests should still be counted"
+         );
+         let peer = client.local_addr().expect("Client has no address");
+         let deadline = Instant::now() + Duration::from_secs(5);
+         let teed = || {
+             let captured = String::from_utf8(sink.0.lock().unwrap().clone())
+                 .expect("Tee output is not UTF-8");
+             captured.lines().any(|line| {
+                 line.starts_with(&format!("{} <-", peer)) && line.contains("still alive")
+             })
+         };
+         while !teed() && Instant::now() < deadline {
+             thread::sleep(Duration::from_millis(10));
+         }
+         assert!(teed(), "The tee stopped working after the panic");
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Sends an echo of empty content to a server using `policy` and returns the response.
+ fn echo_empty(policy: EmptyEchoPolicy) -> Option<server_message::Message> {
+     with_configured_server(|server| server.with_empty_echo_policy(policy), |_, port| {
+         let mut client = client::Client::new("localhost", port.into(), 5000);
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+         let echo = client_message::Message::EchoMessage(EchoMessage { content: String::new() });
+         assert!(client.send(echo).is_ok(), "Failed to send message");
+         let response = client.receive_with_retry(1).expect("Failed to receive response").message;
+
+         assert!(client.disconnect().is_ok(), "Failed to disconnect from the server");
+         response
+     })
+ }
+
+ /// Test case for the default policy echoing empty content as it is.
+ #[test]
+ fn test_empty_echo_policy_echo() {
+     match echo_empty(EmptyEchoPolicy::Echo) {
+         Some(server_message::Message::EchoMessage(echo)) => assert_eq!(echo.content, ""),
+         other => panic!("Expected EchoMessage, but received {:?}", other),
+     }
+ }
+
+ /// Test case for rejecting an echo of empty content.
+ #[test]
+ fn test_empty_echo_policy_reject() {
+     match echo_empty(EmptyEchoPolicy::Reject) {
+         Some(server_message::Message::ErrorResponse(error)) => {
+             assert_eq!(error.code(), ErrorCode::Rejected, "Unexpected error: {}", error.message)
+         }
+         other => panic!("Expected ErrorResponse, but received {:?}", other),
+     }
+ }
+
+ /// Test case for a draining server redirecting an auto-reconnecting client to its replacement.
+ #[test]
+ fn test_redirect_to_replacement_server() {
+     let (replacement, replacement_port) = start_server(|server| server);
+     let (mut running, port) =
+         start_server(|server| server.with_redirect("localhost", replacement_port));
+
+     let mut client = client::Client::builder()
+         .port(port.into())
+         .read_timeout(Duration::from_secs(5))
+         .auto_reconnect(true)
+         .build();
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+     assert_eq!(client.echo("before").expect("Echo failed"), "before");
+
+     running.server.stop_graceful();
+     running.join();
+
+     // The next request reads the redirect, moves over and is resent to the replacement
+     assert_eq!(
+         client
+             .echo("after")
+             .expect("Echo after the redirect failed"),
+         "after"
+     );
+     assert_eq!(
+         replacement.server.active_clients(),
+         1,
+         "The client should now be served by the replacement"
+     );
+
+     assert!(
+         client.disconnect().is_ok(),
+         "Failed to disconnect from the server"
+     );
+ }
+
+ /// Test case for a write timeout ending a send the server never reads.
+ #[test]
+ fn test_send_times_out_when_server_stops_reading() {
+     // A fake server that completes the handshake, then never reads again
+     let listener = TcpListener::bind("localhost:0").expect("Failed to bind");
+     let port = listener.local_addr().expect("No address").port();
+     let (done, finished) = std::sync::mpsc::channel::<()>();
+     let fake_server = thread::spawn(move || {
+         let (mut stream, _) = listener.accept().expect("Failed to accept");
+         frame::read_frame(&mut stream).expect("No Hello received");
+         let hello = ServerMessage {
+             message: Some(server_message::Message::HelloResponse(HelloResponse {
+                 protocol_version: PROTOCOL_VERSION,
+                 ..Default::default()
+             })),
+             ..Default::default()
+         };
+         frame::write_frame(&mut stream, &hello.encode_to_vec()).expect("Failed to answer Hello");
+         let _ = finished.recv_timeout(Duration::from_secs(30));
+     });
+
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     client
+         .set_write_timeout(Some(Duration::from_millis(200)))
+         .expect("Failed to set the write timeout");
+     assert!(
+         client.connect().is_ok(),
+         "Failed to connect to the fake server"
+     );
+
+     // Keep sending until the socket buffers are full and a write stalls
+     let payload = client_message::Message::BinaryEcho(BinaryEcho {
+         data: vec![0; 512 * 1024].into(),
+     });
+     let started = Instant::now();
+     let error = (0..256)
+         .find_map(|_| client.send(payload.clone()).err())
+         .expect("Every send succeeded although nothing reads them");
+     assert_eq!(
+         error.kind(),
+         std::io::ErrorKind::TimedOut,
+         "Unexpected error: {}",
+         error
+     );
+     assert!(
+         started.elapsed() < Duration::from_secs(10),
+         "The send stalled for {:?}",
+         started.elapsed()
+     );
+
+     drop(done);
+     fake_server.join().expect("Fake server panicked");
+ }
+
+ /// Test case for shutdown hooks running once, in registration order, after the drain.
+ #[test]
+ fn test_shutdown_hooks_run_once_in_order() {
+     let calls = Arc::new(Mutex::new(Vec::new()));
+     let configure = |server: Server| {
+         let (first, second) = (calls.clone(), calls.clone());
+         server
+             .on_shutdown(move || first.lock().unwrap().push("first"))
+             .on_shutdown(move || second.lock().unwrap().push("second"))
+     };
+     with_configured_server(configure, |server, port| {
+         let mut client = client::Client::new("localhost", port.into(), 5000);
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+         assert_eq!(client.echo("before shutdown").expect("Echo failed"), "before shutdown");
+         assert!(calls.lock().unwrap().is_empty(), "Hooks must not run before the shutdown");
+
+         server.stop_graceful();
+         assert_eq!(*calls.lock().unwrap(), ["first", "second"]);
+         assert_eq!(server.active_clients(), 0, "Hooks run after every connection is drained");
+         server.stop_graceful();
+         assert_eq!(calls.lock().unwrap().len(), 2, "Hooks must run only once");
+     });
+ }
+
+ /// Test case for the `ServerMessage` accessors picking out the payload of one variant.
+ #[test]
+ fn test_server_message_accessors() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "accessed".to_string(),
+         });
+         assert!(client.send(echo).is_ok(), "Failed to send message");
+         let response = client
+             .receive_with_retry(1)
+             .expect("Failed to receive response");
+         assert_eq!(
+             response.as_echo().map(|echo| echo.content.as_str()),
+             Some("accessed")
+         );
+         assert!(
+             response.as_add_response().is_none() && response.as_error().is_none(),
+             "Only one variant matches"
+         );
+
+         assert!(
+             client
+                 .send(client_message::Message::AddRequest(AddRequest {
+                     a: 2,
+                     b: 40
+                 }))
+                 .is_ok(),
+             "Failed to send message"
+         );
+         let response = client
+             .receive_with_retry(1)
+             .expect("Failed to receive response");
+         assert_eq!(response.as_add_response().map(|add| add.result), Some(42));
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for a second concurrent `run` failing instead of sharing the listener.
+ #[test]
+ fn test_concurrent_run_is_refused() {
+     with_running_server(|server, port| {
+         let second = thread::spawn({
+             let server = server.clone();
+             move || server.run()
+         });
+         let error = second
+             .join()
+             .expect("Second run panicked")
+             .expect_err("The second run should be refused");
+         assert_eq!(error.kind(), std::io::ErrorKind::ResourceBusy);
+         assert!(
+             error
+                 .get_ref()
+                 .is_some_and(|inner| inner.is::<AlreadyRunning>()),
+             "Unexpected error: {}",
+             error
+         );
+
+         // The first run is unaffected
+         let mut client = connect_client(port);
+         assert_eq!(
+             client.echo("still running").expect("Echo failed"),
+             "still running"
+         );
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for a stopped server not accepting again when `run` is called once more.
+ #[test]
+ fn test_run_after_stop_does_not_accept() {
+     let (mut running, port) = start_server(|server| server);
+     running.server.stop();
+     running.join();
+
+     let started = Instant::now();
+     assert!(running.server.run().is_ok(), "run failed after stop");
+     assert!(
+         started.elapsed() < Duration::from_millis(100),
+         "run took {:?} to return",
+         started.elapsed()
+     );
+     assert!(
+         TcpStream::connect(("localhost", port)).is_err(),
+         "The listening socket was not released"
+     );
+ }
+
+ /// Echo request wrapper, as a user adding a message type would write it.
+ struct Shout(String);
+
+ impl client::Request for Shout {
+     fn into_message(self) -> client_message::Message {
+         client_message::Message::EchoMessage(EchoMessage { content: self.0.to_uppercase() })
+     }
+ }
+
+ /// Echo response wrapper holding only the content.
+ struct Echoed(String);
+
+ impl client::Response for Echoed {
+     const VARIANT: &'static str = "EchoMessage";
+
+     fn from_message(message: server_message::Message) -> Result<Self, server_message::Message> {
+         match message {
+             server_message::Message::EchoMessage(echo) => Ok(Echoed(echo.content)),
+             other => Err(other),
+         }
+     }
+ }
+
+ /// Test case for `request_typed` with wrappers implemented outside the client.
+ #[test]
+ fn test_request_typed() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+         let Echoed(content) = client
+             .request_typed(Shout("typed".to_string()))
+             .expect("Typed request failed");
+         assert_eq!(content, "TYPED");
+         let sum: AddResponse = client
+             .request_typed(AddRequest { a: 20, b: 22 })
+             .expect("Typed request failed");
+         assert_eq!(sum.result, 42);
+         let error = client
+             .request_typed::<_, Echoed>(AddRequest { a: 1, b: 1 })
+             .err()
+             .expect("An AddResponse is no echo");
+         assert!(
+             error
+                 .get_ref()
+                 .is_some_and(|inner| inner.is::<client::UnexpectedResponse>()),
+             "Unexpected error: {}",
+             error
+         );
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for `echo_verified` accepting random payloads of many sizes.
+ #[test]
+ fn test_echo_verified_random_payloads() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+         // A fixed xorshift seed keeps failures reproducible
+         let mut state = 0x2545_f491_4f6c_dd1d_u64;
+         let mut next = move || {
+             state ^= state << 13;
+             state ^= state >> 7;
+             state ^= state << 17;
+             state
+         };
+         for size in [0, 1, 3, 255, 4096, 4097, 65_535, 300_000, 900_000] {
+             let data: Vec<u8> = (0..size).map(|_| next() as u8).collect();
+             if let Err(e) = client.echo_verified(&data) {
+                 panic!("Echo of {} random bytes failed verification: {}", size, e);
+             }
+         }
+         assert_eq!(
+             client.echo_verified(b"123456789").expect("Echo failed"),
+             0xcbf4_3926,
+             "CRC-32 check value"
+         );
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for the audit trail of accepted and rejected connections.
+ #[test]
+ fn test_connection_decisions_are_audited() {
+     log_capture::install();
+     let port = TcpListener::bind("localhost:0")
+         .expect("Failed to bind")
+         .local_addr()
+         .expect("No address")
+         .port();
+     let server = Server::new(&format!("localhost:{}", port), 2).expect("Failed to create server");
+     let server = Arc::new(
+         server
+             .with_handler_thread_name("audit-trail")
+             .with_handshake_timeout(Duration::from_millis(300))
+             .with_audit_log_level(log::Level::Warn),
+     );
+     // The accept loop logs on this thread, so its name must match the filter too
+     let handle = thread::Builder::new()
+         .name("audit-trail-accept".to_string())
+         .spawn({
+             let server = server.clone();
+             move || server.run().expect("Server failed")
+         })
+         .expect("Failed to spawn server thread");
+     server
+         .wait_ready(Duration::from_secs(5))
+         .expect("Server never became ready");
+
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+     let accepted = client.local_addr().expect("Client has no address");
+     // A second client takes the last slot but never sends its handshake
+     let mut silent = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+     let deadline = Instant::now() + Duration::from_secs(5);
+     while server.active_clients() < 2 && Instant::now() < deadline {
+         thread::sleep(Duration::from_millis(5));
+     }
+     let mut refused = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+     let (silent_peer, refused_peer) =
+         (silent.local_addr().unwrap(), refused.local_addr().unwrap());
+     for stream in [&mut refused, &mut silent] {
+         stream
+             .set_read_timeout(Some(Duration::from_secs(5)))
+             .unwrap();
+         assert_eq!(
+             stream.read(&mut [0; 1]).unwrap_or(0),
+             0,
+             "The server should close the connection"
+         );
+     }
+
+     // Control channels are off, so a handshake asking for one is refused
+     while server.active_clients() > 1 && Instant::now() < deadline {
+         thread::sleep(Duration::from_millis(5));
+     }
+     let mut control = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+     let control_peer = control.local_addr().unwrap();
+     let hello = client_message::Message::HelloRequest(HelloRequest {
+         control: true,
+         ..Default::default()
+     });
+     let request = ClientMessage {
+         message: Some(hello),
+         ..Default::default()
+     };
+     control
+         .write_all(&frame::encode_frame(&request.encode_to_vec()))
+         .expect("Failed to send handshake");
+     let payload = frame::read_frame(&mut control).expect("Failed to receive response");
+     let response = ServerMessage::decode(payload.as_slice()).expect("Failed to decode response");
+     assert!(
+         response.as_error().is_some(),
+         "Expected ErrorResponse, but received {:?}",
+         response
+     );
+
+     let decisions: Vec<_> = log_capture::records_from("audit-trail")
+         .into_iter()
+         .filter(|record| record.message.starts_with("decision="))
+         .collect();
+     let find = |decision: &str, peer: SocketAddr, reason: &str| {
+         decisions.iter().any(|record| {
+             record
+                 .message
+                 .starts_with(&format!("decision={} peer={} reason=", decision, peer))
+                 && record.message.contains(reason)
+         })
+     };
+     assert!(
+         find("accept", accepted, "new client"),
+         "Accept not audited: {:#?}",
+         decisions
+     );
+     assert!(
+         find("accept", silent_peer, "new client"),
+         "Accept not audited: {:#?}",
+         decisions
+     );
+     assert!(
+         find("reject", refused_peer, "2 clients already connected"),
+         "Reject not audited: {:#?}",
+         decisions
+     );
+     assert!(
+         find("reject", silent_peer, "no handshake within"),
+         "Handshake failure not audited: {:#?}",
+         decisions
+     );
+     assert!(
+         find("reject", control_peer, "control channels are not enabled"),
+         "Control refusal not audited: {:#?}",
+         decisions
+     );
+     assert!(
+         decisions
+             .iter()
+             .all(|record| record.level == log::Level::Warn),
+         "Audit lines use the configured level"
+     );
+
+     assert!(
+         client.disconnect().is_ok(),
+         "Failed to disconnect from the server"
+     );
+     server.stop();
+     assert!(
+         handle.join().is_ok(),
+         "Server thread panicked or failed to join"
+     );
+ }
+
+ /// Middleware tagging echo content on its way in and on its way out.
+ struct Marker(&'static str);
+
+ impl Middleware for Marker {
+     fn on_request(&self, message: &mut client_message::Message) {
+         if let client_message::Message::EchoMessage(echo) = message {
+             echo.content.push_str(&format!(" [in:{}]", self.0));
+         }
+     }
+
+     fn on_response(&self, response: &mut server_message::Message) {
+         if let server_message::Message::EchoMessage(echo) = response {
+             echo.content.push_str(&format!(" [out:{}]", self.0));
+         }
+     }
+ }
+
+ /// Test case for middleware layers rewriting an echo in registration order.
+ #[test]
+ fn test_middleware_chain_marks_echo() {
+     with_configured_server(
+         |server| {
+             server
+                 .with_middleware(Marker("outer"))
+                 .with_middleware(Marker("inner"))
+         },
+         |_, port| {
+             let mut client = connect_client(port);
+             let echo = client_message::Message::EchoMessage(EchoMessage {
+                 content: "hello".to_string(),
+             });
+             assert!(client.send(echo).is_ok(), "Failed to send message");
+             match client
+                 .receive_with_retry(1)
+                 .expect("Failed to receive response")
+                 .message
+             {
+                 Some(server_message::Message::EchoMessage(echo)) => {
+                     assert_eq!(
+                         echo.content,
+                         "hello [in:outer] [in:inner] [out:inner] [out:outer]"
+                     )
+                 }
+                 other => panic!("Expected EchoMessage, but received {:?}", other),
+             }
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// Test case for sending and receiving after an explicit disconnect.
+ #[test]
+ fn test_send_after_disconnect_is_not_connected() {
+     with_running_server(|_, port| {
+         let mut client = client::Client::builder()
+             .port(port.into())
+             .read_timeout(Duration::from_secs(5))
+             .auto_reconnect(true)
+             .build();
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "before".to_string(),
+         });
+         assert!(client.send(echo).is_ok(), "Failed to send message");
+         assert!(
+             client.receive_with_retry(1).is_ok(),
+             "Failed to receive response"
+         );
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+
+         // Auto-reconnect must not revive a connection closed on purpose
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "after".to_string(),
+         });
+         let error = client
+             .send(echo)
+             .expect_err("Send after disconnect should fail");
+         assert_eq!(error.kind(), std::io::ErrorKind::NotConnected);
+         let error = client
+             .receive_with_retry(1)
+             .expect_err("Receive after disconnect should fail");
+         assert_eq!(error.kind(), std::io::ErrorKind::NotConnected);
+         assert!(!client.is_connected());
+         assert!(
+             client.disconnect().is_ok(),
+             "A second disconnect should be harmless"
+         );
+
+         assert!(
+             client.connect().is_ok(),
+             "Failed to reconnect to the server"
+         );
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "again".to_string(),
+         });
+         assert!(
+             client.send(echo).is_ok(),
+             "Failed to send message after reconnecting"
+         );
+         assert!(
+             client.receive_with_retry(1).is_ok(),
+             "Failed to receive response after reconnecting"
+         );
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for a server accepting on a listener it did not bind itself.
+ #[test]
+ fn test_server_from_listener() {
+     let listener = TcpListener::bind("localhost:0").expect("Failed to bind listener");
+     let port = listener.local_addr().unwrap().port();
+     let server = Arc::new(Server::from_listener(listener, 10).expect("Failed to create server"));
+     let _running = RunningServer {
+         handle: Some(setup_server_thread(server.clone())),
+         server,
+     };
+
+     let mut client = client::Client::new("localhost", port.into(), 5000);
+     assert!(client.connect().is_ok(), "Failed to connect to the server");
+     let echo = client_message::Message::EchoMessage(EchoMessage {
+         content: "handed over".to_string(),
+     });
+     assert!(client.send(echo).is_ok(), "Failed to send message");
+     match client
+         .receive_with_retry(1)
+         .expect("Failed to receive response")
+         .message
+     {
+         Some(server_message::Message::EchoMessage(echo)) => {
+             assert_eq!(echo.content, "handed over")
+         }
+         other => panic!("Expected EchoMessage, but received {:?}", other),
+     }
+
+     assert!(
+         client.disconnect().is_ok(),
+         "Failed to disconnect from the server"
+     );
+ }
+
+ /// Test case for a batch whose failing operation does not fail the others.
+ #[test]
+ fn test_batch_returns_partial_results() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+         let operation = |message| ClientMessage {
+             message: Some(message),
+             ..Default::default()
+         };
+         let batch = BatchRequest {
+             operations: vec![
+                 operation(client_message::Message::AddRequest(AddRequest {
+                     a: 10,
+                     b: 20,
+                 })),
+                 operation(client_message::Message::DivideRequest(DivideRequest {
+                     a: 1,
+                     b: 0,
+                 })),
+                 operation(client_message::Message::DivideRequest(DivideRequest {
+                     a: 7,
+                     b: 2,
+                 })),
+             ],
+         };
+         let response: BatchResponse = client.request_typed(batch).expect("Failed to send batch");
+
+         assert_eq!(
+             response.results.len(),
+             3,
+             "Expected one result per operation: {:?}",
+             response
+         );
+         assert_eq!(
+             response.results[0].as_add_response(),
+             Some(&AddResponse {
+                 result: 30,
+                 result64: 30
+             })
+         );
+         let error = response.results[1]
+             .as_error()
+             .expect("Dividing by zero should fail");
+         assert_eq!(
+             error.code(),
+             ErrorCode::InvalidArgument,
+             "Unexpected error: {}",
+             error.message
+         );
+         assert_eq!(
+             response.results[2].as_divide_response(),
+             Some(&DivideResponse { result: 3 })
+         );
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for waiting for one message among pushed noise.
+ #[test]
+ fn test_wait_for_matching_message() {
+     with_configured_server(
+         |server| server.with_control_channels(true),
+         |server, port| {
+             let mut data = connect_client(port);
+             let mut control = data
+                 .connect_control()
+                 .expect("Failed to open the control channel");
+             for i in 0..3 {
+                 assert_eq!(
+                     server.broadcast(&format!("noise {}", i)),
+                     1,
+                     "One control channel is open"
+                 );
+             }
+             assert_eq!(server.broadcast("target"), 1, "One control channel is open");
+
+             let is_target = |message: &ServerMessage| {
+                 message
+                     .as_broadcast()
+                     .is_some_and(|broadcast| broadcast.content == "target")
+             };
+             let message = control
+                 .wait_for(is_target, Duration::from_secs(5))
+                 .expect("The target never arrived");
+             assert_eq!(message.as_broadcast().unwrap().content, "target");
+             let error = control
+                 .wait_for(is_target, Duration::from_millis(200))
+                 .expect_err("Nothing else was sent");
+             assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+
+             assert!(
+                 data.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// Test case for an async handler answering from a spawned thread through the send queue.
+ #[test]
+ fn test_async_handler_pushes_responses() {
+     let configure = |server: Server| {
+         server.with_async_handler(|message, responses| {
+             let client_message::Message::EchoMessage(echo) = message else {
+                 return false;
+             };
+             let (content, responses) = (echo.content.clone(), responses.clone());
+             thread::spawn(move || {
+                 thread::sleep(Duration::from_millis(50));
+                 for part in ["first", "second"] {
+                     let echo = EchoMessage {
+                         content: format!("{} {}", content, part),
+                     };
+                     assert!(
+                         responses.send(server_message::Message::EchoMessage(echo)),
+                         "The connection is still open"
+                     );
+                 }
+             });
+             true
+         })
+     };
+     with_configured_server(configure, |server, port| {
+         let mut client = client::Client::new("localhost", port.into(), 5000);
+         assert!(client.connect().is_ok(), "Failed to connect to the server");
+         let echo = ClientMessage {
+             message: Some(client_message::Message::EchoMessage(EchoMessage {
+                 content: "later".to_string(),
+             })),
+             client_send_ts: 42,
+             ..Default::default()
+         };
+         assert!(
+             client.send_raw(&echo.encode_to_vec()).is_ok(),
+             "Failed to send raw bytes"
+         );
+         let responses = client
+             .collect_responses(2, Duration::from_secs(5))
+             .expect("Failed to read responses");
+         let contents: Vec<_> = responses
+             .iter()
+             .filter_map(|response| response.as_echo())
+             .map(|echo| echo.content.as_str())
+             .collect();
+         assert_eq!(contents, ["later first", "later second"]);
+         for response in &responses {
+             assert_eq!(
+                 response.client_send_ts, 42,
+                 "The request's timestamp is echoed"
+             );
+             assert!(response.server_recv_ts > 0, "The receive time is recorded");
+         }
+         // Counted once, after its responses were written
+         thread::sleep(Duration::from_millis(50));
+         assert_eq!(
+             server.take_metrics().requests_handled,
+             2,
+             "Hello and the echo"
+         );
+
+         // Requests the async handler declines are answered as usual
+         assert!(
+             client
+                 .send(client_message::Message::PingRequest(PingRequest {}))
+                 .is_ok(),
+             "Failed to send ping"
+         );
+         assert!(client
+             .receive_with_retry(1)
+             .expect("Failed to receive response")
+             .as_pong()
+             .is_some());
+
+         assert!(
+             client.disconnect().is_ok(),
+             "Failed to disconnect from the server"
+         );
+     });
+ }
+
+ /// Test case for a response queued after its connection ended being refused, not lost.
+ #[test]
+ fn test_async_send_after_disconnect_is_refused() {
+     let (accepted, refused) = std::sync::mpsc::channel();
+     let configure = move |server: Server| {
+         server.with_async_handler(move |_, responses| {
+             let (responses, accepted) = (responses.clone(), accepted.clone());
+             thread::spawn(move || {
+                 thread::sleep(Duration::from_millis(100));
+                 let echo = EchoMessage {
+                     content: "too late".to_string(),
+                 };
+                 let _ = accepted.send(responses.send(server_message::Message::EchoMessage(echo)));
+             });
+             true
+         })
+     };
+     with_configured_server(configure, |_, port| {
+         let mut stream = TcpStream::connect(("localhost", port)).expect("Failed to connect");
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: "bye".to_string(),
+         });
+         let request = ClientMessage {
+             message: Some(echo),
+             ..Default::default()
+         };
+         stream
+             .write_all(&frame::encode_frame(&request.encode_to_vec()))
+             .expect("Failed to send request");
+         stream
+             .shutdown(std::net::Shutdown::Write)
+             .expect("Failed to half-close");
+         stream
+             .set_read_timeout(Some(Duration::from_secs(5)))
+             .unwrap();
+         assert_eq!(
+             stream.read(&mut [0; 1]).unwrap_or(0),
+             0,
+             "Nothing should be written after the request"
+         );
+
+         let sent = refused
+             .recv_timeout(Duration::from_secs(5))
+             .expect("The handler never answered");
+         assert!(
+             !sent,
+             "The send queue should be closed once the connection ended"
+         );
+     });
+ }
+
+ /// Test case for pipelined echoes answered in order with corked bursts.
+ #[test]
+ fn test_corked_bursts_answer_pipelined_requests() {
+     with_configured_server(
+         |server| server.with_corked_bursts(true),
+         |_, port| {
+             let mut client = connect_client(port);
+             for i in 0..200 {
+                 client.send_buffered(client_message::Message::EchoMessage(EchoMessage {
+                     content: format!("burst {}", i),
+                 }));
+             }
+             assert!(client.flush().is_ok(), "Failed to send the burst");
+             let responses = client
+                 .collect_responses(200, Duration::from_secs(5))
+                 .expect("Failed to read responses");
+             let contents: Vec<_> = responses
+                 .iter()
+                 .filter_map(|response| response.as_echo())
+                 .map(|echo| echo.content.clone())
+                 .collect();
+             assert_eq!(
+                 contents,
+                 (0..200).map(|i| format!("burst {}", i)).collect::<Vec<_>>()
+             );
+
+             // The connection is uncorked once the burst is answered
+             assert_eq!(client.echo("alone").expect("Echo failed"), "alone");
+
+             assert!(
+                 client.disconnect().is_ok(),
+                 "Failed to disconnect from the server"
+             );
+         },
+     );
+ }
+
+ /// Test case for the byte counts reported by `disconnect_with_stats`.
+ #[test]
+ fn test_disconnect_with_stats_reports_bytes() {
+     with_running_server(|_, port| {
+         let mut client = connect_client(port);
+         let content = "x".repeat(1000);
+         let echo = client_message::Message::EchoMessage(EchoMessage {
+             content: content.clone(),
+         });
+         assert!(client.send(echo.clone()).is_ok(), "Failed to send message");
+         assert!(
+             client.receive_with_retry(1).is_ok(),
+             "Failed to receive response"
+         );
+         let stats = client
+             .disconnect_with_stats()
+             .expect("Failed to disconnect from the server");
+
+         // One Hello and one echo each way, each behind a length prefix
+         let hello = client_message::Message::HelloRequest(HelloRequest {
+             protocol_version: PROTOCOL_VERSION,
+             ..Default::default()
+         });
+         let written = [hello, echo]
+             .into_iter()
+             .map(|message| {
+                 frame::HEADER_LEN
+                     + ClientMessage {
+                         message: Some(message),
+                         ..Default::default()
+                     }
+                     .encoded_len()
+             })
+             .sum::<usize>();
+         let hello = server_message::Message::HelloResponse(HelloResponse {
+             protocol_version: PROTOCOL_VERSION,
+             ..Default::default()
+         });
+         let read = [
+             hello,
+             server_message::Message::EchoMessage(EchoMessage { content }),
+         ]
+         .into_iter()
+         .map(|message| {
+             frame::HEADER_LEN
+                 + ServerMessage {
+                     message: Some(message),
+                     ..Default::default()
+                 }
+                 .encoded_len()
+         })
+         .sum::<usize>();
+         assert_eq!(
+             stats,
+             client::SessionStats {
+                 bytes_read: read as u64,
+                 bytes_written: written as u64
+             }
+         );
+         assert_eq!(
+             client.disconnect_with_stats().unwrap(),
+             client::SessionStats::default(),
+             "The counts restart"
+         );
+     });
+ }
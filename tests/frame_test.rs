@@ -0,0 +1,143 @@
+/*!
+ * \file frame_test.rs
+ * \author Mohamed Shaban Waaer
+ * \date 2024-12-24
+ *
+ * \brief Allocation benchmark for the server-side frame decoder.
+ *
+ * This file installs a counting global allocator and compares the number of heap
+ * allocations `FrameDecoder` performs while splitting and decoding a burst of pipelined
+ * frames with the previous `Vec`-based approach, which copied every payload out of the
+ * read buffer, shifted the remaining bytes down after each frame and decoded from a
+ * slice, copying every `bytes` field once more.
+ *
+ * Allocations are counted per thread, so tests running in parallel do not skew
+ * each other's numbers.
+ */
+
+ use embedded_recruitment_task::frame::{self, FrameDecoder};
+ use embedded_recruitment_task::message::{client_message, BinaryEcho, ClientMessage};
+ use prost::Message;
+ use std::alloc::{GlobalAlloc, Layout, System};
+ use std::cell::Cell;
+ use std::io::Read;
+
+ /// Global allocator that counts allocations made by the current thread.
+ struct CountingAllocator;
+
+ thread_local! {
+     static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+ }
+
+ unsafe impl GlobalAlloc for CountingAllocator {
+     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+         ALLOCATIONS.with(|count| count.set(count.get() + 1));
+         System.alloc(layout)
+     }
+
+     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+         System.dealloc(ptr, layout)
+     }
+
+     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+         ALLOCATIONS.with(|count| count.set(count.get() + 1));
+         System.realloc(ptr, layout, new_size)
+     }
+ }
+
+ #[global_allocator]
+ static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+ /// Runs `f` and returns its result with the number of allocations it made.
+ fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+     let before = ALLOCATIONS.with(Cell::get);
+     let result = f();
+     (result, ALLOCATIONS.with(Cell::get) - before)
+ }
+
+ /// Builds a burst of framed binary echo requests as a client would pipeline them.
+ fn pipelined_burst(count: usize) -> Vec<u8> {
+     let mut burst = Vec::new();
+     for i in 0..count {
+         let echo = client_message::Message::BinaryEcho(BinaryEcho {
+             data: format!("payload {}", i).into_bytes().into(),
+         });
+         burst.extend(frame::encode_frame(
+             &ClientMessage {
+                 message: Some(echo),
+                 ..Default::default()
+             }
+             .encode_to_vec(),
+         ));
+     }
+     burst
+ }
+
+ /// Returns the length of the binary echo payload carried by a decoded request.
+ fn echoed_len(request: &ClientMessage) -> usize {
+     match request.message {
+         Some(client_message::Message::BinaryEcho(ref echo)) => echo.data.len(),
+         ref other => panic!("Expected BinaryEcho, but decoded {:?}", other),
+     }
+ }
+
+ /// The previous decoding approach: copy each read into a `Vec`, copy each payload out of it.
+ fn decode_with_vec(mut input: &[u8]) -> usize {
+     let mut chunk = [0u8; 4096];
+     let mut buffer: Vec<u8> = Vec::new();
+     let mut total = 0;
+     loop {
+         let bytes = input.read(&mut chunk).unwrap();
+         if bytes == 0 {
+             return total;
+         }
+         buffer.extend_from_slice(&chunk[..bytes]);
+
+         while buffer.len() >= frame::HEADER_LEN {
+             let len = u32::from_be_bytes(buffer[..frame::HEADER_LEN].try_into().unwrap()) as usize;
+             if buffer.len() < frame::HEADER_LEN + len {
+                 break;
+             }
+             let payload = buffer[frame::HEADER_LEN..frame::HEADER_LEN + len].to_vec();
+             buffer.drain(..frame::HEADER_LEN + len);
+             total += echoed_len(&ClientMessage::decode(payload.as_slice()).unwrap());
+         }
+     }
+ }
+
+ /// The current approach: read into `FrameDecoder` and decode from the `Bytes` views it hands out.
+ fn decode_with_frame_decoder(mut input: &[u8]) -> usize {
+     let mut decoder = FrameDecoder::new();
+     let mut total = 0;
+     while decoder.read_from(&mut input).unwrap() > 0 {
+         while let Some(payload) = decoder.next_frame().unwrap() {
+             let request = ClientMessage::decode(payload.clone()).unwrap();
+             if let Some(client_message::Message::BinaryEcho(ref echo)) = request.message {
+                 let frame = payload.as_ptr_range();
+                 assert!(
+                     frame.contains(&echo.data.as_ptr()),
+                     "The echoed data was copied out of the frame"
+                 );
+             }
+             total += echoed_len(&request);
+         }
+     }
+     total
+ }
+
+ /// Benchmark comparing heap allocations of both splitting and decoding approaches.
+ #[test]
+ fn test_frame_decoder_allocates_less_than_vec() {
+     let burst = pipelined_burst(1000);
+
+     let (vec_total, vec_allocations) = count_allocations(|| decode_with_vec(&burst));
+     let (bytes_total, bytes_allocations) = count_allocations(|| decode_with_frame_decoder(&burst));
+
+     assert_eq!(vec_total, bytes_total, "Both approaches must yield the same payloads");
+     assert!(
+         bytes_allocations * 10 < vec_allocations,
+         "FrameDecoder made {} allocations, Vec approach {}",
+         bytes_allocations,
+         vec_allocations
+     );
+ }
@@ -0,0 +1,73 @@
+/*!
+ * \file log_capture.rs
+ * \author Mohamed Shaban Waaer
+ * \date 2024-12-24
+ * \brief Captures log records emitted by the server during tests.
+ *
+ * The server reports most of its decisions only through the `log` crate. This module
+ * installs a process-wide logger that keeps every record in memory together with the
+ * name of the thread that emitted it. Since tests run in parallel, assertions should
+ * filter records by a thread name prefix unique to the test (see
+ * `Server::with_handler_thread_name`).
+ *
+ */
+
+ use log::{Level, LevelFilter, Log, Metadata, Record};
+ use std::sync::{Mutex, Once};
+
+ /// \brief A single captured log record.
+ #[derive(Debug, Clone)]
+ pub struct CapturedRecord {
+     pub thread: Option<String>,
+     pub level: Level,
+     pub message: String,
+ }
+
+ static RECORDS: Mutex<Vec<CapturedRecord>> = Mutex::new(Vec::new());
+ static INSTALL: Once = Once::new();
+ static LOGGER: CaptureLogger = CaptureLogger;
+
+ struct CaptureLogger;
+
+ impl Log for CaptureLogger {
+     fn enabled(&self, _metadata: &Metadata) -> bool {
+         true
+     }
+
+     fn log(&self, record: &Record) {
+         let captured = CapturedRecord {
+             thread: std::thread::current().name().map(String::from),
+             level: record.level(),
+             message: record.args().to_string(),
+         };
+         RECORDS.lock().unwrap_or_else(|e| e.into_inner()).push(captured);
+     }
+
+     fn flush(&self) {}
+ }
+
+ /*
+  * \brief Installs the capturing logger; safe to call from every test.
+  */
+ pub fn install() {
+     INSTALL.call_once(|| {
+         log::set_logger(&LOGGER).expect("Another logger is already installed");
+         log::set_max_level(LevelFilter::Debug);
+     });
+ }
+
+ /*
+  * \brief Returns the records emitted by threads whose name starts with `prefix`.
+  *
+  * \param prefix The thread name prefix to filter on.
+  * \return The matching records, in emission order.
+  */
+ pub fn records_from(prefix: &str) -> Vec<CapturedRecord> {
+     RECORDS
+         .lock()
+         .unwrap_or_else(|e| e.into_inner())
+         .iter()
+         .filter(|record| record.thread.as_deref().is_some_and(|name| name.starts_with(prefix)))
+         .cloned()
+         .collect()
+ }